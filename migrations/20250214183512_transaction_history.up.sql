@@ -0,0 +1,11 @@
+CREATE TABLE transaction_history
+(
+    id          INTEGER PRIMARY KEY,
+    sender_id   INTEGER NOT NULL REFERENCES User (id),
+    receiver_id INTEGER NOT NULL REFERENCES User (id),
+    token_id    INTEGER NOT NULL REFERENCES Token (id),
+    amount      INTEGER NOT NULL,
+    created_at  TEXT    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+) STRICT;
+
+CREATE INDEX transaction_history_relation ON transaction_history (receiver_id, token_id, sender_id);
@@ -0,0 +1,3 @@
+DROP TRIGGER user_balance_after_transaction;
+DROP INDEX user_balance_token;
+DROP TABLE user_balance;
@@ -0,0 +1,5 @@
+DROP INDEX token_name_key;
+DROP INDEX user_name_key;
+
+ALTER TABLE Token DROP COLUMN name_key;
+ALTER TABLE User DROP COLUMN name_key;
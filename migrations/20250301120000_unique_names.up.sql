@@ -0,0 +1,8 @@
+-- Normalized names (see NameNormalization), which are unique among all users and all tokens respectively.
+-- The program fills in the keys, as the normalization can't be done in SQL. Rows without a key are duplicates
+-- of an existing name, that are left for the "dedupe" command.
+ALTER TABLE User ADD COLUMN name_key TEXT;
+ALTER TABLE Token ADD COLUMN name_key TEXT;
+
+CREATE UNIQUE INDEX user_name_key ON User (name_key);
+CREATE UNIQUE INDEX token_name_key ON Token (name_key);
@@ -0,0 +1,3 @@
+DROP TABLE token_minter;
+
+ALTER TABLE Token DROP COLUMN owner_id;
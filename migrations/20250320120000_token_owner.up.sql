@@ -0,0 +1,11 @@
+-- Issuer of the token, who may mint and burn it and delegate this to other users.
+-- Tokens created before have no owner, until somebody claims them.
+ALTER TABLE Token ADD COLUMN owner_id INTEGER REFERENCES User (id);
+
+-- Users, that may mint and burn a token besides its owner
+CREATE TABLE token_minter
+(
+    token_id INTEGER NOT NULL REFERENCES Token (id),
+    user_id  INTEGER NOT NULL REFERENCES User (id),
+    PRIMARY KEY (token_id, user_id)
+) STRICT, WITHOUT ROWID;
@@ -0,0 +1,14 @@
+-- Descriptive fields of a token. Amounts are stored in the smallest unit and displayed with "decimals" decimal places,
+-- followed by the symbol (e.g. 1250 with 2 decimals and the symbol "KDS" reads "12.50 KDS").
+ALTER TABLE Token ADD COLUMN description TEXT;
+ALTER TABLE Token ADD COLUMN symbol TEXT;
+ALTER TABLE Token ADD COLUMN decimals INTEGER NOT NULL DEFAULT 0 CHECK (decimals BETWEEN 0 AND 18);
+
+-- Arbitrary key/value pairs of a token
+CREATE TABLE token_metadata
+(
+    token_id INTEGER NOT NULL REFERENCES Token (id),
+    key      TEXT    NOT NULL,
+    value    TEXT    NOT NULL,
+    PRIMARY KEY (token_id, key)
+) STRICT, WITHOUT ROWID;
@@ -0,0 +1,3 @@
+-- Amounts, running totals and credit limits are 64 bit (TokenAmount). INTEGER columns of SQLite already hold 64 bit,
+-- so only the PostgreSQL schema changes; this migration keeps the versions of both schemas in step.
+SELECT 1;
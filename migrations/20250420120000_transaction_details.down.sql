@@ -0,0 +1,3 @@
+ALTER TABLE transaction_history DROP COLUMN actor_id;
+ALTER TABLE transaction_history DROP COLUMN reference;
+ALTER TABLE transaction_history DROP COLUMN memo;
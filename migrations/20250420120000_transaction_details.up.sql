@@ -0,0 +1,5 @@
+-- Optional details of a transaction: free text, an external reference (e.g. a ticket ID) and the user, who recorded
+-- the transaction on behalf of the sender. The UTC timestamp is kept in "created_at" since the table exists.
+ALTER TABLE transaction_history ADD COLUMN memo TEXT;
+ALTER TABLE transaction_history ADD COLUMN reference TEXT;
+ALTER TABLE transaction_history ADD COLUMN actor_id INTEGER REFERENCES User (id);
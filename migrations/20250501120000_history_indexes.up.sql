@@ -0,0 +1,5 @@
+-- Indexes of the history query: transactions are listed by time, optionally for one sender, receiver or token
+CREATE INDEX transaction_history_time ON transaction_history (created_at, id);
+CREATE INDEX transaction_history_sender_time ON transaction_history (sender_id, created_at, id);
+CREATE INDEX transaction_history_receiver_time ON transaction_history (receiver_id, created_at, id);
+CREATE INDEX transaction_history_token_time ON transaction_history (token_id, created_at, id);
@@ -0,0 +1,12 @@
+-- Client-supplied keys of transactions, so a retried transaction returns the original result instead of being
+-- recorded twice. Keys are forgotten after the retention window (see CoreConfig) and may be used again afterwards.
+CREATE TABLE transaction_idempotency
+(
+    idempotency_key TEXT    PRIMARY KEY,
+    transaction_id  INTEGER NOT NULL REFERENCES transaction_history (id),
+    -- Running total of the relation right after the transaction, as returned by the original call
+    new_total       INTEGER NOT NULL,
+    created_at      TEXT    NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+) STRICT;
+
+CREATE INDEX transaction_idempotency_time ON transaction_idempotency (created_at);
@@ -0,0 +1,2 @@
+DROP TABLE "User";
+DROP TABLE Token;
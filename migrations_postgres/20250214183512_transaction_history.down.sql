@@ -0,0 +1,2 @@
+DROP INDEX transaction_history_relation;
+DROP TABLE transaction_history;
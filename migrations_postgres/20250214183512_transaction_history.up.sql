@@ -0,0 +1,11 @@
+CREATE TABLE transaction_history
+(
+    id          BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    sender_id   BIGINT      NOT NULL REFERENCES "User" (id),
+    receiver_id BIGINT      NOT NULL REFERENCES "User" (id),
+    token_id    BIGINT      NOT NULL REFERENCES Token (id),
+    amount      INTEGER     NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX transaction_history_relation ON transaction_history (receiver_id, token_id, sender_id);
@@ -0,0 +1,4 @@
+DROP TRIGGER user_balance_after_transaction ON transaction_history;
+DROP FUNCTION user_balance_after_transaction();
+DROP INDEX user_balance_token;
+DROP TABLE user_balance;
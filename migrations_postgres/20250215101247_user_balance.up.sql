@@ -0,0 +1,31 @@
+-- Running totals per (sender, receiver, token), maintained from transaction_history.
+CREATE TABLE user_balance
+(
+    sender_id     BIGINT  NOT NULL REFERENCES "User" (id),
+    receiver_id   BIGINT  NOT NULL REFERENCES "User" (id),
+    token_id      BIGINT  NOT NULL REFERENCES Token (id),
+    current_total INTEGER NOT NULL,
+    PRIMARY KEY (receiver_id, token_id, sender_id)
+);
+
+CREATE INDEX user_balance_token ON user_balance (token_id);
+
+INSERT INTO user_balance(sender_id, receiver_id, token_id, current_total)
+SELECT sender_id, receiver_id, token_id, SUM(amount)
+FROM transaction_history
+GROUP BY sender_id, receiver_id, token_id;
+
+CREATE FUNCTION user_balance_after_transaction() RETURNS TRIGGER AS
+$$
+BEGIN
+    INSERT INTO user_balance(sender_id, receiver_id, token_id, current_total)
+    VALUES (NEW.sender_id, NEW.receiver_id, NEW.token_id, NEW.amount)
+    ON CONFLICT (receiver_id, token_id, sender_id) DO UPDATE SET current_total = user_balance.current_total + excluded.current_total;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER user_balance_after_transaction
+    AFTER INSERT ON transaction_history
+    FOR EACH ROW
+EXECUTE FUNCTION user_balance_after_transaction();
@@ -0,0 +1 @@
+ALTER TABLE Token DROP COLUMN credit_limit;
@@ -0,0 +1,3 @@
+-- Overdraft policy of the token (see OverdraftPolicy): how far the balance of a sender may drop below zero.
+-- NULL means unlimited ("free"), 0 means the balance has to cover every transaction ("balance-backed").
+ALTER TABLE Token ADD COLUMN credit_limit INTEGER;
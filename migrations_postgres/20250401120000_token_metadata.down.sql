@@ -0,0 +1,5 @@
+DROP TABLE token_metadata;
+
+ALTER TABLE Token DROP COLUMN decimals;
+ALTER TABLE Token DROP COLUMN symbol;
+ALTER TABLE Token DROP COLUMN description;
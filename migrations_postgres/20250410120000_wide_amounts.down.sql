@@ -0,0 +1,4 @@
+-- Fails, if any amount, total or credit limit doesn't fit into 32 bit
+ALTER TABLE Token ALTER COLUMN credit_limit TYPE INTEGER;
+ALTER TABLE user_balance ALTER COLUMN current_total TYPE INTEGER;
+ALTER TABLE transaction_history ALTER COLUMN amount TYPE INTEGER;
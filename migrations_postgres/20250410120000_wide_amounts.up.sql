@@ -0,0 +1,4 @@
+-- Amounts, running totals and credit limits are 64 bit (TokenAmount)
+ALTER TABLE transaction_history ALTER COLUMN amount TYPE BIGINT;
+ALTER TABLE user_balance ALTER COLUMN current_total TYPE BIGINT;
+ALTER TABLE Token ALTER COLUMN credit_limit TYPE BIGINT;
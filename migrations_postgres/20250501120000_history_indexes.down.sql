@@ -0,0 +1,4 @@
+DROP INDEX transaction_history_token_time;
+DROP INDEX transaction_history_receiver_time;
+DROP INDEX transaction_history_sender_time;
+DROP INDEX transaction_history_time;
@@ -0,0 +1,2 @@
+DROP INDEX transaction_history_reverses;
+ALTER TABLE transaction_history DROP COLUMN reverses_id;
@@ -0,0 +1,4 @@
+-- A reversal is a compensating transaction with the negated amount, that refers to the transaction it reverses.
+-- Transactions are never deleted, and the unique index makes sure, that each one is reversed at most once.
+ALTER TABLE transaction_history ADD COLUMN reverses_id BIGINT REFERENCES transaction_history (id);
+CREATE UNIQUE INDEX transaction_history_reverses ON transaction_history (reverses_id);
@@ -0,0 +1,12 @@
+-- Client-supplied keys of transactions, so a retried transaction returns the original result instead of being
+-- recorded twice. Keys are forgotten after the retention window (see CoreConfig) and may be used again afterwards.
+CREATE TABLE transaction_idempotency
+(
+    idempotency_key TEXT        PRIMARY KEY,
+    transaction_id  BIGINT      NOT NULL REFERENCES transaction_history (id),
+    -- Running total of the relation right after the transaction, as returned by the original call
+    new_total       BIGINT      NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX transaction_idempotency_time ON transaction_idempotency (created_at);
@@ -1,133 +1,692 @@
 /*
  * CLI DEFINITION:
  *
+ * global options: [--format=(table|json|jsonl|csv|tsv)] [--no-header]
+ *      (the names below are the column names of the header and the keys of json objects)
+ *
  * create-user <name>
- *      <new_id>
- * user-list [<user_name>]
+ *      <id>
+ * user-list [<user_name>] [--match=(exact|ignore-case|prefix|substring)]
  *      <id> <name>
  *
- * create-token <token_name>
- *      <new_id>
- * token-list [<token_name>]
- *      <id> <name> <owner_name> <owner_id>
+ * create-token <token_name> [--owner=<user>]
+ *      <id>
+ * token-list [<token_name>] [--match=(exact|ignore-case|prefix|substring)]
+ *      <id> <name> <owner_name> <owner_id> <symbol> <decimals> <description> <metadata>
+ *
+ * (every <user> and <token> is either a name or an ID like "#12")
+ * (amounts of tokens with decimals or a symbol are displayed like "12.50 KDS" and entered like "12.5")
+ *
+ * token-edit <token> --as=<owner> [--description=<text>] [--symbol=<symbol>] [--decimals=<n>]
+ *            [--set=<key>=<value>]... [--unset=<key>]...
+ *      <id> <name> <owner_name> <owner_id> <symbol> <decimals> <description> <metadata>
+ *
+ * token-owner <token> <new_owner> --as=<owner>
+ * minter-add <token> <user> --as=<owner>
+ * minter-remove <token> <user> --as=<owner>
+ *      (no output)
+ * minter-list <token>
+ *      <id> <name>
+ *
+ * mint <receiver> <token> <amount> --as=<owner_or_minter>
+ * burn <holder> <token> <amount> --as=<owner_or_minter>
+ *      <balance>
+ *
+ * token-policy <token> [free|balance-backed|credit-limit(<n>) --as=<owner>]
+ *      <policy>
  *
- * tr <sender_user_id> <receiver_user_id> <token_id> [-]<amount>
+ * tr <sender_user> <receiver_user> <token> [-]<amount> [--create-missing] [--memo=<text>] [--ref=<reference>]
+ *    [--as=<user>] [--idempotency-key=<key>]
  *      <current_amount>
+ *      (repeating a transaction with the same key prints the original <current_amount> and records nothing)
+ *
+ * tr-batch [<file>] [--create-missing]
+ *      <current_amount>
+ *      ...
+ *      (one transaction per line of <file> or stdin, as JSON object with the arguments and options of tr as keys,
+ *      e.g. {"sender": "alice", "receiver": "#2", "token": "kudos", "amount": "2.5", "memo": "thanks"}; either all
+ *      transactions are recorded or none)
+ *
+ * reverse <transaction_id> --reason=<text>
+ *      (the reversal, with the columns of history)
+ *
+ * balance <user> <token>
+ *      <balance>
+ *
+ * history [--sender=<user>] [--receiver=<user>] [--token=<token>] [--min-amount=<amount>] [--max-amount=<amount>]
+ *         [--since=<time>] [--until=<time>] [--memo=<text>] [--order=(asc|desc)] [--limit=<n>] [--after=<cursor>]
+ *      <id> <created_at> <sender> <receiver> <token> <amount> <memo> <reference> <actor> <reverses>
+ *      <reversed_by> <cursor>
+ *      ...
+ *      (--after=<cursor of the last row> shows the next page, <reverses> and <reversed_by> link reversals and the
+ *      transactions they undo)
  *
- * ls-user-tokens <user_id> <token_id> [--order-by=(sender|amount)] [--asc|--desc]
- *      <sender> <amount>
+ * ls-user-tokens <user> <token> [--order-by=(sender|amount)] [--asc|--desc]
+ *      <sender> <amount> <reversed>
  *      ...
  *
- * ls-tokens <user_id> [--order-by=(token|sender|amount)] [--asc|--desc]
- *      <token> <sender_user> <amount>
+ * ls-tokens <user> [--order-by=(token|sender|amount)] [--asc|--desc]
+ *      <token> <sender_user> <amount> <reversed>
  *      ...
  *
- * ls-users <token_id> [--order-by=(receiver|sender|amount)] [--asc|--desc]
- *      <receiver_user> <sender_user> <amount>
+ * ls-users <token> [--order-by=(receiver|sender|amount)] [--asc|--desc]
+ *      <receiver_user> <sender_user> <amount> <reversed>
+ *      ...
+ *      (<reversed> is the sum of the reversed transactions, which <amount> no longer includes)
+ *
+ * import [<file>] [--input-format=(csv|jsonl)] [--dry-run]
+ *      <users> <tokens> <transactions>
+ *      (one record per line of <file> or stdin: JSON objects or CSV rows below a header line with the keys
+ *      type=user: name
+ *      type=token: name [owner]
+ *      type=transaction: sender receiver token amount [created_at] [memo] [ref]
+ *      users and tokens are referred to by name and created on demand; <users>, <tokens> and <transactions> count the
+ *      created ones. If any record fails, nothing is imported and the error names its line; --dry-run lists
+ *      <line> <error> of every failed record instead, if there are any)
+ *
+ * dedupe [--dry-run]
+ *      <type> <kept_id> <kept_name> <merged_id> <merged_name>
+ *      ...
+ *
+ * migrate (status|up|down)
+ *      <version> <description> <state>
  *      ...
  */
 
+use async_trait::async_trait;
+use chrono::SecondsFormat;
 use clap::Parser;
 use points_exchange_rs::cli::cli_consumer::CliConsumer; // TODO: alias in module or something?
 use points_exchange_rs::cli::*;
-use points_exchange_rs::core::*;
+use points_exchange_rs::core::{
+    persistance_layer::{HistoryEntry, Token},
+    *,
+};
+use points_exchange_rs::error::Error;
+use serde_json::Value;
+use std::collections::{hash_map::Entry, HashMap};
 
 #[tokio::main]
 async fn main()
 {
     let args = Args::parse(); // TODO: encapsulate call to remove lib dependency
-    let mut core = Core::new().await;
-
-    match args.command
-    {
-        Action::CreateUser { name } => CliWrapper::create_user(&mut core, &name),
-        Action::UserList { name } => CliWrapper::query_user(&mut core, name.as_deref()),
-        Action::CreateToken { name } => CliWrapper::create_token(&mut core, &name),
-        Action::TokenList { name } => CliWrapper::query_token(&mut core, name.as_deref()),
-        Action::Transaction {
-            sender_id,
-            receiver_id,
-            token_id,
-            amount,
-        } => CliWrapper::transaction(&mut core, sender_id, receiver_id, token_id, amount),
-        Action::LsUserToken {
-            user_id,
-            token_id,
-            order,
-            order_by,
-        } => CliWrapper::list_user_token(&mut core, user_id, token_id, order, order_by),
-        Action::LsTokensByUser { user_id, order, order_by } => CliWrapper::list_tokens_by_user(&mut core, user_id, order, order_by),
-        Action::LsUsersByToken { token_id, order, order_by } => CliWrapper::list_users_by_token(&mut core, token_id, order, order_by),
+
+    let mut config = match CoreConfig::load(args.config_file())
+    {
+        Ok(config) => config,
+        Err(err) => exit_with_error(err),
+    };
+    if let Some(database) = args.database
+    {
+        config.database_url = database;
+    }
+    if let Action::Migrate { .. } = args.command
+    {
+        // Leave the schema untouched, so it can be inspected and changed explicitly
+        config.run_migrations = false;
     }
+
+    let core = if args.ephemeral
+    {
+        Core::new_in_memory().await
+    }
+    else
+    {
+        Core::new(&config).await
+    };
+    let mut core = match core
+    {
+        Ok(core) => core,
+        Err(err) => exit_with_error(err),
+    };
+
+    let listing = match CliWrapper::run(&mut core, args.command).await
+    {
+        Ok(listing) => listing,
+        Err(err) => exit_with_error(err),
+    };
+    if let Err(err) = listing.write(&mut std::io::stdout().lock(), args.format, !args.no_header)
+    {
+        exit_with_error(err.into());
+    }
+}
+
+fn exit_with_error(err: Error) -> !
+{
+    eprintln!("Error: {err}");
+    std::process::exit(exit_code(&err));
+}
+
+/// Amount in the display format of the token (see [Token::format_amount]). Amounts of plain tokens stay numbers.
+fn amount(token: &Token, amount: TokenAmount) -> Value
+{
+    match token.decimals == 0 && token.symbol.is_none()
+    {
+        true => amount.into(),
+        false => token.format_amount(amount).into(),
+    }
+}
+
+/// Transactions with the token amounts in their display format (see [amount])
+async fn history_listing(core: &mut Core, entries: Vec<HistoryEntry>) -> Result<Listing, Error>
+{
+    let mut tokens: HashMap<TokenID, Token> = HashMap::new();
+    let mut listing = Listing::new(&[
+        "id",
+        "created_at",
+        "sender",
+        "receiver",
+        "token",
+        "amount",
+        "memo",
+        "reference",
+        "actor",
+        "reverses",
+        "reversed_by",
+        "cursor",
+    ]);
+    for entry in entries
+    {
+        if let Entry::Vacant(vacant) = tokens.entry(entry.token_id)
+        {
+            vacant.insert(core.get_token(TokenQueryModeStrict::ById(entry.token_id)).await?);
+        }
+        let cursor = HistoryCursor::after(&entry);
+        listing.push(vec![
+            entry.id.into(),
+            entry
+                .created_at
+                .map(|created_at| created_at.to_rfc3339_opts(SecondsFormat::Secs, true))
+                .into(),
+            entry.sender_name.into(),
+            entry.receiver_name.into(),
+            entry.token_name.into(),
+            amount(&tokens[&entry.token_id], entry.amount),
+            entry.memo.into(),
+            entry.reference.into(),
+            entry.actor_name.into(),
+            entry.reverses_id.into(),
+            entry.reversed_by_id.into(),
+            cursor.to_string().into(),
+        ]);
+    }
+    Ok(listing)
+}
+
+fn token_listing(tokens: Vec<Token>) -> Listing
+{
+    let mut listing = Listing::new(&[
+        "id",
+        "name",
+        "owner_name",
+        "owner_id",
+        "symbol",
+        "decimals",
+        "description",
+        "metadata",
+    ]);
+    for token in tokens
+    {
+        let metadata = match token.metadata.is_empty()
+        {
+            true => Value::Null,
+            false => token.metadata.into_iter().map(|(key, value)| (key, Value::String(value))).collect(),
+        };
+        listing.push(vec![
+            token.id.into(),
+            token.name.into(),
+            token.owner_name.into(),
+            token.owner_id.into(),
+            token.symbol.into(),
+            token.decimals.into(),
+            token.description.into(),
+            metadata,
+        ]);
+    }
+    listing
 }
 
 struct CliWrapper;
 
+#[async_trait]
 impl CliConsumer for CliWrapper
 {
-    fn create_user(core: &mut Core, name: &str)
+    async fn create_user(core: &mut Core, name: &str) -> Result<Listing, Error>
     {
-        println!("{:?}", core.create_user(name));
+        let user = core.create_user(name).await?;
+
+        let mut listing = Listing::new(&["id"]);
+        listing.push(vec![user.id.into()]);
+        Ok(listing)
     }
 
-    fn query_user(core: &mut Core, name: Option<&str>)
+    async fn query_user(core: &mut Core, name: Option<&str>, matching: NameMatch) -> Result<Listing, Error>
     {
-        println!("{:?}", core.query_user(name));
+        let users = match name
+        {
+            Some(name) => core.query_user(name, matching).await?,
+            None => core.query_all_users().await?,
+        };
+
+        let mut listing = Listing::new(&["id", "name"]);
+        for user in users
+        {
+            listing.push(vec![user.id.into(), user.name.into()]);
+        }
+        Ok(listing)
     }
 
-    fn create_token(core: &mut Core, name: &str)
+    async fn create_token(core: &mut Core, name: &str, owner: Option<&NameOrId>) -> Result<Listing, Error>
     {
-        println!("{:?}", core.create_token(name));
+        let token = core.create_token(name, owner.map(NameOrId::user)).await?;
+
+        let mut listing = Listing::new(&["id"]);
+        listing.push(vec![token.id.into()]);
+        Ok(listing)
     }
 
-    fn query_token(core: &mut Core, name: Option<&str>)
+    async fn query_token(core: &mut Core, name: Option<&str>, matching: NameMatch) -> Result<Listing, Error>
     {
-        println!("{:?}", core.query_token(name));
+        let tokens = match name
+        {
+            Some(name) => core.query_token(name, matching).await?,
+            None => core.query_all_tokens().await?,
+        };
+
+        Ok(token_listing(tokens))
     }
 
-    fn transaction(core: &mut Core, sender_id: UserID, receiver_id: UserID, token_id: TokenID, amount: TokenAmount)
+    async fn edit_token(core: &mut Core, actor: &NameOrId, token: &NameOrId, edit: TokenEdit) -> Result<Listing, Error>
     {
-        println!(
-            "{:?}",
-            core.transaction(
-                UserQueryModeWithCreation::ById(sender_id),
-                UserQueryModeWithCreation::ById(receiver_id),
-                TokenQueryModeWithCreation::ById(token_id),
-                amount
-            )
-            .unwrap()
-        );
+        let token = core.edit_token(actor.user(), token.token(), &edit).await?;
+        Ok(token_listing(vec![token]))
+    }
+
+    async fn set_token_owner(core: &mut Core, actor: &NameOrId, token: &NameOrId, owner: &NameOrId) -> Result<Listing, Error>
+    {
+        core.set_token_owner(actor.user(), token.token(), owner.user()).await?;
+        Ok(Listing::empty())
+    }
+
+    async fn set_minter(core: &mut Core, actor: &NameOrId, token: &NameOrId, user: &NameOrId, allowed: bool) -> Result<Listing, Error>
+    {
+        match allowed
+        {
+            true => core.grant_minter(actor.user(), token.token(), user.user()).await?,
+            false => core.revoke_minter(actor.user(), token.token(), user.user()).await?,
+        }
+        Ok(Listing::empty())
+    }
+
+    async fn query_minters(core: &mut Core, token: &NameOrId) -> Result<Listing, Error>
+    {
+        let mut listing = Listing::new(&["id", "name"]);
+        for user in core.query_minters(token.token()).await?
+        {
+            listing.push(vec![user.id.into(), user.name.into()]);
+        }
+        Ok(listing)
+    }
+
+    async fn mint(core: &mut Core, actor: &NameOrId, receiver: &NameOrId, token: &NameOrId, amount: DecimalAmount)
+        -> Result<Listing, Error>
+    {
+        let token = core.get_token(token.token()).await?;
+        let amount = amount.scaled(token.decimals)?;
+        let balance = core
+            .mint(actor.user(), receiver.user(), TokenQueryModeStrict::ById(token.id), amount)
+            .await?;
+
+        let mut listing = Listing::new(&["balance"]);
+        listing.push(vec![self::amount(&token, balance)]);
+        Ok(listing)
     }
 
-    fn list_user_token(core: &mut Core, user_id: UserID, token_id: TokenID, order: Order, order_by: Option<OrderBySenderOrAmount>)
+    async fn burn(core: &mut Core, actor: &NameOrId, holder: &NameOrId, token: &NameOrId, amount: DecimalAmount) -> Result<Listing, Error>
     {
-        println!(
-            "{:?}",
-            core.list_user_token(
-                UserQueryModeStrict::ById(user_id),
-                TokenQueryModeStrict::ById(token_id),
-                order,
-                order_by
+        let token = core.get_token(token.token()).await?;
+        let amount = amount.scaled(token.decimals)?;
+        let balance = core
+            .burn(actor.user(), holder.user(), TokenQueryModeStrict::ById(token.id), amount)
+            .await?;
+
+        let mut listing = Listing::new(&["balance"]);
+        listing.push(vec![self::amount(&token, balance)]);
+        Ok(listing)
+    }
+
+    async fn overdraft_policy(
+        core: &mut Core,
+        actor: Option<&NameOrId>,
+        token: &NameOrId,
+        policy: Option<OverdraftPolicy>,
+    ) -> Result<Listing, Error>
+    {
+        if let Some(policy) = policy
+        {
+            let actor = actor.ok_or_else(|| Error::InvalidArgument("changing the overdraft policy requires --as=<owner>".into()))?;
+            core.set_overdraft_policy(actor.user(), token.token(), policy).await?;
+        }
+        let policy = core.overdraft_policy(token.token()).await?;
+
+        let mut listing = Listing::new(&["policy"]);
+        listing.push(vec![policy.to_string().into()]);
+        Ok(listing)
+    }
+
+    async fn balance(core: &mut Core, user: &NameOrId, token: &NameOrId) -> Result<Listing, Error>
+    {
+        let balance = core.get_balance(user.user(), token.token()).await?;
+        let token = core.get_token(token.token()).await?;
+
+        let mut listing = Listing::new(&["balance"]);
+        listing.push(vec![amount(&token, balance)]);
+        Ok(listing)
+    }
+
+    async fn transaction(
+        core: &mut Core,
+        sender: &NameOrId,
+        receiver: &NameOrId,
+        token: &NameOrId,
+        amount: DecimalAmount,
+        create_missing: bool,
+        details: &TransactionDetails<'_>,
+    ) -> Result<Listing, Error>
+    {
+        // Tokens, that are about to be created, have no decimal places yet
+        let decimals = match core.get_token(token.token()).await
+        {
+            Ok(token) => token.decimals,
+            Err(Error::TokenNotFound(_)) if create_missing => 0,
+            Err(err) => return Err(err),
+        };
+        let amount = amount.scaled(decimals)?;
+        let total = core
+            .transaction_with_details(
+                sender.user_with_creation(create_missing),
+                receiver.user_with_creation(create_missing),
+                token.token_with_creation(create_missing),
+                amount,
+                details,
             )
-            .unwrap()
-        );
+            .await?;
+        let token = core.get_token(token.token()).await?;
+
+        let mut listing = Listing::new(&["current_amount"]);
+        listing.push(vec![self::amount(&token, total)]);
+        Ok(listing)
     }
 
-    fn list_tokens_by_user(core: &mut Core, user_id: UserID, order: Order, order_by: Option<OrderByTokenOrSenderOrAmount>)
+    async fn batch_transaction(core: &mut Core, legs: &[LegArgs], create_missing: bool) -> Result<Listing, Error>
     {
-        println!(
-            "{:?}",
-            core.list_tokens_by_user(UserQueryModeStrict::ById(user_id), order, order_by)
-                .unwrap()
-        );
+        let mut transfers = Vec::with_capacity(legs.len());
+        for leg in legs
+        {
+            // Tokens, that are about to be created, have no decimal places yet
+            let decimals = match core.get_token(leg.token.token()).await
+            {
+                Ok(token) => token.decimals,
+                Err(Error::TokenNotFound(_)) if create_missing => 0,
+                Err(err) => return Err(err),
+            };
+            transfers.push(TransferLeg {
+                sender:   leg.sender.user_with_creation(create_missing),
+                receiver: leg.receiver.user_with_creation(create_missing),
+                token:    leg.token.token_with_creation(create_missing),
+                amount:   leg.amount.scaled(decimals)?,
+                details:  leg.details(),
+            });
+        }
+        let totals = core.batch_transaction(transfers).await?;
+
+        let mut listing = Listing::new(&["current_amount"]);
+        for (leg, total) in legs.iter().zip(totals)
+        {
+            let token = core.get_token(leg.token.token()).await?;
+            listing.push(vec![amount(&token, total)]);
+        }
+        Ok(listing)
+    }
+
+    async fn reverse_transaction(core: &mut Core, id: TransactionID, reason: &str) -> Result<Listing, Error>
+    {
+        let reversal = core.reverse_transaction(id, reason).await?;
+        history_listing(core, vec![reversal]).await
+    }
+
+    async fn query_history(core: &mut Core, query: &HistoryArgs) -> Result<Listing, Error>
+    {
+        // Amounts are given with the decimals of the token (or in plain units without a token)
+        let decimals = match &query.token
+        {
+            Some(token) => core.get_token(token.token()).await?.decimals,
+            None => 0,
+        };
+        let scaled = |amount: Option<DecimalAmount>| amount.map(|amount| amount.scaled(decimals)).transpose();
+        let filter = HistoryFilter {
+            sender:     query.sender.as_ref().map(NameOrId::user),
+            receiver:   query.receiver.as_ref().map(NameOrId::user),
+            token:      query.token.as_ref().map(NameOrId::token),
+            min_amount: scaled(query.min_amount)?,
+            max_amount: scaled(query.max_amount)?,
+            since:      query.since,
+            until:      query.until,
+            memo:       query.memo.clone(),
+        };
+        let page = core.query_history(&filter, query.order, query.after, query.limit).await?;
+        history_listing(core, page.entries).await
+    }
+
+    async fn list_user_token(
+        core: &mut Core,
+        user: &NameOrId,
+        token: &NameOrId,
+        order: Order,
+        order_by: Option<OrderBySenderOrAmount>,
+    ) -> Result<Listing, Error>
+    {
+        let entries = core.list_user_token(user.user(), token.token(), order, order_by).await?;
+        let token = core.get_token(token.token()).await?;
+
+        let mut listing = Listing::new(&["sender", "amount", "reversed"]);
+        for entry in entries
+        {
+            listing.push(vec![
+                entry.sender.name.into(),
+                amount(&token, entry.amount),
+                amount(&token, entry.reversed),
+            ]);
+        }
+        Ok(listing)
+    }
+
+    async fn list_tokens_by_user(
+        core: &mut Core,
+        user: &NameOrId,
+        order: Order,
+        order_by: Option<OrderByTokenOrSenderOrAmount>,
+    ) -> Result<Listing, Error>
+    {
+        let mut listing = Listing::new(&["token", "sender_user", "amount", "reversed"]);
+        for entry in core.list_tokens_by_user(user.user(), order, order_by).await?
+        {
+            for by_sender in entry.amount_by_sender
+            {
+                listing.push(vec![
+                    entry.token.name.clone().into(),
+                    by_sender.sender.name.into(),
+                    amount(&entry.token, by_sender.amount),
+                    amount(&entry.token, by_sender.reversed),
+                ]);
+            }
+        }
+        Ok(listing)
+    }
+
+    async fn list_users_by_token(
+        core: &mut Core,
+        token: &NameOrId,
+        order: Order,
+        order_by: Option<OrderByReceiverOrSenderOrAmount>,
+    ) -> Result<Listing, Error>
+    {
+        let entries = core.list_users_by_token(token.token(), order, order_by).await?;
+        let token = core.get_token(token.token()).await?;
+
+        let mut listing = Listing::new(&["receiver_user", "sender_user", "amount", "reversed"]);
+        for entry in entries
+        {
+            for by_sender in entry.amount_by_sender
+            {
+                listing.push(vec![
+                    entry.receiver.name.clone().into(),
+                    by_sender.sender.name.into(),
+                    amount(&token, by_sender.amount),
+                    amount(&token, by_sender.reversed),
+                ]);
+            }
+        }
+        Ok(listing)
+    }
+
+    async fn import(core: &mut Core, rows: &[(usize, ImportRow)], dry_run: bool) -> Result<Listing, Error>
+    {
+        // Amounts have the decimals of the token (tokens, that are about to be created, have none yet)
+        let mut decimals: HashMap<&str, u8> = HashMap::new();
+        let mut records = Vec::with_capacity(rows.len());
+        for (line, row) in rows
+        {
+            records.push(match row
+            {
+                ImportRow::User { name } => ImportRecord::User { name },
+                ImportRow::Token { name, owner } => ImportRecord::Token {
+                    name,
+                    owner: owner.as_deref(),
+                },
+                ImportRow::Transaction {
+                    sender,
+                    receiver,
+                    token,
+                    amount,
+                    created_at,
+                    memo,
+                    reference,
+                } =>
+                {
+                    if let Entry::Vacant(vacant) = decimals.entry(token)
+                    {
+                        vacant.insert(match core.get_token(TokenQueryModeStrict::ByName(token)).await
+                        {
+                            Ok(token) => token.decimals,
+                            Err(Error::TokenNotFound(_)) => 0,
+                            Err(err) => return Err(err),
+                        });
+                    }
+                    let amount = amount.scaled(decimals[token.as_str()]).map_err(|err| match err
+                    {
+                        Error::InvalidArgument(message) => Error::InvalidArgument(format!("line {line}: {message}")),
+                        err => err,
+                    })?;
+                    ImportRecord::Transaction {
+                        leg:        TransferLeg {
+                            sender: UserQueryModeWithCreation::ByNameOrCreate(sender),
+                            receiver: UserQueryModeWithCreation::ByNameOrCreate(receiver),
+                            token: TokenQueryModeWithCreation::ByNameOrCreate(token),
+                            amount,
+                            details: TransactionDetails {
+                                memo: memo.clone(),
+                                reference: reference.clone(),
+                                ..TransactionDetails::default()
+                            },
+                        },
+                        created_at: *created_at,
+                    }
+                }
+            });
+        }
+        let report = core.import(&records, dry_run).await?;
+
+        if let Some((index, err)) = report.failures.first().filter(|_| !dry_run)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "nothing has been imported, {} of {} records failed (line {}: {err}; see --dry-run for all of them)",
+                report.failures.len(),
+                rows.len(),
+                rows[*index].0
+            )));
+        }
+        if !report.failures.is_empty()
+        {
+            let mut listing = Listing::new(&["line", "error"]);
+            for (index, err) in report.failures
+            {
+                listing.push(vec![rows[index].0.into(), err.to_string().into()]);
+            }
+            return Ok(listing);
+        }
+
+        let mut listing = Listing::new(&["users", "tokens", "transactions"]);
+        listing.push(vec![report.users.into(), report.tokens.into(), report.transactions.into()]);
+        Ok(listing)
+    }
+
+    async fn dedupe(core: &mut Core, dry_run: bool) -> Result<Listing, Error>
+    {
+        let report = core.dedupe(dry_run).await?;
+
+        let mut listing = Listing::new(&["type", "kept_id", "kept_name", "merged_id", "merged_name"]);
+        for group in report.users
+        {
+            for user in group.merged
+            {
+                listing.push(vec![
+                    "user".into(),
+                    group.kept.id.into(),
+                    group.kept.name.clone().into(),
+                    user.id.into(),
+                    user.name.into(),
+                ]);
+            }
+        }
+        for group in report.tokens
+        {
+            for token in group.merged
+            {
+                listing.push(vec![
+                    "token".into(),
+                    group.kept.id.into(),
+                    group.kept.name.clone().into(),
+                    token.id.into(),
+                    token.name.into(),
+                ]);
+            }
+        }
+        Ok(listing)
     }
 
-    fn list_users_by_token(core: &mut Core, token_id: UserID, order: Order, order_by: Option<OrderByReceiverOrSenderOrAmount>)
+    async fn migrate(core: &mut Core, action: MigrateAction) -> Result<Listing, Error>
     {
-        println!(
-            "{:?}",
-            core.list_users_by_token(TokenQueryModeStrict::ById(token_id), order, order_by)
-                .unwrap()
-        );
+        let mut listing = Listing::new(&["version", "description", "state"]);
+        match action
+        {
+            MigrateAction::Status =>
+            {
+                for migration in core.migration_status().await?
+                {
+                    let state = if migration.applied { "applied" } else { "pending" };
+                    listing.push(vec![migration.version.into(), migration.description.into(), state.into()]);
+                }
+            }
+            MigrateAction::Up => core.migrate_up().await?,
+            MigrateAction::Down =>
+            {
+                if let Some(migration) = core.migrate_down().await?
+                {
+                    listing.push(vec![migration.version.into(), migration.description.into(), "reverted".into()]);
+                }
+            }
+        }
+        Ok(listing)
     }
 }
@@ -1,18 +1,380 @@
 use crate::core::{
-    Order, OrderByReceiverOrSenderOrAmount, OrderBySenderOrAmount, OrderByTokenOrSenderOrAmount, TokenAmount, TokenID, UserID,
+    persistance_layer::DbPk, DecimalAmount, HistoryCursor, NameMatch, Order, OrderByReceiverOrSenderOrAmount, OrderBySenderOrAmount,
+    OrderByTokenOrSenderOrAmount, OverdraftPolicy, TokenEdit, TokenQueryModeStrict, TokenQueryModeWithCreation, TransactionDetails,
+    TransactionID, UserQueryModeStrict, UserQueryModeWithCreation,
 };
+use crate::error::Error;
+use chrono::{DateTime, NaiveDate, Utc};
 use clap::{Parser, Subcommand};
+use serde::{Deserialize, Deserializer};
+use std::{
+    convert::Infallible,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 pub mod cli_consumer;
+pub mod import;
+pub mod output;
+
+pub use import::{parse_import, ImportFormat, ImportRow};
+pub use output::{Listing, OutputFormat};
+
+/// Config file, that will be used if present and no other config file is specified
+pub const DEFAULT_CONFIG_FILE: &str = "points_exchange.toml";
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Args
 {
+    /// Database URL, e.g. "sqlite://var/data.db" (overrides the DATABASE_URL environment variable and the config file)
+    #[arg(long, global = true)]
+    pub database: Option<String>,
+
+    /// TOML config file [default: points_exchange.toml, if present]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Use a throw-away in-memory database instead of the configured one (nothing will be persisted)
+    #[arg(long, global = true, conflicts_with = "database")]
+    pub ephemeral: bool,
+
+    /// Output format
+    #[arg(value_enum, long, global = true, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+
+    /// Omit the header line of the table, csv and tsv formats
+    #[arg(long, global = true)]
+    pub no_header: bool,
+
     #[command(subcommand)]
     pub command: Action,
 }
 
+impl Args
+{
+    /// The explicitly specified config file or the default one, if it exists
+    pub fn config_file(&self) -> Option<&Path>
+    {
+        match &self.config
+        {
+            Some(path) => Some(path),
+            None => Some(Path::new(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()),
+        }
+    }
+}
+
+/// Exit code of the CLI for the given error:
+/// 1 storage error, 2 invalid arguments or configuration, 3 unknown user or token, 4 rejected (e.g. duplicate name
+/// or missing rights), 5 database unavailable (or too new)
+pub fn exit_code(err: &Error) -> i32
+{
+    match err
+    {
+        Error::Storage(_) => 1,
+        Error::Config(_) | Error::InvalidArgument(_) => 2,
+        Error::UserNotFound(_) | Error::TokenNotFound(_) | Error::TransactionNotFound(_) => 3,
+        Error::AmbiguousName { .. }
+        | Error::DuplicateName(_)
+        | Error::NotAuthorized { .. }
+        | Error::InsufficientBalance { .. }
+        | Error::AlreadyReversed { .. }
+        | Error::DecimalsInUse(_)
+        | Error::Overflow => 4,
+        Error::BackendUnavailable(_) | Error::SchemaTooNew { .. } => 5,
+    }
+}
+
+/// User or token argument: "#12" refers to the ID 12, anything else is a name
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum NameOrId
+{
+    Id(DbPk),
+    Name(String),
+}
+
+impl FromStr for NameOrId
+{
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err>
+    {
+        Ok(match value.strip_prefix('#').and_then(|id| id.parse().ok())
+        {
+            Some(id) => NameOrId::Id(id),
+            None => NameOrId::Name(value.to_string()),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for NameOrId
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let value = String::deserialize(deserializer)?;
+        Ok(value.parse().unwrap_or_else(|never: Infallible| match never {}))
+    }
+}
+
+impl NameOrId
+{
+    pub fn user(&self) -> UserQueryModeStrict<'_>
+    {
+        match self
+        {
+            NameOrId::Id(id) => UserQueryModeStrict::ById(*id),
+            NameOrId::Name(name) => UserQueryModeStrict::ByName(name),
+        }
+    }
+
+    pub fn token(&self) -> TokenQueryModeStrict<'_>
+    {
+        match self
+        {
+            NameOrId::Id(id) => TokenQueryModeStrict::ById(*id),
+            NameOrId::Name(name) => TokenQueryModeStrict::ByName(name),
+        }
+    }
+
+    /// Unknown names will be created, if _create_missing_ is set (IDs have to exist either way)
+    pub fn user_with_creation(&self, create_missing: bool) -> UserQueryModeWithCreation<'_>
+    {
+        match self
+        {
+            NameOrId::Name(name) if create_missing => UserQueryModeWithCreation::ByNameOrCreate(name),
+            _ => self.user().into(),
+        }
+    }
+
+    /// Unknown names will be created, if _create_missing_ is set (IDs have to exist either way)
+    pub fn token_with_creation(&self, create_missing: bool) -> TokenQueryModeWithCreation<'_>
+    {
+        match self
+        {
+            NameOrId::Name(name) if create_missing => TokenQueryModeWithCreation::ByNameOrCreate(name),
+            _ => self.token().into(),
+        }
+    }
+}
+
+/// Changes of "token-edit"
+#[derive(clap::Args)]
+pub struct TokenEditArgs
+{
+    /// New description (an empty one removes it)
+    #[arg(long)]
+    pub description: Option<String>,
+
+    /// New symbol, e.g. "KDS" (an empty one removes it)
+    #[arg(long)]
+    pub symbol: Option<String>,
+
+    /// Number of decimal places amounts are displayed with (fixed once the token has been used in a transaction)
+    #[arg(long)]
+    pub decimals: Option<u8>,
+
+    /// Set a metadata entry
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_metadata_entry)]
+    pub set: Vec<(String, String)>,
+
+    /// Remove a metadata entry
+    #[arg(long = "unset", value_name = "KEY")]
+    pub unset: Vec<String>,
+}
+
+fn parse_metadata_entry(value: &str) -> Result<(String, String), String>
+{
+    value
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("invalid metadata entry \"{value}\" (expected KEY=VALUE)"))
+}
+
+impl From<TokenEditArgs> for TokenEdit
+{
+    fn from(args: TokenEditArgs) -> Self
+    {
+        let removable = |value: String| Some(value).filter(|value| !value.is_empty());
+        TokenEdit {
+            description: args.description.map(removable),
+            symbol:      args.symbol.map(removable),
+            decimals:    args.decimals,
+            metadata:    args
+                .set
+                .into_iter()
+                .map(|(key, value)| (key, Some(value)))
+                .chain(args.unset.into_iter().map(|key| (key, None)))
+                .collect(),
+        }
+    }
+}
+
+/// Optional details of "tr", that are kept in the history
+#[derive(clap::Args)]
+pub struct TransactionDetailsArgs
+{
+    /// Free text, e.g. "thanks for the code review"
+    #[arg(long)]
+    pub memo: Option<String>,
+
+    /// External reference, e.g. a ticket ID or a message link
+    #[arg(long = "ref")]
+    pub reference: Option<String>,
+
+    /// User, who records the transaction on behalf of the sender (name or #id)
+    #[arg(long = "as")]
+    pub actor: Option<NameOrId>,
+
+    /// Unique key of this transaction: repeating the command with the same key doesn't record it again
+    #[arg(long)]
+    pub idempotency_key: Option<String>,
+}
+
+impl TransactionDetailsArgs
+{
+    pub fn details(&self) -> TransactionDetails<'_>
+    {
+        TransactionDetails {
+            memo:            self.memo.clone(),
+            reference:       self.reference.clone(),
+            actor:           self.actor.as_ref().map(NameOrId::user),
+            idempotency_key: self.idempotency_key.clone(),
+        }
+    }
+}
+
+/// One transaction of "tr-batch": the arguments and options of "tr" as keys of a JSON object, e.g.
+/// {"sender": "alice", "receiver": "#2", "token": "kudos", "amount": "2.5", "memo": "thanks"}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LegArgs
+{
+    pub sender:          NameOrId,
+    pub receiver:        NameOrId,
+    pub token:           NameOrId,
+    /// Amount as string or number, with up to as many decimal places as the token has
+    #[serde(deserialize_with = "deserialize_amount")]
+    pub amount:          DecimalAmount,
+    pub memo:            Option<String>,
+    #[serde(rename = "ref")]
+    pub reference:       Option<String>,
+    #[serde(rename = "as")]
+    pub actor:           Option<NameOrId>,
+    pub idempotency_key: Option<String>,
+}
+
+impl LegArgs
+{
+    pub fn details(&self) -> TransactionDetails<'_>
+    {
+        TransactionDetails {
+            memo:            self.memo.clone(),
+            reference:       self.reference.clone(),
+            actor:           self.actor.as_ref().map(NameOrId::user),
+            idempotency_key: self.idempotency_key.clone(),
+        }
+    }
+}
+
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DecimalAmount, D::Error>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount
+    {
+        Text(String),
+        Number(serde_json::Number),
+    }
+
+    let text = match Amount::deserialize(deserializer)?
+    {
+        Amount::Text(text) => text,
+        Amount::Number(number) => number.to_string(),
+    };
+    text.parse().map_err(serde::de::Error::custom)
+}
+
+/// Transactions of "tr-batch", one JSON object per line (see [LegArgs]). Empty lines are skipped.
+pub fn parse_legs(text: &str) -> Result<Vec<LegArgs>, Error>
+{
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| serde_json::from_str(line).map_err(|err| Error::InvalidArgument(format!("line {}: {err}", index + 1))))
+        .collect()
+}
+
+/// Content of the file _input_, or of stdin without a file or for "-"
+pub fn read_input(input: Option<&Path>) -> Result<String, Error>
+{
+    match input.filter(|path| *path != Path::new("-"))
+    {
+        Some(path) =>
+        {
+            std::fs::read_to_string(path).map_err(|err| Error::InvalidArgument(format!("can't read \"{}\": {err}", path.display())))
+        }
+        None => Ok(std::io::read_to_string(std::io::stdin())?),
+    }
+}
+
+/// Filters, order and page of "history"
+#[derive(clap::Args)]
+pub struct HistoryArgs
+{
+    /// Only transactions of this sender (name or #id)
+    #[arg(long)]
+    pub sender: Option<NameOrId>,
+
+    /// Only transactions to this receiver (name or #id)
+    #[arg(long)]
+    pub receiver: Option<NameOrId>,
+
+    /// Only transactions of this token (name or #id)
+    #[arg(long)]
+    pub token: Option<NameOrId>,
+
+    /// Smallest amount (with the decimals of --token, if given)
+    #[arg(long, allow_negative_numbers = true)]
+    pub min_amount: Option<DecimalAmount>,
+
+    /// Largest amount (with the decimals of --token, if given)
+    #[arg(long, allow_negative_numbers = true)]
+    pub max_amount: Option<DecimalAmount>,
+
+    /// Earliest time, e.g. "2025-04-14" or "2025-04-14T09:30:00Z" (UTC, unless an offset is given)
+    #[arg(long, value_parser = parse_time)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Time, before which the transactions happened (same format as --since)
+    #[arg(long, value_parser = parse_time)]
+    pub until: Option<DateTime<Utc>>,
+
+    /// Text, that the memo contains (ignoring case)
+    #[arg(long)]
+    pub memo: Option<String>,
+
+    /// Order output in ascending (asc) or descending (desc) order of time
+    #[arg(value_enum, long, short = 'o', default_value_t = Order::Desc)]
+    pub order: Order,
+
+    /// Maximum number of transactions
+    #[arg(long, default_value_t = 50)]
+    pub limit: usize,
+
+    /// Continue after the transaction with this cursor (see the "cursor" column)
+    #[arg(long)]
+    pub after: Option<HistoryCursor>,
+}
+
+/// RFC 3339 time or a date (midnight UTC)
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String>
+{
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.to_utc())
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_time(Default::default()).and_utc()))
+        .map_err(|_| format!("invalid time \"{value}\" (expected e.g. 2025-04-14 or 2025-04-14T09:30:00Z)"))
+}
+
 #[derive(Subcommand)]
 pub enum Action
 {
@@ -27,6 +389,10 @@ pub enum Action
     {
         /// User name to search for
         name: Option<String>,
+
+        /// How the name is matched
+        #[arg(value_enum, long = "match", short = 'm', default_value_t = NameMatch::Substring)]
+        matching: NameMatch,
     },
 
     /// Register a new token explicitly
@@ -34,39 +400,202 @@ pub enum Action
     {
         /// Displayed name of this new token
         name: String,
+
+        /// Issuer of the token, who may mint and burn it (name or #id)
+        #[arg(long)]
+        owner: Option<NameOrId>,
     },
     /// Get token information by token name (or all tokens)
     TokenList
     {
         /// Token name to search for
         name: Option<String>,
+
+        /// How the name is matched
+        #[arg(value_enum, long = "match", short = 'm', default_value_t = NameMatch::Substring)]
+        matching: NameMatch,
+    },
+
+    /// Change the description, symbol, decimals or metadata of a token
+    TokenEdit
+    {
+        /// Token (name or #id)
+        token: NameOrId,
+
+        /// Owner of the token (name or #id)
+        #[arg(long = "as")]
+        actor: NameOrId,
+
+        #[command(flatten)]
+        edit: TokenEditArgs,
+    },
+
+    /// Hand a token over to another owner (or claim a token, that has no owner yet)
+    TokenOwner
+    {
+        /// Token (name or #id)
+        token: NameOrId,
+
+        /// New owner (name or #id)
+        owner: NameOrId,
+
+        /// Current owner (name or #id)
+        #[arg(long = "as")]
+        actor: NameOrId,
+    },
+    /// Allow a user to mint and burn a token
+    MinterAdd
+    {
+        /// Token (name or #id)
+        token: NameOrId,
+
+        /// User, who becomes a minter (name or #id)
+        user: NameOrId,
+
+        /// Owner of the token (name or #id)
+        #[arg(long = "as")]
+        actor: NameOrId,
+    },
+    /// Take minting rights back
+    MinterRemove
+    {
+        /// Token (name or #id)
+        token: NameOrId,
+
+        /// Minter (name or #id)
+        user: NameOrId,
+
+        /// Owner of the token (name or #id)
+        #[arg(long = "as")]
+        actor: NameOrId,
+    },
+    /// Show the users, that may mint and burn a token besides its owner
+    MinterList
+    {
+        /// Token (name or #id)
+        token: NameOrId,
+    },
+    /// Issue new points of a token (owner and minters only)
+    Mint
+    {
+        /// Receiver of the new points (name or #id)
+        receiver: NameOrId,
+
+        /// Token (name or #id)
+        token: NameOrId,
+
+        /// Amount, with up to as many decimal places as the token has (e.g. 2.25)
+        amount: DecimalAmount,
+
+        /// Owner or minter of the token (name or #id)
+        #[arg(long = "as")]
+        actor: NameOrId,
+    },
+    /// Take points of a token out of circulation (owner and minters only)
+    Burn
+    {
+        /// Current holder of the points (name or #id)
+        holder: NameOrId,
+
+        /// Token (name or #id)
+        token: NameOrId,
+
+        /// Amount, with up to as many decimal places as the token has (e.g. 2.25)
+        amount: DecimalAmount,
+
+        /// Owner or minter of the token (name or #id)
+        #[arg(long = "as")]
+        actor: NameOrId,
+    },
+
+    /// Show or change the overdraft policy of a token
+    TokenPolicy
+    {
+        /// Token (name or #id)
+        token: NameOrId,
+
+        /// New policy: free, balance-backed or credit-limit(n)
+        #[arg(requires = "actor")]
+        policy: Option<OverdraftPolicy>,
+
+        /// Owner of the token (name or #id), required to change the policy
+        #[arg(long = "as")]
+        actor: Option<NameOrId>,
     },
 
     /// Send tokens from User A to User B
-    #[command(name = "tr")]
+    #[command(name = "tr", allow_negative_numbers = true)]
     Transaction
     {
-        /// Token "sender"
-        sender_id: UserID,
+        /// Token "sender" (name or #id)
+        sender: NameOrId,
+
+        /// Token "receiver" (name or #id)
+        receiver: NameOrId,
+
+        /// Token (name or #id)
+        token: NameOrId,
+
+        /// Amount, with up to as many decimal places as the token has (e.g. 2.25)
+        amount: DecimalAmount,
+
+        /// Create users and tokens, that don't exist yet
+        #[arg(long)]
+        create_missing: bool,
+
+        #[command(flatten)]
+        details: TransactionDetailsArgs,
+    },
+
+    /// Send several transactions at once: either all of them are recorded or none
+    #[command(name = "tr-batch")]
+    TrBatch
+    {
+        /// File with one transaction per line, as JSON object with the arguments and options of "tr" as keys, e.g.
+        /// {"sender": "alice", "receiver": "bob", "token": "kudos", "amount": 2.5} (stdin, if missing or "-")
+        input: Option<PathBuf>,
+
+        /// Create users and tokens, that don't exist yet
+        #[arg(long)]
+        create_missing: bool,
+    },
+
+    /// Undo a transaction by recording a compensating one (the original stays in the history)
+    Reverse
+    {
+        /// ID of the transaction (see "history")
+        id: TransactionID,
 
-        /// Token "receiver"
-        receiver_id: UserID,
+        /// Why the transaction is reversed (kept as the memo of the reversal)
+        #[arg(long)]
+        reason: String,
+    },
+
+    /// Show the balance of a user for a token (everything received minus everything sent)
+    Balance
+    {
+        /// User (name or #id)
+        user: NameOrId,
 
-        /// Token ID
-        token_id: TokenID,
+        /// Token (name or #id)
+        token: NameOrId,
+    },
 
-        /// Amount
-        amount: TokenAmount,
+    /// Show individual transactions (newest first), e.g. who sent a user which points last week
+    History
+    {
+        #[command(flatten)]
+        query: HistoryArgs,
     },
 
     /// Show the amount of a _specific_ token a _specific_ user received from each other user
     LsUserToken
     {
-        /// User
-        user_id: UserID,
+        /// User (name or #id)
+        user: NameOrId,
 
-        /// Token
-        token_id: TokenID,
+        /// Token (name or #id)
+        token: NameOrId,
 
         /// Order output in ascending (asc) or descending (desc) order
         #[arg(value_enum, long, short = 'o', default_value_t=Order::Desc)]
@@ -79,8 +608,8 @@ pub enum Action
     /// Show the amount of _all_ tokens a _specific_ user received from each other user
     LsTokensByUser
     {
-        /// User
-        user_id: UserID,
+        /// User (name or #id)
+        user: NameOrId,
 
         /// Order output in ascending (asc) or descending (desc) order
         #[arg(value_enum, long, short = 'o', default_value_t=Order::Desc)]
@@ -93,8 +622,8 @@ pub enum Action
     /// Show the amount of a _specific_ token _all_ users received from each other user
     LsUsersByToken
     {
-        /// Token
-        token_id: TokenID,
+        /// Token (name or #id)
+        token: NameOrId,
 
         /// Order output in ascending (asc) or descending (desc) order
         #[arg(value_enum, long, short = 'o', default_value_t=Order::Desc)]
@@ -104,4 +633,47 @@ pub enum Action
         #[arg(value_enum, long = "order-by")]
         order_by: Option<OrderByReceiverOrSenderOrAmount>,
     },
+
+    /// Load users, tokens and past transactions, e.g. from a spreadsheet: either all records are imported or none
+    Import
+    {
+        /// File with one record per line, as JSON object or as CSV row below a header line with the keys, e.g.
+        /// {"type": "transaction", "sender": "alice", "receiver": "bob", "token": "kudos", "amount": 2.5,
+        /// "created_at": "2024-03-01"} (stdin, if missing or "-")
+        input: Option<PathBuf>,
+
+        /// Format of the input [default: csv for *.csv files, jsonl otherwise]
+        #[arg(value_enum, long)]
+        input_format: Option<ImportFormat>,
+
+        /// Only validate the records and report the failed ones
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Merge users (and tokens) whose names are the same according to the configured name normalization
+    Dedupe
+    {
+        /// Only report the duplicates
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Inspect or change the database schema version (migrations are otherwise applied automatically)
+    Migrate
+    {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MigrateAction
+{
+    /// List all known migrations and whether they have been applied
+    Status,
+    /// Apply all pending migrations
+    Up,
+    /// Revert the most recently applied migration
+    Down,
 }
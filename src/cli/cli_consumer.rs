@@ -1,15 +1,136 @@
-use crate::core::Core;
+use crate::core::{Core, DecimalAmount, TokenEdit, TransactionDetails, TransactionID};
+use crate::error::Error;
+use async_trait::async_trait;
 
-use super::*;
+use super::{output::Listing, *};
 
+/// Front end of the CLI: every method executes one [Action] by awaiting the corresponding [Core] call and returns
+/// the result as a [Listing], which the caller prints in the selected [OutputFormat].
+/// Errors are passed on, so the caller can turn them into an exit code (see [exit_code]).
+#[async_trait]
 pub trait CliConsumer
 {
-    fn query_user(core: &mut Core, name: Option<&str>);
-    fn query_token(core: &mut Core, name: Option<&str>);
-    fn transaction(core: &mut Core, sender_id: UserID, receiver_id: UserID, token_id: TokenID, amount: TokenAmount);
-    fn list_user_token(core: &mut Core, user_id: UserID, token_id: TokenID, order: Order, order_by: Option<OrderBySenderOrAmount>);
-    fn list_tokens_by_user(core: &mut Core, user_id: UserID, order: Order, order_by: Option<OrderByTokenOrSenderOrAmount>);
-    fn list_users_by_token(core: &mut Core, token_id: UserID, order: Order, order_by: Option<OrderByReceiverOrSenderOrAmount>);
-    fn create_user(core: &mut Core, name: &str);
-    fn create_token(core: &mut Core, name: &str);
+    async fn query_user(core: &mut Core, name: Option<&str>, matching: NameMatch) -> Result<Listing, Error>;
+    async fn query_token(core: &mut Core, name: Option<&str>, matching: NameMatch) -> Result<Listing, Error>;
+    async fn overdraft_policy(
+        core: &mut Core,
+        actor: Option<&NameOrId>,
+        token: &NameOrId,
+        policy: Option<OverdraftPolicy>,
+    ) -> Result<Listing, Error>;
+    async fn balance(core: &mut Core, user: &NameOrId, token: &NameOrId) -> Result<Listing, Error>;
+    async fn transaction(
+        core: &mut Core,
+        sender: &NameOrId,
+        receiver: &NameOrId,
+        token: &NameOrId,
+        amount: DecimalAmount,
+        create_missing: bool,
+        details: &TransactionDetails<'_>,
+    ) -> Result<Listing, Error>;
+    async fn batch_transaction(core: &mut Core, legs: &[LegArgs], create_missing: bool) -> Result<Listing, Error>;
+    async fn reverse_transaction(core: &mut Core, id: TransactionID, reason: &str) -> Result<Listing, Error>;
+    async fn query_history(core: &mut Core, query: &HistoryArgs) -> Result<Listing, Error>;
+    async fn list_user_token(
+        core: &mut Core,
+        user: &NameOrId,
+        token: &NameOrId,
+        order: Order,
+        order_by: Option<OrderBySenderOrAmount>,
+    ) -> Result<Listing, Error>;
+    async fn list_tokens_by_user(
+        core: &mut Core,
+        user: &NameOrId,
+        order: Order,
+        order_by: Option<OrderByTokenOrSenderOrAmount>,
+    ) -> Result<Listing, Error>;
+    async fn list_users_by_token(
+        core: &mut Core,
+        token: &NameOrId,
+        order: Order,
+        order_by: Option<OrderByReceiverOrSenderOrAmount>,
+    ) -> Result<Listing, Error>;
+    async fn create_user(core: &mut Core, name: &str) -> Result<Listing, Error>;
+    async fn create_token(core: &mut Core, name: &str, owner: Option<&NameOrId>) -> Result<Listing, Error>;
+    async fn edit_token(core: &mut Core, actor: &NameOrId, token: &NameOrId, edit: TokenEdit) -> Result<Listing, Error>;
+    async fn set_token_owner(core: &mut Core, actor: &NameOrId, token: &NameOrId, owner: &NameOrId) -> Result<Listing, Error>;
+    async fn set_minter(core: &mut Core, actor: &NameOrId, token: &NameOrId, user: &NameOrId, allowed: bool) -> Result<Listing, Error>;
+    async fn query_minters(core: &mut Core, token: &NameOrId) -> Result<Listing, Error>;
+    async fn mint(
+        core: &mut Core,
+        actor: &NameOrId,
+        receiver: &NameOrId,
+        token: &NameOrId,
+        amount: DecimalAmount,
+    ) -> Result<Listing, Error>;
+    async fn burn(core: &mut Core, actor: &NameOrId, holder: &NameOrId, token: &NameOrId, amount: DecimalAmount) -> Result<Listing, Error>;
+    async fn import(core: &mut Core, rows: &[(usize, ImportRow)], dry_run: bool) -> Result<Listing, Error>;
+    async fn dedupe(core: &mut Core, dry_run: bool) -> Result<Listing, Error>;
+    async fn migrate(core: &mut Core, action: MigrateAction) -> Result<Listing, Error>;
+
+    /// Dispatches the action to the matching method
+    async fn run(core: &mut Core, action: Action) -> Result<Listing, Error>
+    {
+        match action
+        {
+            Action::CreateUser { name } => Self::create_user(core, &name).await,
+            Action::UserList { name, matching } => Self::query_user(core, name.as_deref(), matching).await,
+            Action::CreateToken { name, owner } => Self::create_token(core, &name, owner.as_ref()).await,
+            Action::TokenList { name, matching } => Self::query_token(core, name.as_deref(), matching).await,
+            Action::TokenEdit { token, actor, edit } => Self::edit_token(core, &actor, &token, edit.into()).await,
+            Action::TokenOwner { token, owner, actor } => Self::set_token_owner(core, &actor, &token, &owner).await,
+            Action::MinterAdd { token, user, actor } => Self::set_minter(core, &actor, &token, &user, true).await,
+            Action::MinterRemove { token, user, actor } => Self::set_minter(core, &actor, &token, &user, false).await,
+            Action::MinterList { token } => Self::query_minters(core, &token).await,
+            Action::Mint {
+                receiver,
+                token,
+                amount,
+                actor,
+            } => Self::mint(core, &actor, &receiver, &token, amount).await,
+            Action::Burn {
+                holder,
+                token,
+                amount,
+                actor,
+            } => Self::burn(core, &actor, &holder, &token, amount).await,
+            Action::TokenPolicy { token, policy, actor } => Self::overdraft_policy(core, actor.as_ref(), &token, policy).await,
+            Action::Transaction {
+                sender,
+                receiver,
+                token,
+                amount,
+                create_missing,
+                details,
+            } => Self::transaction(core, &sender, &receiver, &token, amount, create_missing, &details.details()).await,
+            Action::TrBatch { input, create_missing } =>
+            {
+                let legs = parse_legs(&read_input(input.as_deref())?)?;
+                Self::batch_transaction(core, &legs, create_missing).await
+            }
+            Action::Reverse { id, reason } => Self::reverse_transaction(core, id, &reason).await,
+            Action::Balance { user, token } => Self::balance(core, &user, &token).await,
+            Action::History { query } => Self::query_history(core, &query).await,
+            Action::LsUserToken {
+                user,
+                token,
+                order,
+                order_by,
+            } => Self::list_user_token(core, &user, &token, order, order_by).await,
+            Action::LsTokensByUser { user, order, order_by } => Self::list_tokens_by_user(core, &user, order, order_by).await,
+            Action::LsUsersByToken { token, order, order_by } => Self::list_users_by_token(core, &token, order, order_by).await,
+            Action::Import {
+                input,
+                input_format,
+                dry_run,
+            } =>
+            {
+                let format = input_format.unwrap_or_else(|| ImportFormat::of(input.as_deref()));
+                let rows = parse_import(&read_input(input.as_deref())?, format)?;
+                Self::import(core, &rows, dry_run).await
+            }
+            Action::Dedupe { dry_run } => Self::dedupe(core, dry_run).await,
+            Action::Migrate { action } => Self::migrate(core, action).await,
+        }
+    }
 }
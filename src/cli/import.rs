@@ -0,0 +1,189 @@
+use super::{deserialize_amount, parse_time};
+use crate::core::DecimalAmount;
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Input format of "import"
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum ImportFormat
+{
+    /// Comma separated values (RFC 4180) with a header line, that names the keys of the records
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl ImportFormat
+{
+    /// Format according to the extension of _path_: CSV for ".csv" files, JSON lines for everything else
+    pub fn of(path: Option<&Path>) -> ImportFormat
+    {
+        match path
+            .and_then(Path::extension)
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"))
+        {
+            true => ImportFormat::Csv,
+            false => ImportFormat::Jsonl,
+        }
+    }
+}
+
+/// One record of "import". The "type" key (or CSV column) selects the kind of record, e.g.
+/// {"type": "transaction", "sender": "alice", "receiver": "bob", "token": "kudos", "amount": 2.5,
+/// "created_at": "2024-03-01"}. Users and tokens are referred to by name and created, if they don't exist yet.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ImportRow
+{
+    User
+    {
+        name: String
+    },
+    Token
+    {
+        name: String, owner: Option<String>
+    },
+    Transaction
+    {
+        sender:     String,
+        receiver:   String,
+        token:      String,
+        /// Amount as string or number, with up to as many decimal places as the token has
+        #[serde(deserialize_with = "deserialize_amount")]
+        amount:     DecimalAmount,
+        /// Original time of the transaction (like "history --since"), now if missing
+        #[serde(default, deserialize_with = "deserialize_time")]
+        created_at: Option<DateTime<Utc>>,
+        memo:       Option<String>,
+        #[serde(rename = "ref")]
+        reference:  Option<String>,
+    },
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+{
+    let value = String::deserialize(deserializer)?;
+    parse_time(&value).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Records of "import" and the lines they start on. Empty lines are skipped, empty CSV fields count as missing.
+pub fn parse_import(text: &str, format: ImportFormat) -> Result<Vec<(usize, ImportRow)>, Error>
+{
+    let objects = match format
+    {
+        ImportFormat::Jsonl => text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| match serde_json::from_str(line)
+            {
+                Ok(object) => Ok((index + 1, object)),
+                Err(err) => Err(Error::InvalidArgument(format!("line {}: {err}", index + 1))),
+            })
+            .collect::<Result<_, _>>()?,
+        ImportFormat::Csv => csv_objects(text)?,
+    };
+
+    objects
+        .into_iter()
+        .map(|(line, object)| match serde_json::from_value(object)
+        {
+            Ok(row) => Ok((line, row)),
+            Err(err) => Err(Error::InvalidArgument(format!("line {line}: {err}"))),
+        })
+        .collect()
+}
+
+/// The rows of CSV text as objects with the keys of the header line (without empty fields)
+fn csv_objects(text: &str) -> Result<Vec<(usize, Value)>, Error>
+{
+    let mut rows = csv_rows(text)?.into_iter();
+    let Some((_, header)) = rows.next()
+    else
+    {
+        return Ok(Vec::new());
+    };
+
+    rows.map(|(line, fields)| {
+        if fields.len() != header.len()
+        {
+            return Err(Error::InvalidArgument(format!(
+                "line {line}: {} fields instead of {} (see the header line)",
+                fields.len(),
+                header.len()
+            )));
+        }
+        let object: Map<String, Value> = header
+            .iter()
+            .zip(fields)
+            .filter(|(_, field)| !field.is_empty())
+            .map(|(key, field)| (key.clone(), Value::String(field)))
+            .collect();
+        Ok((line, Value::Object(object)))
+    })
+    .collect()
+}
+
+/// Fields of every non-empty CSV row and the line it starts on. Quoted fields may contain commas, line breaks and
+/// quotes (written as "").
+fn csv_rows(text: &str) -> Result<Vec<(usize, Vec<String>)>, Error>
+{
+    let mut rows = Vec::new();
+    let (mut fields, mut field) = (Vec::new(), String::new());
+    let (mut line, mut row_line, mut quoted) = (1, 1, false);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next()
+    {
+        match (quoted, c)
+        {
+            (true, '"') if chars.peek() == Some(&'"') =>
+            {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => (),
+            (false, '\n') =>
+            {
+                fields.push(std::mem::take(&mut field));
+                let row = std::mem::take(&mut fields);
+                if !is_blank(&row)
+                {
+                    rows.push((row_line, row));
+                }
+                line += 1;
+                row_line = line;
+            }
+            (_, c) =>
+            {
+                if c == '\n'
+                {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+
+    if quoted
+    {
+        return Err(Error::InvalidArgument(format!("line {row_line}: unterminated quoted field")));
+    }
+    fields.push(field);
+    if !is_blank(&fields)
+    {
+        rows.push((row_line, fields));
+    }
+    Ok(rows)
+}
+
+fn is_blank(fields: &[String]) -> bool
+{
+    matches!(fields, [field] if field.trim().is_empty())
+}
@@ -0,0 +1,201 @@
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+
+/// Output format of the command results
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum OutputFormat
+{
+    /// Aligned columns for humans
+    Table,
+    /// One JSON array of objects
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    /// Comma separated values (RFC 4180)
+    Csv,
+    /// Tab separated values, with tabs, line breaks and backslashes escaped as "\t", "\n" and "\\"
+    Tsv,
+}
+
+/// Result of a CLI command: rows with a fixed set of named columns.
+/// The column names are part of the interface (JSON keys and CSV/TSV headers), so they must stay stable.
+#[derive(Debug)]
+pub struct Listing
+{
+    columns: Vec<&'static str>,
+    rows:    Vec<Vec<Value>>,
+}
+
+impl Listing
+{
+    pub fn new(columns: &[&'static str]) -> Listing
+    {
+        Listing {
+            columns: columns.to_vec(),
+            rows:    Vec::new(),
+        }
+    }
+
+    /// Appends a row, which needs one value per column
+    pub fn push(&mut self, row: Vec<Value>)
+    {
+        assert_eq!(row.len(), self.columns.len(), "row doesn't match the columns {:?}", self.columns);
+        self.rows.push(row);
+    }
+
+    /// Listing of a command without result, which prints nothing at all
+    pub fn empty() -> Listing
+    {
+        Listing::new(&[])
+    }
+
+    /// Writes all rows in the given format. Without _header_, the header line of the table, CSV and TSV formats is omitted.
+    pub fn write(&self, out: &mut impl Write, format: OutputFormat, header: bool) -> io::Result<()>
+    {
+        if self.columns.is_empty()
+        {
+            return Ok(());
+        }
+
+        match format
+        {
+            OutputFormat::Table => self.write_table(out, header),
+            OutputFormat::Json =>
+            {
+                let objects: Vec<Value> = self.objects().collect();
+                serde_json::to_writer_pretty(&mut *out, &objects)?;
+                writeln!(out)
+            }
+            OutputFormat::Jsonl =>
+            {
+                for object in self.objects()
+                {
+                    serde_json::to_writer(&mut *out, &object)?;
+                    writeln!(out)?;
+                }
+                Ok(())
+            }
+            OutputFormat::Csv => self.write_separated(out, header, ",", csv_field),
+            OutputFormat::Tsv => self.write_separated(out, header, "\t", tsv_field),
+        }
+    }
+
+    fn objects(&self) -> impl Iterator<Item = Value> + '_
+    {
+        self.rows.iter().map(|row| {
+            Value::Object(
+                self.columns
+                    .iter()
+                    .map(|column| column.to_string())
+                    .zip(row.iter().cloned())
+                    .collect::<Map<String, Value>>(),
+            )
+        })
+    }
+
+    fn write_separated(&self, out: &mut impl Write, header: bool, separator: &str, escape: fn(&str) -> String) -> io::Result<()>
+    {
+        if header
+        {
+            let names: Vec<String> = self.columns.iter().map(|column| escape(column)).collect();
+            writeln!(out, "{}", names.join(separator))?;
+        }
+        for row in &self.rows
+        {
+            let fields: Vec<String> = row.iter().map(|value| escape(&plain(value))).collect();
+            writeln!(out, "{}", fields.join(separator))?;
+        }
+        Ok(())
+    }
+
+    fn write_table(&self, out: &mut impl Write, header: bool) -> io::Result<()>
+    {
+        let cells: Vec<Vec<String>> = self.rows.iter().map(|row| row.iter().map(plain).collect()).collect();
+
+        let mut widths: Vec<usize> = match header
+        {
+            true => self.columns.iter().map(|column| column.chars().count()).collect(),
+            false => vec![0; self.columns.len()],
+        };
+        for row in &cells
+        {
+            for (width, cell) in widths.iter_mut().zip(row)
+            {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        // Numbers (and formatted amounts) are right aligned, everything else left aligned
+        let numeric: Vec<bool> = (0..self.columns.len())
+            .map(|column| !self.rows.is_empty() && self.rows.iter().all(|row| is_numeric(&row[column])))
+            .collect();
+
+        let mut write_line = |cells: &[String]| -> io::Result<()> {
+            let line: Vec<String> = cells
+                .iter()
+                .enumerate()
+                .map(|(column, cell)| match numeric[column]
+                {
+                    true => format!("{cell:>width$}", width = widths[column]),
+                    false => format!("{cell:<width$}", width = widths[column]),
+                })
+                .collect();
+            writeln!(out, "{}", line.join("  ").trim_end())
+        };
+
+        if header
+        {
+            write_line(&self.columns.iter().map(|column| column.to_string()).collect::<Vec<_>>())?;
+        }
+        for row in &cells
+        {
+            write_line(row)?;
+        }
+        Ok(())
+    }
+}
+
+/// Text representation of a value (strings without quotes)
+fn plain(value: &Value) -> String
+{
+    match value
+    {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Numbers and formatted amounts like "-12.50 KDS"
+fn is_numeric(value: &Value) -> bool
+{
+    match value
+    {
+        Value::Number(_) => true,
+        Value::String(text) =>
+        {
+            let number = text.split(' ').next().unwrap_or_default();
+            let digits = number.strip_prefix('-').unwrap_or(number);
+            digits.starts_with(|c: char| c.is_ascii_digit()) && digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+        }
+        _ => false,
+    }
+}
+
+fn csv_field(text: &str) -> String
+{
+    match text.contains([',', '"', '\n', '\r'])
+    {
+        true => format!("\"{}\"", text.replace('"', "\"\"")),
+        false => text.to_string(),
+    }
+}
+
+fn tsv_field(text: &str) -> String
+{
+    text.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
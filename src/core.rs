@@ -1,14 +1,31 @@
+use crate::error::{Error, Identifier};
+use chrono::{DateTime, SecondsFormat, Utc};
 use clap::ValueEnum;
+use data_file::DataFile;
+use data_postgres::DataPostgres;
 use data_sqlite::DataSQLite;
 use persistance_layer::*;
-use tokio::{spawn, task::JoinHandle};
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
 
+pub use config::{CoreConfig, JournalMode, DATABASE_URL_ENV};
+pub use names::{DedupeReport, MergedNames, NameNormalization};
+
+mod config;
+mod data_file;
+mod data_postgres;
 mod data_sqlite;
-mod persistance_layer;
+mod names;
+pub mod persistance_layer;
+mod sql_common;
 
 pub type UserID = DbPk;
 pub type TokenID = DbPk;
-pub type TokenAmount = i32;
+pub type TransactionID = DbPk;
+/// Amounts, totals and balances. All arithmetic on them is checked and fails with [Error::Overflow].
+pub type TokenAmount = i64;
 
 /// Order output in ascending (asc) or descending (desc) order
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -40,23 +57,389 @@ pub enum OrderByReceiverOrSenderOrAmount
     Amount,
 }
 
+/// How a searched name is compared with the names of users or tokens
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum NameMatch
+{
+    /// Same name, as far as names are told apart at all (see [NameNormalization])
+    Exact,
+    /// Same name, ignoring upper and lower case
+    IgnoreCase,
+    /// Name starts with the search term (ignoring case)
+    Prefix,
+    /// Name contains the search term (ignoring case)
+    Substring,
+}
+
+impl NameMatch
+{
+    /// Whether _name_ matches the search term _search_ in this mode. [NameMatch::Exact] compares the names as they
+    /// are, without their normalization.
+    pub fn matches(self, name: &str, search: &str) -> bool
+    {
+        match self
+        {
+            NameMatch::Exact => name == search,
+            NameMatch::IgnoreCase => name.to_lowercase() == search.to_lowercase(),
+            NameMatch::Prefix => name.to_lowercase().starts_with(&search.to_lowercase()),
+            NameMatch::Substring => name.to_lowercase().contains(&search.to_lowercase()),
+        }
+    }
+}
+
+/// Whether users can send more of a token than they hold (see [Core::set_overdraft_policy]).
+///
+/// The balance of a user is everything received minus everything sent. A transaction with a negative amount takes
+/// points back, so the receiver's balance decreases instead.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub enum OverdraftPolicy
+{
+    /// Points are created out of thin air (no balance is required). On a token with an owner, only those who may
+    /// issue points anyway (see [Core::mint]) get to do so, everybody else's balance must cover the amount.
+    #[default]
+    Free,
+    /// The balance must cover the amount
+    BalanceBacked,
+    /// The balance may drop down to minus the given limit
+    CreditLimit(TokenAmount),
+}
+
+impl OverdraftPolicy
+{
+    /// Lowest balance allowed, negated (_None_ means unlimited). This is how the policy gets stored.
+    pub fn credit_limit(self) -> Option<TokenAmount>
+    {
+        match self
+        {
+            OverdraftPolicy::Free => None,
+            OverdraftPolicy::BalanceBacked => Some(0),
+            OverdraftPolicy::CreditLimit(limit) => Some(limit),
+        }
+    }
+
+    pub fn from_credit_limit(credit_limit: Option<TokenAmount>) -> OverdraftPolicy
+    {
+        match credit_limit
+        {
+            None => OverdraftPolicy::Free,
+            Some(0) => OverdraftPolicy::BalanceBacked,
+            Some(limit) => OverdraftPolicy::CreditLimit(limit),
+        }
+    }
+
+    /// The user, whose balance a transaction decreases, and by how much (_None_, if nobody's balance decreases)
+    pub(crate) fn debited(sender_id: UserID, receiver_id: UserID, amount: TokenAmount) -> Result<Option<(UserID, TokenAmount)>, Error>
+    {
+        if sender_id == receiver_id
+        {
+            return Ok(None);
+        }
+        Ok(match amount.cmp(&0)
+        {
+            std::cmp::Ordering::Greater => Some((sender_id, amount)),
+            std::cmp::Ordering::Less => Some((receiver_id, amount.checked_neg().ok_or(Error::Overflow)?)),
+            std::cmp::Ordering::Equal => None,
+        })
+    }
+
+    /// [OverdraftPolicy::debited], if this policy limits the debited user at all
+    pub(crate) fn limited_debit(
+        self,
+        sender_id: UserID,
+        receiver_id: UserID,
+        amount: TokenAmount,
+    ) -> Result<Option<(UserID, TokenAmount)>, Error>
+    {
+        Ok(Self::debited(sender_id, receiver_id, amount)?.filter(|_| self != OverdraftPolicy::Free))
+    }
+
+    /// Fails with [Error::InsufficientBalance], if a user with _balance_ may not give away _amount_
+    pub(crate) fn check(self, balance: TokenAmount, amount: TokenAmount) -> Result<(), Error>
+    {
+        let Some(limit) = self.credit_limit()
+        else
+        {
+            return Ok(());
+        };
+
+        // Beyond the range of amounts, the balance covers any amount anyway
+        let available = balance.saturating_add(limit);
+        match amount <= available
+        {
+            true => Ok(()),
+            false => Err(Error::InsufficientBalance {
+                available,
+                required: amount,
+            }),
+        }
+    }
+}
+
+/// "free", "balance-backed" or "credit-limit(n)" (or "credit-limit=n")
+impl FromStr for OverdraftPolicy
+{
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err>
+    {
+        let limit = value
+            .strip_prefix("credit-limit(")
+            .and_then(|limit| limit.strip_suffix(')'))
+            .or_else(|| value.strip_prefix("credit-limit="));
+        match (value, limit)
+        {
+            ("free", _) => Ok(OverdraftPolicy::Free),
+            ("balance-backed", _) => Ok(OverdraftPolicy::BalanceBacked),
+            (_, Some(limit)) => match limit.trim().parse()
+            {
+                Ok(limit) if limit >= 0 => Ok(OverdraftPolicy::CreditLimit(limit)),
+                _ => Err(format!("invalid credit limit \"{limit}\"")),
+            },
+            _ => Err(format!(
+                "invalid overdraft policy \"{value}\" (expected free, balance-backed or credit-limit(n))"
+            )),
+        }
+    }
+}
+
+impl Display for OverdraftPolicy
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            OverdraftPolicy::Free => write!(f, "free"),
+            OverdraftPolicy::BalanceBacked => write!(f, "balance-backed"),
+            OverdraftPolicy::CreditLimit(limit) => write!(f, "credit-limit({limit})"),
+        }
+    }
+}
+
+/// What a user is about to do with a token, which requires some rights (see [Error::NotAuthorized])
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum TokenRight
+{
+    /// Hand the token over to another owner (or claim it, if it has no owner yet)
+    Transfer,
+    /// Grant or revoke minting rights
+    Delegate,
+    /// Mint or burn points
+    Issue,
+    /// Change the description, symbol, decimals or metadata (and the overdraft policy)
+    Edit,
+    /// Send more than the balance, while the token's policy is [OverdraftPolicy::Free]
+    Overdraw,
+}
+
+impl TokenRight
+{
+    /// Whether the user has this right for a token with the given owner
+    pub(crate) fn allows(self, user_id: UserID, owner_id: Option<UserID>, is_minter: bool) -> bool
+    {
+        match self
+        {
+            TokenRight::Transfer | TokenRight::Edit => owner_id.is_none() || owner_id == Some(user_id),
+            TokenRight::Delegate => owner_id == Some(user_id),
+            TokenRight::Issue => owner_id == Some(user_id) || is_minter,
+            TokenRight::Overdraw => owner_id.is_none() || owner_id == Some(user_id) || is_minter,
+        }
+    }
+
+    /// Fails with [Error::NotAuthorized], unless the user has this right
+    pub(crate) fn check(self, user_id: UserID, token_id: TokenID, owner_id: Option<UserID>, is_minter: bool) -> Result<(), Error>
+    {
+        match self.allows(user_id, owner_id, is_minter)
+        {
+            true => Ok(()),
+            false => Err(Error::NotAuthorized {
+                user:  Identifier::Id(user_id),
+                token: Identifier::Id(token_id),
+            }),
+        }
+    }
+}
+
+/// Amount as users write it, e.g. "2.25", before it is scaled to the smallest unit of a token (see [Token::decimals])
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DecimalAmount
+{
+    /// All digits without the decimal point, e.g. 225 for "2.25"
+    pub digits: TokenAmount,
+    /// Number of decimal places, e.g. 2 for "2.25"
+    pub scale:  u8,
+}
+
+impl DecimalAmount
+{
+    /// The amount in units of a token with _decimals_ decimal places (225 for "2.25" with 2 decimals, 2250 with 3).
+    /// Fails with [Error::InvalidArgument], if the amount is more precise than the token.
+    pub fn scaled(self, decimals: u8) -> Result<TokenAmount, Error>
+    {
+        let Some(shift) = decimals.checked_sub(self.scale)
+        else
+        {
+            return Err(Error::InvalidArgument(format!("{self} has more than {decimals} decimal places")));
+        };
+        10_i64
+            .checked_pow(shift.into())
+            .and_then(|factor| self.digits.checked_mul(factor))
+            .ok_or(Error::Overflow)
+    }
+}
+
+impl From<TokenAmount> for DecimalAmount
+{
+    fn from(digits: TokenAmount) -> Self
+    {
+        DecimalAmount { digits, scale: 0 }
+    }
+}
+
+impl FromStr for DecimalAmount
+{
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err>
+    {
+        let (whole, fraction) = value.split_once('.').unwrap_or((value, "0"));
+        let unsigned = whole.strip_prefix(['-', '+']).unwrap_or(whole);
+        let is_number = |digits: &str| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit());
+        if !is_number(unsigned) || !is_number(fraction)
+        {
+            return Err(format!("invalid amount \"{value}\""));
+        }
+
+        // Trailing zeros don't make an amount more precise ("2.50" is fine for a token with 1 decimal)
+        let fraction = fraction.trim_end_matches('0');
+        let scale = u8::try_from(fraction.len())
+            .ok()
+            .filter(|&scale| scale <= TokenEdit::MAX_DECIMALS)
+            .ok_or_else(|| format!("amount \"{value}\" has more than {} decimal places", TokenEdit::MAX_DECIMALS))?;
+        let digits = format!("{whole}{fraction}")
+            .parse()
+            .map_err(|_| format!("amount \"{value}\" is out of range"))?;
+        Ok(DecimalAmount { digits, scale })
+    }
+}
+
+impl Display for DecimalAmount
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        match self.scale
+        {
+            0 => write!(f, "{}", self.digits),
+            scale =>
+            {
+                let unit = 10_u64.pow(scale.into());
+                let sign = if self.digits < 0 { "-" } else { "" };
+                let (whole, fraction) = (self.digits.unsigned_abs() / unit, self.digits.unsigned_abs() % unit);
+                write!(f, "{sign}{whole}.{fraction:0width$}", width = usize::from(scale))
+            }
+        }
+    }
+}
+
+/// Changes to the descriptive fields of a token (see [Core::edit_token]).
+/// _None_ leaves a field unchanged, _Some(None)_ removes the description or symbol.
+#[derive(Clone, Default, Debug)]
+pub struct TokenEdit
+{
+    pub description: Option<Option<String>>,
+    pub symbol:      Option<Option<String>>,
+    pub decimals:    Option<u8>,
+    /// Metadata entries to set, or to remove (_None_)
+    pub metadata:    Vec<(String, Option<String>)>,
+}
+
+impl TokenEdit
+{
+    /// Most decimal places a token may have, so every [TokenAmount] can be displayed
+    pub const MAX_DECIMALS: u8 = 18;
+    /// Most characters a symbol may have
+    pub const MAX_SYMBOL_LENGTH: usize = 8;
+
+    /// Fails with [Error::InvalidArgument], if the changes would result in an invalid token
+    fn validate(&self) -> Result<(), Error>
+    {
+        if let Some(decimals) = self.decimals.filter(|&decimals| decimals > Self::MAX_DECIMALS)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "{decimals} decimals (at most {} are allowed)",
+                Self::MAX_DECIMALS
+            )));
+        }
+        if let Some(Some(symbol)) = &self.symbol
+        {
+            if symbol.trim().is_empty() || symbol.chars().any(char::is_whitespace) || symbol.chars().count() > Self::MAX_SYMBOL_LENGTH
+            {
+                return Err(Error::InvalidArgument(format!(
+                    "symbol \"{symbol}\" (expected up to {} characters without spaces)",
+                    Self::MAX_SYMBOL_LENGTH
+                )));
+            }
+        }
+        if self.metadata.iter().any(|(key, _)| key.is_empty())
+        {
+            return Err(Error::InvalidArgument("empty metadata key".into()));
+        }
+        Ok(())
+    }
+
+    /// Whether the decimals of the token would change. Amounts are stored in the smallest unit of a token, so that is
+    /// only allowed until the token has been used in a transaction (see [Error::DecimalsInUse]).
+    pub(crate) fn changes_decimals(&self, token: &Token) -> bool
+    {
+        self.decimals.is_some_and(|decimals| decimals != token.decimals)
+    }
+
+    /// Applies the changes to the in-memory representation of a token
+    pub(crate) fn apply(&self, token: &mut Token)
+    {
+        if let Some(description) = &self.description
+        {
+            token.description = description.clone();
+        }
+        if let Some(symbol) = &self.symbol
+        {
+            token.symbol = symbol.clone();
+        }
+        if let Some(decimals) = self.decimals
+        {
+            token.decimals = decimals;
+        }
+        for (key, value) in &self.metadata
+        {
+            match value
+            {
+                Some(value) => token.metadata.insert(key.clone(), value.clone()),
+                None => token.metadata.remove(key),
+            };
+        }
+    }
+}
+
 // Query options
+#[derive(Copy, Clone, Debug)]
 pub enum UserQueryModeStrict<'a>
 {
     ById(UserID),
     ByName(&'a str),
 }
+#[derive(Copy, Clone, Debug)]
 pub enum UserQueryModeWithCreation<'a>
 {
     ById(UserID),
     ByName(&'a str),
     ByNameOrCreate(&'a str),
 }
+#[derive(Copy, Clone, Debug)]
 pub enum TokenQueryModeStrict<'a>
 {
     ById(TokenID),
     ByName(&'a str),
 }
+#[derive(Copy, Clone, Debug)]
 pub enum TokenQueryModeWithCreation<'a>
 {
     ById(TokenID),
@@ -64,287 +447,548 @@ pub enum TokenQueryModeWithCreation<'a>
     ByNameOrCreate(&'a str),
 }
 
-// =================================================================================================================
-pub struct Core
+impl<'a> From<UserQueryModeStrict<'a>> for UserQueryModeWithCreation<'a>
 {
-    db: DbImplementors,
+    fn from(value: UserQueryModeStrict<'a>) -> Self
+    {
+        match value
+        {
+            UserQueryModeStrict::ById(id) => UserQueryModeWithCreation::ById(id),
+            UserQueryModeStrict::ByName(name) => UserQueryModeWithCreation::ByName(name),
+        }
+    }
 }
-
-#[derive(Debug)]
-struct RelativeUserAmountEntry
+impl<'a> From<TokenQueryModeStrict<'a>> for TokenQueryModeWithCreation<'a>
 {
-    pub receiver:         User,
-    pub amount_by_sender: Vec<RelativeUserTokenAmountEntry>,
+    fn from(value: TokenQueryModeStrict<'a>) -> Self
+    {
+        match value
+        {
+            TokenQueryModeStrict::ById(id) => TokenQueryModeWithCreation::ById(id),
+            TokenQueryModeStrict::ByName(name) => TokenQueryModeWithCreation::ByName(name),
+        }
+    }
 }
 
-#[derive(Debug)]
-struct RelativeTokenAmountEntry
+/// Optional details of a transaction, that are kept in its history entry (see [Core::transaction_with_details])
+#[derive(Clone, Default, Debug)]
+pub struct TransactionDetails<'a>
 {
-    pub token:            Token,
-    pub amount_by_sender: Vec<RelativeUserTokenAmountEntry>,
+    /// Free text, e.g. "thanks for the code review"
+    pub memo:            Option<String>,
+    /// External reference, e.g. a ticket ID or a message link
+    pub reference:       Option<String>,
+    /// User, who records the transaction on behalf of the sender (only kept, if it's somebody else)
+    pub actor:           Option<UserQueryModeStrict<'a>>,
+    /// Client-supplied key, that makes retries safe: a transaction with a key, that has been used within the
+    /// retention window ([CoreConfig::idempotency_retention]), returns the original new total without recording
+    /// anything. Using the key for different users, token or amount fails with [Error::InvalidArgument].
+    pub idempotency_key: Option<String>,
 }
 
-#[derive(Debug)]
-struct RelativeUserTokenAmountEntry
+/// One transaction of a batch (see [Core::batch_transaction])
+#[derive(Clone, Debug)]
+pub struct TransferLeg<'a>
 {
-    pub sender: User,
-    pub amount: TokenAmount,
+    pub sender:   UserQueryModeWithCreation<'a>,
+    pub receiver: UserQueryModeWithCreation<'a>,
+    /// Tokens, that have to be created, are owned by the sender of the leg
+    pub token:    TokenQueryModeWithCreation<'a>,
+    pub amount:   TokenAmount,
+    pub details:  TransactionDetails<'a>,
 }
 
-// TODO {CustomErrorType}
-#[derive(Debug)]
-enum DeferredUserId
+/// One record of an import (see [Core::import]). Users and tokens are looked up by name and created, if they don't
+/// exist yet (like [UserQueryModeWithCreation::ByNameOrCreate]).
+#[derive(Clone, Debug)]
+pub enum ImportRecord<'a>
 {
-    Immediate(DbPk),
-    Deferred(JoinHandle<Result<User, sqlx::Error>>),
+    User
+    {
+        name: &'a str
+    },
+    /// Tokens, that already exist, keep their owner
+    Token
+    {
+        name: &'a str, owner: Option<&'a str>
+    },
+    /// Transaction, that happened at _created_at_ (or now, if unknown)
+    Transaction
+    {
+        leg:        TransferLeg<'a>,
+        created_at: Option<DateTime<Utc>>,
+    },
 }
 
-// TODO {CustomErrorType}
-#[derive(Debug)]
-enum DeferredTokenId
+/// Result of [Core::import]
+#[derive(Default, Debug)]
+pub struct ImportReport
 {
-    Immediate(DbPk),
-    Deferred(JoinHandle<Result<Token, sqlx::Error>>),
+    /// Number of users, that have been created (or would have been, without the failed records or in a dry run)
+    pub users:        usize,
+    /// Number of tokens, that have been created (see [ImportReport::users])
+    pub tokens:       usize,
+    /// Number of transactions, that have been recorded (see [ImportReport::users])
+    pub transactions: usize,
+    /// Records, that can't be imported: their index and the reason
+    pub failures:     Vec<(usize, Error)>,
+    /// Whether the import has been committed
+    pub committed:    bool,
 }
 
-impl Core
+impl ImportReport
 {
-    // TODO: Make PersistanceLayer configurable
-    pub async fn new() -> Core
+    /// Notes that the record at _index_ can't be imported. Errors of the backend itself abort the whole import instead.
+    pub(crate) fn add_failure(&mut self, index: usize, err: Error) -> Result<(), Error>
     {
-        Core {
-            db: DbImplementors::SQLite(DataSQLite::new().await), // TODO
+        match err
+        {
+            Error::Storage(_) | Error::BackendUnavailable(_) => Err(err),
+            err =>
+            {
+                self.failures.push((index, err));
+                Ok(())
+            }
         }
     }
+}
 
-    // =========================================== Workaround helper methods ===========================================
-    // WORKAROUND {AsyncPersistanceLayerTraitObject}:
-    //      Since async in trait objects is not a thing yet in rust, these helper methods will hide the enum for now.
-    async fn db_create_user(&self, name: &str) -> Result<User, sqlx::Error>
+/// Which transactions [Core::query_history] returns. Unset fields match every transaction.
+#[derive(Clone, Default, Debug)]
+pub struct HistoryFilter<'a>
+{
+    pub sender:     Option<UserQueryModeStrict<'a>>,
+    pub receiver:   Option<UserQueryModeStrict<'a>>,
+    pub token:      Option<TokenQueryModeStrict<'a>>,
+    /// Smallest amount (inclusive)
+    pub min_amount: Option<TokenAmount>,
+    /// Largest amount (inclusive)
+    pub max_amount: Option<TokenAmount>,
+    /// Earliest time (inclusive)
+    pub since:      Option<DateTime<Utc>>,
+    /// Time, before which the transactions happened (exclusive)
+    pub until:      Option<DateTime<Utc>>,
+    /// Text, that the memo contains (ignoring case)
+    pub memo:       Option<String>,
+}
+
+/// Position in the history, after which the next page of [Core::query_history] starts.
+/// Transactions are ordered by time, and by ID if they happened at the same time.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct HistoryCursor
+{
+    pub created_at: Option<DateTime<Utc>>,
+    pub id:         DbPk,
+}
+
+impl HistoryCursor
+{
+    /// Cursor of a page, that continues after _entry_
+    pub fn after(entry: &HistoryEntry) -> HistoryCursor
     {
-        match &self.db
-        {
-            DbImplementors::SQLite(db) => db.create_user(name).await,
+        HistoryCursor {
+            created_at: entry.created_at,
+            id:         entry.id,
         }
     }
-    async fn db_get_all_users(&self) -> Result<Vec<User>, sqlx::Error>
+}
+
+/// "<id>@<RFC 3339 time>", or just "<id>" without a time
+impl Display for HistoryCursor
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
     {
-        match &self.db
+        match self.created_at
         {
-            DbImplementors::SQLite(db) => db.get_all_users().await,
+            Some(created_at) => write!(f, "{}@{}", self.id, created_at.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+            None => write!(f, "{}", self.id),
         }
     }
-    async fn db_query_user(&self, name: &str) -> Result<Vec<User>, sqlx::Error>
+}
+
+impl FromStr for HistoryCursor
+{
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err>
     {
-        match &self.db
+        let (id, created_at) = match value.split_once('@')
         {
-            DbImplementors::SQLite(db) => db.query_user(name).await,
-        }
+            Some((id, created_at)) => (id, Some(created_at)),
+            None => (value, None),
+        };
+        let invalid = || format!("invalid cursor \"{value}\"");
+        Ok(HistoryCursor {
+            created_at: created_at
+                .map(|created_at| DateTime::parse_from_rfc3339(created_at).map(|time| time.to_utc()))
+                .transpose()
+                .map_err(|_| invalid())?,
+            id:         id.parse().map_err(|_| invalid())?,
+        })
     }
-    async fn db_create_token(&self, name: &str) -> Result<Token, sqlx::Error>
+}
+
+/// One page of [Core::query_history]
+#[derive(Debug)]
+pub struct HistoryPage
+{
+    pub entries: Vec<HistoryEntry>,
+    /// Where the next page starts (_None_, if this is the last page)
+    pub next:    Option<HistoryCursor>,
+}
+
+/// The only element of _matches_, the result of looking up _name_
+fn single_match<T>(mut matches: Vec<T>, name: &str, not_found: fn(Identifier) -> Error) -> Result<T, Error>
+{
+    match matches.len()
     {
-        match &self.db
-        {
-            DbImplementors::SQLite(db) => db.create_token(name).await,
-        }
+        0 => Err(not_found(Identifier::Name(name.to_string()))),
+        1 => Ok(matches.remove(0)),
+        matches => Err(Error::AmbiguousName {
+            name: name.to_string(),
+            matches,
+        }),
     }
-    async fn db_query_token(&self, name: &str) -> Result<Vec<Token>, sqlx::Error>
+}
+
+// =================================================================================================================
+pub struct Core
+{
+    db: Box<dyn PersistanceLayer>,
+}
+
+impl Core
+{
+    /// Connects to the persistance layer selected by the scheme of the configured database URL.
+    pub async fn new(config: &CoreConfig) -> Result<Core, Error>
     {
-        match &self.db
+        let db: Box<dyn PersistanceLayer> = match config.database_url.split_once(':').map(|(scheme, _)| scheme)
         {
-            DbImplementors::SQLite(db) => db.query_token(name).await,
-        }
+            Some("sqlite") => Box::new(DataSQLite::new(config).await?),
+            Some("postgres" | "postgresql") => Box::new(DataPostgres::new(config).await?),
+            Some("file") => Box::new(DataFile::new(config)?),
+            _ => return Err(Error::Config(format!("unsupported database URL \"{}\"", config.database_url))),
+        };
+
+        Ok(Core { db })
     }
-    async fn db_get_all_tokens(&self) -> Result<Vec<Token>, sqlx::Error>
+
+    /// Core backed by a fresh in-memory database. Nothing is persisted, which is useful for tests and demos.
+    pub async fn new_in_memory() -> Result<Core, Error>
     {
-        match &self.db
-        {
-            DbImplementors::SQLite(db) => db.get_all_tokens().await,
-        }
+        Ok(Core::with_persistance_layer(Box::new(DataSQLite::new_in_memory().await?)))
     }
-    async fn db_get_current_total(
+
+    /// Core on top of any (e.g. third-party) persistance layer implementation
+    pub fn with_persistance_layer(db: Box<dyn PersistanceLayer>) -> Core
+    {
+        Core { db }
+    }
+
+    // ================================================ Schema Migrations ================================================
+    pub async fn migration_status(&self) -> Result<Vec<MigrationInfo>, Error>
+    {
+        self.db.migration_status().await
+    }
+
+    pub async fn migrate_up(&self) -> Result<(), Error>
+    {
+        self.db.migrate_up().await
+    }
+
+    /// Reverts the most recently applied migration and returns it (_None_, if there was nothing to revert)
+    pub async fn migrate_down(&self) -> Result<Option<MigrationInfo>, Error>
+    {
+        self.db.migrate_down().await
+    }
+
+    /// Merges users (and tokens) whose names are the same with respect to the configured [NameNormalization]
+    /// into the one with the lowest ID. Their transactions are kept and attributed to the remaining user (or token).
+    ///
+    /// With _dry_run_, the duplicates are only reported.
+    pub async fn dedupe(&self, dry_run: bool) -> Result<DedupeReport, Error>
+    {
+        self.db.dedupe(dry_run).await
+    }
+
+    // ================================================ User Management ================================================
+    pub async fn create_user(&self, name: &str) -> Result<User, Error>
+    {
+        self.db.create_user(name).await
+    }
+
+    pub async fn query_all_users(&self) -> Result<Vec<User>, Error>
+    {
+        self.db.get_all_users().await
+    }
+
+    /// Users whose name matches _name_ (see [NameMatch]), ordered by ID.
+    /// "%" and "_" have no special meaning in any mode.
+    pub async fn query_user(&self, name: &str, matching: NameMatch) -> Result<Vec<User>, Error>
+    {
+        self.db.query_user(name, matching).await
+    }
+
+    /// The one user whose name matches _name_ (see [Core::query_user]). Fails with [Error::AmbiguousName], if several
+    /// users match.
+    pub async fn find_user(&self, name: &str, matching: NameMatch) -> Result<User, Error>
+    {
+        single_match(self.db.query_user(name, matching).await?, name, Error::UserNotFound)
+    }
+
+    // ================================================ Token Management ================================================
+    /// Creates a token, that is issued by _owner_ (see [Core::mint])
+    pub async fn create_token(&self, name: &str, owner: Option<UserQueryModeStrict<'_>>) -> Result<Token, Error>
+    {
+        self.db.create_token(name, owner).await
+    }
+
+    pub async fn query_all_tokens(&self) -> Result<Vec<Token>, Error>
+    {
+        self.db.get_all_tokens().await
+    }
+
+    /// Tokens whose name matches _name_ (see [NameMatch]), ordered by ID.
+    /// "%" and "_" have no special meaning in any mode.
+    pub async fn query_token(&self, name: &str, matching: NameMatch) -> Result<Vec<Token>, Error>
+    {
+        self.db.query_token(name, matching).await
+    }
+
+    /// The one token whose name matches _name_ (see [Core::query_token]). Fails with [Error::AmbiguousName], if several
+    /// tokens match.
+    pub async fn find_token(&self, name: &str, matching: NameMatch) -> Result<Token, Error>
+    {
+        single_match(self.db.query_token(name, matching).await?, name, Error::TokenNotFound)
+    }
+
+    /// Token including its metadata
+    pub async fn get_token(&self, token: TokenQueryModeStrict<'_>) -> Result<Token, Error>
+    {
+        self.db.get_token(token).await
+    }
+
+    /// Changes the description, symbol, decimals or metadata of the token. Only the owner may do this, unless the
+    /// token has no owner yet (see [Core::set_token_owner]).
+    ///
+    /// Changing the decimals doesn't touch any amounts, just how they are displayed (see [Token::format_amount]).
+    pub async fn edit_token(
         &self,
-        sender: UserQueryModeStrict<'_>,
-        receiver: UserQueryModeStrict<'_>,
+        actor: UserQueryModeStrict<'_>,
         token: TokenQueryModeStrict<'_>,
-    ) -> Result<Option<TokenAmount>, sqlx::Error>
+        edit: &TokenEdit,
+    ) -> Result<Token, Error>
     {
-        match &self.db
-        {
-            DbImplementors::SQLite(db) => db.get_current_total(sender, receiver, token).await,
-        }
+        edit.validate()?;
+        self.db.edit_token(actor, token, edit).await
     }
-    async fn db_transaction(
+
+    /// Hands the token over to _owner_. Only the current owner may do this, unless the token has no owner yet
+    /// (e.g. as it has been created before owners were recorded), in which case anybody can claim it.
+    pub async fn set_token_owner(
         &self,
-        sender: UserQueryModeStrict<'_>,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        owner: UserQueryModeStrict<'_>,
+    ) -> Result<(), Error>
+    {
+        self.db.set_token_owner(actor, token, owner).await
+    }
+
+    /// Allows _user_ to mint and burn the token as well. Only the owner of the token may do this.
+    pub async fn grant_minter(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        user: UserQueryModeStrict<'_>,
+    ) -> Result<(), Error>
+    {
+        self.db.set_minter(actor, token, user, true).await
+    }
+
+    /// Takes back the rights given by [Core::grant_minter]. Only the owner of the token may do this.
+    pub async fn revoke_minter(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        user: UserQueryModeStrict<'_>,
+    ) -> Result<(), Error>
+    {
+        self.db.set_minter(actor, token, user, false).await
+    }
+
+    /// Users, that may mint and burn the token besides its owner, ordered by ID
+    pub async fn query_minters(&self, token: TokenQueryModeStrict<'_>) -> Result<Vec<User>, Error>
+    {
+        self.db.get_minters(token).await
+    }
+
+    /// Issues new points of the token to _receiver_. Only the owner of the token and its minters may do this, and
+    /// only for somebody else (fails with [Error::InvalidArgument] otherwise).
+    ///
+    /// Minting is a transaction from the minter, that ignores the [OverdraftPolicy]. So the balance of the minters
+    /// tracks how many points they have put into circulation.
+    ///
+    /// Returns the new balance of the receiver.
+    pub async fn mint(
+        &self,
+        minter: UserQueryModeStrict<'_>,
         receiver: UserQueryModeStrict<'_>,
         token: TokenQueryModeStrict<'_>,
         amount: TokenAmount,
-    ) -> Result<TokenAmount, sqlx::Error>
+    ) -> Result<TokenAmount, Error>
     {
-        match &self.db
-        {
-            DbImplementors::SQLite(db) => db.transaction(sender, receiver, token, amount).await,
-        }
+        self.db.issue(minter, receiver, token, amount).await
     }
 
-    // ================================================ User Management ================================================
-    pub async fn create_user(&self, name: &str) -> Result<User, sqlx::Error>
+    /// Takes points of the token out of circulation, i.e. back from _holder_ (the opposite of [Core::mint]).
+    /// The holder's balance has to allow this according to the [OverdraftPolicy].
+    ///
+    /// Returns the new balance of the holder.
+    pub async fn burn(
+        &self,
+        burner: UserQueryModeStrict<'_>,
+        holder: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        amount: TokenAmount,
+    ) -> Result<TokenAmount, Error>
     {
-        self.db_create_user(name).await
+        let amount = amount.checked_neg().ok_or(Error::Overflow)?;
+        self.db.issue(burner, holder, token, amount).await
     }
 
-    pub async fn query_all_users(&self) -> Result<Vec<User>, sqlx::Error>
+    pub async fn overdraft_policy(&self, token: TokenQueryModeStrict<'_>) -> Result<OverdraftPolicy, Error>
     {
-        self.db_get_all_users().await
+        self.db.get_overdraft_policy(token).await
     }
 
-    pub async fn query_user(&self, name: &str) -> Result<Vec<User>, sqlx::Error>
+    /// Changes the [OverdraftPolicy] of the token. It only applies to future transactions, existing balances
+    /// are left untouched, even if they violate the new policy. Only the owner may do this (anybody, if the token has no
+    /// owner), otherwise it fails with [Error::NotAuthorized].
+    pub async fn set_overdraft_policy(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        policy: OverdraftPolicy,
+    ) -> Result<(), Error>
     {
-        self.db_query_user(name).await
+        self.db.set_overdraft_policy(actor, token, policy).await
     }
 
-    // ================================================ Token Management ================================================
-    pub async fn create_token(&self, name: &str) -> Result<Token, sqlx::Error>
+    // ================================================ Transactions ================================================
+    /// Will execute a transaction and, if necessary, will create all users and tokens on the fly (opt-in).
+    /// Tokens created this way are owned by the sender.
+    ///
+    /// Fails with [Error::InsufficientBalance], if the [OverdraftPolicy] of the token doesn't allow the transaction
+    /// (on a token with an owner, [OverdraftPolicy::Free] only spares the owner and the minters). The balance is checked
+    /// within the same database transaction, so concurrent transactions can't overdraw it.
+    ///
+    /// Returns the new total the receiver got from the sender for this token.
+    /// This is always the "last known total" + "the transaction amount", determined within the same database transaction.
+    /// Doing another query after the transaction, to check the new total, could include other transactions
+    /// that might have happend in the brief time window while this function executes. This would be unintuitive.
+    pub async fn transaction(
+        &self,
+        sender: UserQueryModeWithCreation<'_>,
+        receiver: UserQueryModeWithCreation<'_>,
+        token: TokenQueryModeWithCreation<'_>,
+        amount: TokenAmount,
+    ) -> Result<TokenAmount, Error>
     {
-        self.db_create_token(name).await
+        self.transaction_with_details(sender, receiver, token, amount, &TransactionDetails::default())
+            .await
     }
 
-    pub async fn query_all_tokens(&self) -> Result<Vec<Token>, sqlx::Error>
+    /// Same as [Core::transaction], but records a memo, an external reference or the acting user as well.
+    /// Retries with the same [TransactionDetails::idempotency_key] are only recorded once.
+    pub async fn transaction_with_details(
+        &self,
+        sender: UserQueryModeWithCreation<'_>,
+        receiver: UserQueryModeWithCreation<'_>,
+        token: TokenQueryModeWithCreation<'_>,
+        amount: TokenAmount,
+        details: &TransactionDetails<'_>,
+    ) -> Result<TokenAmount, Error>
     {
-        self.db_get_all_tokens().await
+        self.db.transaction(sender, receiver, token, amount, details).await
     }
 
-    pub async fn query_token(&self, name: &str) -> Result<Vec<Token>, sqlx::Error>
+    /// Records several transactions at once, e.g. awarding a token to a whole team. The legs are applied in order,
+    /// each one like [Core::transaction_with_details], so the overdraft policy of every leg is checked against the
+    /// balances after the previous legs. Either all legs are recorded or, if any of them fails, none.
+    /// Returns the new total of every leg.
+    pub async fn batch_transaction(&self, legs: Vec<TransferLeg<'_>>) -> Result<Vec<TokenAmount>, Error>
     {
-        self.db_query_token(name).await
+        self.db.batch_transaction(&legs).await
     }
 
-    // ================================================ Transactions ================================================
-    // TODO {CustomErrorType}: Define error type
+    /// Imports users, tokens and (historical) transactions in one go, e.g. from another points tracker. The records
+    /// are applied in order, transactions like [Core::batch_transaction] (including the overdraft policies).
+    ///
+    /// Every record is validated, even after a failed one, so the report lists all problems. The import is only
+    /// committed, if no record failed and it's no _dry_run_; otherwise nothing is written.
+    pub async fn import(&self, records: &[ImportRecord<'_>], dry_run: bool) -> Result<ImportReport, Error>
+    {
+        self.db.import(records, dry_run).await
+    }
 
-    async fn get_or_create_user_by_name(&self, name: &str, create_if_missing: bool) -> Result<User, sqlx::Error>
+    /// Reverses the transaction _id_ by appending a compensating transaction with the negated amount between the same
+    /// users, that refers to the original and keeps _reason_ as its memo. No transaction is ever deleted.
+    ///
+    /// Fails with [Error::TransactionNotFound], with [Error::AlreadyReversed] for a second reversal, with
+    /// [Error::InvalidArgument] for reversals, which can't be reversed themselves, and with
+    /// [Error::InsufficientBalance], if the overdraft policy of the token doesn't allow to take the amount back.
+    /// Returns the reversal, as it appears in the history.
+    pub async fn reverse_transaction(&self, id: TransactionID, reason: &str) -> Result<HistoryEntry, Error>
     {
-        match self.db_query_user(name).await?.into_iter().next()
-        {
-            Some(user) => Ok(user),
-            None =>
-            {
-                if create_if_missing
-                {
-                    Ok(self.db_create_user(name).await?)
-                }
-                else
-                {
-                    Err(sqlx::Error::RowNotFound)
-                }
-            }
-        }
+        self.db.reverse_transaction(id, reason).await
     }
 
-    async fn get_or_create_token_by_name(&self, name: &str, create_if_missing: bool) -> Result<Token, sqlx::Error>
+    /// Individual transactions, including their timestamp, memo, reference and acting user, ordered by time.
+    /// Returns at most _limit_ transactions, starting after the cursor _after_ (or at the beginning).
+    pub async fn query_history(
+        &self,
+        filter: &HistoryFilter<'_>,
+        order: Order,
+        after: Option<HistoryCursor>,
+        limit: usize,
+    ) -> Result<HistoryPage, Error>
     {
-        match self.db_query_token(name).await?.into_iter().next()
+        if limit == 0
         {
-            Some(token) => Ok(token),
-            None =>
+            return Err(Error::InvalidArgument("the limit of a history page must be positive".to_string()));
+        }
+
+        // One more entry tells, whether there is another page
+        let mut entries = self.db.query_history(filter, order, after, limit.saturating_add(1)).await?;
+        let next = match entries.len() > limit
+        {
+            true =>
             {
-                if create_if_missing
-                {
-                    Ok(self.db_create_token(name).await?)
-                }
-                else
-                {
-                    Err(sqlx::Error::RowNotFound)
-                }
+                entries.truncate(limit);
+                entries.last().map(HistoryCursor::after)
             }
-        }
+            false => None,
+        };
+        Ok(HistoryPage { entries, next })
     }
 
-    /// Will execute a transaction and, if necessary, will create all users and tokens on the fly (opt-in).
-    pub async fn transaction(
+    /// Everything the user received of the token minus everything the user sent
+    pub async fn get_balance(&self, user: UserQueryModeStrict<'_>, token: TokenQueryModeStrict<'_>) -> Result<TokenAmount, Error>
+    {
+        self.db.get_balance(user, token).await
+    }
+
+    /// Total amount of a token the receiver got from the sender so far.
+    /// A return value of _None_ means, there are no transactions present.
+    pub async fn get_current_total(
         &self,
-        _sender: UserQueryModeWithCreation<'_>,
-        _receiver: UserQueryModeWithCreation<'_>,
-        _token: TokenQueryModeWithCreation<'_>,
-        _amount: TokenAmount,
-    ) -> Result<TokenAmount, sqlx::Error>
-    {
-        // TODO: Refactor DB-Layer state to support ASYNC
-        // This code block is just a simple test, that needs to compile
-        let name = "test";
-        let handle = spawn(self.get_or_create_user_by_name(name, false));
-        let _ = handle.await;
-
-        // TODO: CONTINUE HERE (compiler error below)
-        // // Spawn all necessary SQL query tasks
-        // let deferred_sender_id = match sender
-        // {
-        //     UserQueryModeWithCreation::ById(id) => DeferredUserId::Immediate(id),
-        //     UserQueryModeWithCreation::ByName(name) => DeferredUserId::Deferred(spawn(self.get_or_create_user_by_name(name, false))),
-        //     UserQueryModeWithCreation::ByNameOrCreate(name) => DeferredUserId::Deferred(spawn(self.get_or_create_user_by_name(name, true))),
-        // };
-        // let deferred_receiver_id = match receiver
-        // {
-        //     UserQueryModeWithCreation::ById(id) => DeferredUserId::Immediate(id),
-        //     UserQueryModeWithCreation::ByName(name) => DeferredUserId::Deferred(spawn(self.get_or_create_user_by_name(name, false))),
-        //     UserQueryModeWithCreation::ByNameOrCreate(name) => DeferredUserId::Deferred(spawn(self.get_or_create_user_by_name(name, true))),
-        // };
-        // let deferred_token_id = match token
-        // {
-        //     TokenQueryModeWithCreation::ById(id) => DeferredTokenId::Immediate(id),
-        //     TokenQueryModeWithCreation::ByName(name) => DeferredTokenId::Deferred(spawn(self.get_or_create_token_by_name(name, false))),
-        //     TokenQueryModeWithCreation::ByNameOrCreate(name) =>
-        //     {
-        //         DeferredTokenId::Deferred(spawn(self.get_or_create_token_by_name(name, true)))
-        //     }
-        // };
-        //
-        // // Join all queries into current async task
-        // let sender_id = match deferred_sender_id
-        // {
-        //     DeferredUserId::Immediate(id) => id,
-        //     DeferredUserId::Deferred(handle) => handle.await.unwrap()?.id,
-        // };
-        // let receiver_id = match deferred_receiver_id
-        // {
-        //     DeferredUserId::Immediate(id) => id,
-        //     DeferredUserId::Deferred(handle) => handle.await.unwrap()?.id,
-        // };
-        // let token_id = match deferred_token_id
-        // {
-        //     DeferredTokenId::Immediate(id) => id,
-        //     DeferredTokenId::Deferred(handle) => handle.await.unwrap()?.id,
-        // };
-        //
-        // // Retain previous total before the actual transaction takes place.
-        // // Reason:
-        // //      Doing another query after the transaction, to check the new total, could include other transactions
-        // //      that might have happend in the brief time window while this function executes. This would be unintuitive.
-        // //      So the return value should always be the "last known total" + "the transaction amount."
-        // //      Even if it's not the most recent total.
-        // let previous_total = self
-        //     .db_get_current_total(
-        //         UserQueryModeStrict::ById(sender_id),
-        //         UserQueryModeStrict::ById(receiver_id),
-        //         TokenQueryModeStrict::ById(token_id),
-        //     )
-        //     .await?
-        //     .unwrap_or_default();
-        //
-        // // Transaction
-        // let transaction_amount = self
-        //     .db_transaction(
-        //         UserQueryModeStrict::ById(sender_id),
-        //         UserQueryModeStrict::ById(receiver_id),
-        //         TokenQueryModeStrict::ById(token_id),
-        //         amount,
-        //     )
-        //     .await?;
-        //
-        // // Return the the new total, including the new transaction
-        // Ok(previous_total + transaction_amount)
-        Ok(1337)
+        sender: UserQueryModeStrict<'_>,
+        receiver: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+    ) -> Result<Option<TokenAmount>, Error>
+    {
+        self.db.get_current_total(sender, receiver, token).await
     }
 
     // ================================================ List Tokens =================================================
@@ -354,29 +998,9 @@ impl Core
         token: TokenQueryModeStrict<'_>,
         order: Order,
         order_by: Option<OrderBySenderOrAmount>,
-    ) -> Result<Vec<RelativeUserTokenAmountEntry>, sqlx::Error>
+    ) -> Result<Vec<RelativeUserTokenAmountEntry>, Error>
     {
-        let _receiver_id: DbPk = match receiver
-        {
-            UserQueryModeStrict::ById(id) => id,
-            UserQueryModeStrict::ByName(name) =>
-            {
-                self.db_query_user(name)
-                    .await?
-                    .into_iter()
-                    .next()
-                    .ok_or(sqlx::Error::RowNotFound)?
-                    .id
-            }
-        };
-
-        let _token_id: DbPk = match token
-        {
-            TokenQueryModeStrict::ById(id) => id,
-            TokenQueryModeStrict::ByName(name) => self.query_token(name).await?.into_iter().next().ok_or(sqlx::Error::RowNotFound)?.id,
-        };
-
-        self.db_list_user_token(receiver, token, order, order_by)
+        self.db.list_user_token(receiver, token, order, order_by).await
     }
 
     pub async fn list_tokens_by_user(
@@ -384,24 +1008,9 @@ impl Core
         receiver: UserQueryModeStrict<'_>,
         order: Order,
         order_by: Option<OrderByTokenOrSenderOrAmount>,
-    ) -> Result<Vec<RelativeTokenAmountEntry>, sqlx::Error>
+    ) -> Result<Vec<RelativeTokenAmountEntry>, Error>
     {
-        let _receiver_id: UserID = match receiver
-        {
-            // TODO: pop-ing the >last< match is not optimal
-            UserQueryModeStrict::ById(id) => id,
-            UserQueryModeStrict::ByName(name) =>
-            {
-                self.db_query_user(name)
-                    .await?
-                    .into_iter()
-                    .next()
-                    .ok_or(sqlx::Error::RowNotFound)?
-                    .id
-            }
-        };
-
-        self.db_list_tokens_by_user(receiver, order, order_by)
+        self.db.list_tokens_by_user(receiver, order, order_by).await
     }
 
     pub async fn list_users_by_token(
@@ -409,15 +1018,8 @@ impl Core
         token: TokenQueryModeStrict<'_>,
         order: Order,
         order_by: Option<OrderByReceiverOrSenderOrAmount>,
-    ) -> Result<Vec<RelativeUserAmountEntry>, sqlx::Error>
+    ) -> Result<Vec<RelativeUserAmountEntry>, Error>
     {
-        let _token_id: UserID = match token
-        {
-            // TODO: pop-ing the >last< match is not optimal
-            TokenQueryModeStrict::ById(id) => id,
-            TokenQueryModeStrict::ByName(name) => self.query_token(name).await?.into_iter().next().ok_or(sqlx::Error::RowNotFound)?.id,
-        };
-
-        self.db.list_users_by_token(token, order, order_by)
+        self.db.list_users_by_token(token, order, order_by).await
     }
 }
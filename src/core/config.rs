@@ -0,0 +1,113 @@
+use super::{Error, NameNormalization};
+use chrono::TimeDelta;
+use serde::Deserialize;
+use std::{path::Path, time::Duration};
+
+/// Environment variable that overrides the database URL of the config file
+pub const DATABASE_URL_ENV: &str = "DATABASE_URL";
+
+/// Journal mode of SQLite databases (see https://www.sqlite.org/pragma.html#pragma_journal_mode)
+#[derive(Copy, Clone, PartialEq, Eq, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalMode
+{
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+/// Settings for [Core::new](super::Core::new). The URL scheme selects the persistance layer:
+/// "sqlite:", "postgres:" (or "postgresql:") and "file:" for a plain JSON-lines file.
+///
+/// Can be loaded from a TOML file, where all keys are optional:
+/// ```toml
+/// database_url = "sqlite://var/data.db"
+/// max_connections = 5
+/// create_if_missing = true
+/// busy_timeout_ms = 5000
+/// journal_mode = "wal"
+/// run_migrations = true
+/// idempotency_hours = 24
+///
+/// [names]
+/// case_insensitive = true
+/// unicode_nfkc = true
+/// trim = true
+/// ```
+#[derive(Clone, PartialEq, Eq, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoreConfig
+{
+    pub database_url:      String,
+    pub max_connections:   u32,
+    pub create_if_missing: bool,
+    pub busy_timeout_ms:   u64,
+    pub journal_mode:      JournalMode,
+    /// Apply pending schema migrations while connecting
+    pub run_migrations:    bool,
+    /// How many hours the idempotency key of a transaction is remembered (see
+    /// [TransactionDetails::idempotency_key](super::TransactionDetails::idempotency_key))
+    pub idempotency_hours: u64,
+    /// When two user (or token) names are considered the same
+    pub names:             NameNormalization,
+}
+
+impl Default for CoreConfig
+{
+    fn default() -> Self
+    {
+        CoreConfig {
+            database_url:      "sqlite://var/data.db".to_string(),
+            max_connections:   5,
+            create_if_missing: true,
+            busy_timeout_ms:   5000,
+            journal_mode:      JournalMode::Wal,
+            run_migrations:    true,
+            idempotency_hours: 24,
+            names:             NameNormalization::default(),
+        }
+    }
+}
+
+impl CoreConfig
+{
+    /// Reads the given config file (if any) and applies the `DATABASE_URL` environment variable on top of it.
+    /// A `.env` file isn't read: the one in the checkout only serves the compile time checked queries.
+    pub fn load(file: Option<&Path>) -> Result<CoreConfig, Error>
+    {
+        let mut config = match file
+        {
+            Some(path) => Self::from_file(path)?,
+            None => CoreConfig::default(),
+        };
+
+        if let Ok(url) = std::env::var(DATABASE_URL_ENV)
+        {
+            config.database_url = url;
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<CoreConfig, Error>
+    {
+        let content = std::fs::read_to_string(path).map_err(|err| Error::Config(format!("{}: {err}", path.display())))?;
+        toml::from_str(&content).map_err(|err| Error::Config(format!("{}: {err}", path.display())))
+    }
+
+    pub fn busy_timeout(&self) -> Duration
+    {
+        Duration::from_millis(self.busy_timeout_ms)
+    }
+
+    pub fn idempotency_retention(&self) -> TimeDelta
+    {
+        i64::try_from(self.idempotency_hours)
+            .ok()
+            .and_then(TimeDelta::try_hours)
+            .unwrap_or(TimeDelta::MAX)
+    }
+}
@@ -0,0 +1,1751 @@
+use super::{
+    names,
+    persistance_layer::*,
+    sql_common::{self, group_balance_rows, BalanceRow, KeyedTransaction, Participants, ReversalTarget},
+    CoreConfig, DedupeReport, Error, HistoryCursor, HistoryFilter, Identifier, ImportRecord, ImportReport, NameMatch, NameNormalization,
+    Order, OrderByReceiverOrSenderOrAmount, OrderBySenderOrAmount, OrderByTokenOrSenderOrAmount, OverdraftPolicy, TokenAmount, TokenEdit,
+    TokenID, TokenQueryModeStrict, TokenQueryModeWithCreation, TokenRight, TransactionDetails, TransactionID, TransferLeg, UserID,
+    UserQueryModeStrict, UserQueryModeWithCreation,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{File, OpenOptions, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+/// Version of the file format written by this program (first record of every file)
+const FORMAT_VERSION: i64 = 1;
+
+/// One line of the file
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record
+{
+    Format
+    {
+        version: i64
+    },
+    User
+    {
+        id: UserID, name: String
+    },
+    Token
+    {
+        id:       TokenID,
+        name:     String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner_id: Option<UserID>,
+    },
+    /// The token has been handed over to a new owner
+    TokenOwner
+    {
+        token_id: TokenID, owner_id: UserID
+    },
+    /// Minting rights have been granted (or revoked)
+    Minter
+    {
+        token_id: TokenID,
+        user_id:  UserID,
+        allowed:  bool,
+    },
+    /// Latest descriptive fields of a token
+    TokenDetails
+    {
+        token_id: TokenID,
+        #[serde(flatten)]
+        details:  TokenDetails,
+    },
+    /// Latest overdraft policy of a token (see [OverdraftPolicy::credit_limit])
+    OverdraftPolicy
+    {
+        token_id:     TokenID,
+        credit_limit: Option<TokenAmount>,
+    },
+    Transaction
+    {
+        sender_id:   UserID,
+        receiver_id: UserID,
+        token_id:    TokenID,
+        amount:      TokenAmount,
+        #[serde(flatten)]
+        info:        TransactionInfo,
+    },
+}
+
+/// Timestamp, memo, reference, acting user, reversed transaction and idempotency key of a transaction
+/// (see [HistoryEntry])
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, Debug)]
+struct TransactionInfo
+{
+    /// Missing in files, that were written before timestamps were kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at:      Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memo:            Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reference:       Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    actor_id:        Option<UserID>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reverses_id:     Option<TransactionID>,
+    /// Kept forever, but only honored within the retention window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
+    /// When the idempotency key has been recorded, if that's later than _created_at_ (imported transactions). The
+    /// retention window starts here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyed_at:        Option<DateTime<Utc>>,
+}
+
+/// Description, symbol, decimals and metadata of a token (see [Token])
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, Debug)]
+struct TokenDetails
+{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symbol:      Option<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    decimals:    u8,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata:    BTreeMap<String, String>,
+}
+
+fn is_zero(value: &u8) -> bool
+{
+    *value == 0
+}
+
+/// Persistance layer for tiny single-user deployments, that stores everything in one JSON-lines file
+/// (URL "file:points.jsonl").
+///
+/// The file is append-only: every change is appended as one or more records, and the current state is rebuilt by
+/// replaying them on startup. A partially written last line (e.g. after a crash) is dropped while loading and the file
+/// gets compacted, i.e. rewritten from the loaded state into a temporary file, that then atomically replaces the original.
+///
+/// All data is kept in memory, so the file can only be used by one process at a time. This is enforced by
+/// an exclusive lock on "<file>.lock".
+#[derive(Debug)]
+pub struct DataFile
+{
+    ledger:        Mutex<Ledger>,
+    key_retention: TimeDelta,
+}
+
+#[derive(Debug)]
+struct Ledger
+{
+    path:             PathBuf,
+    file:             File,
+    _lock:            File,
+    names:            NameNormalization,
+    users:            BTreeMap<UserID, String>,
+    tokens:           BTreeMap<TokenID, String>,
+    /// IDs by normalized name. If several names share a key (before "dedupe"), the lowest ID wins.
+    user_keys:        HashMap<String, UserID>,
+    token_keys:       HashMap<String, TokenID>,
+    token_owners:     BTreeMap<TokenID, UserID>,
+    /// Tokens with a description, symbol, decimals or metadata
+    token_details:    BTreeMap<TokenID, TokenDetails>,
+    /// (token, user) for every minter
+    minters:          BTreeSet<(TokenID, UserID)>,
+    /// Tokens with an overdraft policy other than "free"
+    credit_limits:    BTreeMap<TokenID, TokenAmount>,
+    transactions:     Vec<Record>,
+    /// Current total by (receiver, token, sender), just like the "user_balance" table of the SQL databases
+    balances:         HashMap<(UserID, TokenID, UserID), TokenAmount>,
+    /// Sum of the reversed transactions by (receiver, token, sender)
+    reversed:         HashMap<(UserID, TokenID, UserID), TokenAmount>,
+    /// ID of the reversal by ID of the reversed transaction
+    reversed_by:      HashMap<TransactionID, TransactionID>,
+    /// ID, new total and key time (see [TransactionInfo::keyed_at]) of the latest transaction by idempotency key
+    idempotency_keys: HashMap<String, (TransactionID, TokenAmount, Option<DateTime<Utc>>)>,
+    /// The file might end with an incomplete line
+    torn:             bool,
+}
+
+fn storage_error(message: String) -> Error
+{
+    Error::Storage(message.into())
+}
+
+impl DataFile
+{
+    pub fn new(config: &CoreConfig) -> Result<DataFile, Error>
+    {
+        let path = config
+            .database_url
+            .strip_prefix("file:")
+            .map(|path| path.strip_prefix("//").unwrap_or(path))
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| Error::Config(format!("invalid file URL \"{}\"", config.database_url)))?;
+
+        Ok(DataFile {
+            ledger:        Mutex::new(Ledger::open(Path::new(path), config.create_if_missing, config.names)?),
+            key_retention: config.idempotency_retention(),
+        })
+    }
+
+    fn ledger(&self) -> MutexGuard<'_, Ledger>
+    {
+        self.ledger.lock().expect("ledger lock poisoned")
+    }
+}
+
+impl Ledger
+{
+    fn open(path: &Path, create_if_missing: bool, names: NameNormalization) -> Result<Ledger, Error>
+    {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(Self::sibling(path, "lock"))
+            .map_err(|err| Error::BackendUnavailable(Box::new(err)))?;
+        match lock.try_lock()
+        {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) =>
+            {
+                return Err(Error::BackendUnavailable(
+                    format!("{} is in use by another process", path.display()).into(),
+                ))
+            }
+            Err(TryLockError::Error(err)) => return Err(Error::BackendUnavailable(Box::new(err))),
+        }
+
+        let content = match std::fs::read_to_string(path)
+        {
+            Ok(content) => Some(content),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && create_if_missing => None,
+            Err(err) => return Err(Error::BackendUnavailable(Box::new(err))),
+        };
+
+        // Placeholder handle, until the file is known to be complete
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| Error::BackendUnavailable(Box::new(err)))?;
+
+        let mut ledger = Ledger {
+            path: path.to_path_buf(),
+            file,
+            _lock: lock,
+            names,
+            users: BTreeMap::new(),
+            tokens: BTreeMap::new(),
+            user_keys: HashMap::new(),
+            token_keys: HashMap::new(),
+            token_owners: BTreeMap::new(),
+            token_details: BTreeMap::new(),
+            minters: BTreeSet::new(),
+            credit_limits: BTreeMap::new(),
+            transactions: Vec::new(),
+            balances: HashMap::new(),
+            reversed: HashMap::new(),
+            reversed_by: HashMap::new(),
+            idempotency_keys: HashMap::new(),
+            torn: false,
+        };
+
+        let needs_compaction = match content
+        {
+            Some(content) => ledger.replay(&content)?,
+            None => true,
+        };
+        if needs_compaction
+        {
+            ledger.compact()?;
+        }
+
+        Ok(ledger)
+    }
+
+    fn sibling(path: &Path, extension: &str) -> PathBuf
+    {
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(extension);
+        PathBuf::from(sibling)
+    }
+
+    /// Rebuilds the state from the file content. Returns whether the file should be compacted.
+    fn replay(&mut self, content: &str) -> Result<bool, Error>
+    {
+        // Every record is written including its line break, so anything after the last line break is an incomplete write
+        let (complete, torn) = match content.rfind('\n')
+        {
+            Some(end) => content.split_at(end + 1),
+            None => ("", content),
+        };
+
+        let mut lines = complete.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        match lines.next()
+        {
+            Some((_, line)) => match serde_json::from_str(line)
+            {
+                Ok(Record::Format { version }) if version > FORMAT_VERSION =>
+                {
+                    return Err(Error::SchemaTooNew {
+                        database:  version,
+                        supported: FORMAT_VERSION,
+                    })
+                }
+                Ok(Record::Format { .. }) => (),
+                _ => return Err(storage_error(format!("{} is not a points exchange file", self.path.display()))),
+            },
+            None => return Ok(true),
+        }
+
+        for (index, line) in lines
+        {
+            let record =
+                serde_json::from_str(line).map_err(|err| storage_error(format!("{}:{}: {err}", self.path.display(), index + 1)))?;
+            self.apply(record)
+                .map_err(|err| storage_error(format!("{}:{}: {err}", self.path.display(), index + 1)))?;
+        }
+
+        Ok(!torn.is_empty())
+    }
+
+    /// Forgets the in-memory state
+    fn clear(&mut self)
+    {
+        self.users.clear();
+        self.tokens.clear();
+        self.user_keys.clear();
+        self.token_keys.clear();
+        self.token_owners.clear();
+        self.token_details.clear();
+        self.minters.clear();
+        self.credit_limits.clear();
+        self.transactions.clear();
+        self.balances.clear();
+        self.reversed.clear();
+        self.reversed_by.clear();
+        self.idempotency_keys.clear();
+    }
+
+    /// Replaces the in-memory state by the content of the file
+    fn reload(&mut self) -> Result<(), Error>
+    {
+        let content = std::fs::read_to_string(&self.path)?;
+        self.clear();
+        self.torn = self.replay(&content)?;
+        Ok(())
+    }
+
+    /// Updates the in-memory state. Fails for records, that refer to unknown users or tokens.
+    fn apply(&mut self, record: Record) -> Result<(), Error>
+    {
+        match record
+        {
+            Record::Format { .. } => (),
+            Record::User { id, name } =>
+            {
+                self.user_keys.entry(self.names.key(&name)).or_insert(id);
+                self.users.insert(id, name);
+            }
+            Record::Token { id, name, owner_id } =>
+            {
+                if let Some(owner_id) = owner_id
+                {
+                    self.verify_user_id(owner_id)?;
+                    self.token_owners.insert(id, owner_id);
+                }
+                self.token_keys.entry(self.names.key(&name)).or_insert(id);
+                self.tokens.insert(id, name);
+            }
+            Record::TokenOwner { token_id, owner_id } =>
+            {
+                self.verify_token_id(token_id)?;
+                self.verify_user_id(owner_id)?;
+                self.token_owners.insert(token_id, owner_id);
+            }
+            Record::TokenDetails { token_id, details } =>
+            {
+                self.verify_token_id(token_id)?;
+                match details == TokenDetails::default()
+                {
+                    true => self.token_details.remove(&token_id),
+                    false => self.token_details.insert(token_id, details),
+                };
+            }
+            Record::Minter {
+                token_id,
+                user_id,
+                allowed,
+            } =>
+            {
+                self.verify_token_id(token_id)?;
+                self.verify_user_id(user_id)?;
+                match allowed
+                {
+                    true => self.minters.insert((token_id, user_id)),
+                    false => self.minters.remove(&(token_id, user_id)),
+                };
+            }
+            Record::OverdraftPolicy { token_id, credit_limit } =>
+            {
+                self.verify_token_id(token_id)?;
+                match credit_limit
+                {
+                    Some(limit) => self.credit_limits.insert(token_id, limit),
+                    None => self.credit_limits.remove(&token_id),
+                };
+            }
+            Record::Transaction {
+                sender_id,
+                receiver_id,
+                token_id,
+                amount,
+                ref info,
+            } =>
+            {
+                self.verify_user_id(sender_id)?;
+                self.verify_user_id(receiver_id)?;
+                self.verify_token_id(token_id)?;
+                if let Some(actor_id) = info.actor_id
+                {
+                    self.verify_user_id(actor_id)?;
+                }
+                let total = self.new_total(sender_id, receiver_id, token_id, amount)?;
+                let relation = (receiver_id, token_id, sender_id);
+                let reversal = match info.reverses_id
+                {
+                    Some(reverses_id) =>
+                    {
+                        let (target, compensation) = sql_common::check_reversal(reverses_id, self.reversal_target(reverses_id))?;
+                        if (target.sender_id, target.receiver_id, target.token_id, compensation)
+                            != (sender_id, receiver_id, token_id, amount)
+                        {
+                            return Err(storage_error(format!("transaction doesn't compensate transaction {reverses_id}")));
+                        }
+                        let reversed = self.reversed.get(&relation).copied().unwrap_or_default();
+                        Some((reverses_id, reversed.checked_sub(amount).ok_or(Error::Overflow)?))
+                    }
+                    None => None,
+                };
+
+                let idempotency_key = info.idempotency_key.clone().map(|key| (key, info.keyed_at.or(info.created_at)));
+
+                self.balances.insert(relation, total);
+                self.transactions.push(record);
+                if let Some((key, keyed_at)) = idempotency_key
+                {
+                    self.idempotency_keys.insert(key, (self.last_transaction_id(), total, keyed_at));
+                }
+                if let Some((reverses_id, reversed)) = reversal
+                {
+                    self.reversed.insert(relation, reversed);
+                    self.reversed_by.insert(reverses_id, self.last_transaction_id());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the records to the file and applies them afterwards.
+    fn append(&mut self, records: Vec<Record>) -> Result<(), Error>
+    {
+        self.write(&records)?;
+        for record in records
+        {
+            self.apply(record)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the records to the file at once, so a crash can only ever tear the last line.
+    /// After a failed write, the file is compacted before anything else gets appended.
+    fn write(&mut self, records: &[Record]) -> Result<(), Error>
+    {
+        if self.torn
+        {
+            self.compact()?;
+        }
+
+        let mut buffer = Vec::new();
+        for record in records
+        {
+            serde_json::to_writer(&mut buffer, record).map_err(|err| Error::Storage(Box::new(err)))?;
+            buffer.push(b'\n');
+        }
+
+        let result = self.file.write_all(&buffer).and_then(|()| self.file.sync_data());
+        if result.is_err()
+        {
+            self.torn = true;
+        }
+        Ok(result?)
+    }
+
+    /// Atomically replaces the file by a minimal one, that describes the current state.
+    fn compact(&mut self) -> Result<(), Error>
+    {
+        let mut buffer = Vec::new();
+        let mut write = |record: &Record| -> Result<(), Error> {
+            serde_json::to_writer(&mut buffer, record).map_err(|err| Error::Storage(Box::new(err)))?;
+            buffer.push(b'\n');
+            Ok(())
+        };
+
+        write(&Record::Format { version: FORMAT_VERSION })?;
+        for (&id, name) in &self.users
+        {
+            write(&Record::User { id, name: name.clone() })?;
+        }
+        for (&id, name) in &self.tokens
+        {
+            write(&Record::Token {
+                id,
+                name: name.clone(),
+                owner_id: self.token_owners.get(&id).copied(),
+            })?;
+        }
+        for (&token_id, details) in &self.token_details
+        {
+            write(&Record::TokenDetails {
+                token_id,
+                details: details.clone(),
+            })?;
+        }
+        for &(token_id, user_id) in &self.minters
+        {
+            write(&Record::Minter {
+                token_id,
+                user_id,
+                allowed: true,
+            })?;
+        }
+        for (&token_id, &limit) in &self.credit_limits
+        {
+            write(&Record::OverdraftPolicy {
+                token_id,
+                credit_limit: Some(limit),
+            })?;
+        }
+        for record in &self.transactions
+        {
+            write(record)?;
+        }
+
+        let temporary = Self::sibling(&self.path, "tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)?;
+
+        // Persist the rename as well
+        if let Some(directory) = self.path.parent().filter(|directory| !directory.as_os_str().is_empty())
+        {
+            File::open(directory).and_then(|directory| directory.sync_all()).ok();
+        }
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.torn = false;
+        Ok(())
+    }
+
+    fn user(&self, id: UserID) -> User
+    {
+        User {
+            id,
+            name: self.users.get(&id).cloned().unwrap_or_default(),
+        }
+    }
+
+    fn token(&self, id: TokenID) -> Token
+    {
+        let owner_id = self.token_owners.get(&id).copied();
+        let details = self.token_details.get(&id).cloned().unwrap_or_default();
+        Token {
+            id,
+            name: self.tokens.get(&id).cloned().unwrap_or_default(),
+            owner_id,
+            owner_name: owner_id.map(|owner_id| self.user(owner_id).name),
+            description: details.description,
+            symbol: details.symbol,
+            decimals: details.decimals,
+            metadata: details.metadata,
+        }
+    }
+
+    /// Fails with [Error::NotAuthorized], unless the user has the right for the token
+    fn check_right(&self, right: TokenRight, user_id: UserID, token_id: TokenID) -> Result<(), Error>
+    {
+        let owner_id = self.token_owners.get(&token_id).copied();
+        right.check(user_id, token_id, owner_id, self.minters.contains(&(token_id, user_id)))
+    }
+
+    fn overdraft_policy(&self, token_id: TokenID) -> OverdraftPolicy
+    {
+        OverdraftPolicy::from_credit_limit(self.credit_limits.get(&token_id).copied())
+    }
+
+    /// Everything the user received of the token minus everything the user sent
+    fn balance(&self, user_id: UserID, token_id: TokenID) -> Result<TokenAmount, Error>
+    {
+        sql_common::balance(
+            user_id,
+            self.balances
+                .iter()
+                .filter(|(&(_, token, _), _)| token == token_id)
+                .map(|(&(receiver, _, sender), &total)| (receiver, sender, total)),
+        )
+    }
+
+    /// Running total of the relation after a transaction of _amount_
+    fn new_total(&self, sender_id: UserID, receiver_id: UserID, token_id: TokenID, amount: TokenAmount) -> Result<TokenAmount, Error>
+    {
+        sql_common::new_total(self.balances.get(&(receiver_id, token_id, sender_id)).copied(), amount)
+    }
+
+    /// Transactions are identified by their position in the log (which compaction preserves)
+    fn last_transaction_id(&self) -> TransactionID
+    {
+        self.transactions.len() as TransactionID
+    }
+
+    fn transaction(&self, id: TransactionID) -> Option<&Record>
+    {
+        self.transactions.get(usize::try_from(id.checked_sub(1)?).ok()?)
+    }
+
+    /// The transaction with the given ID as [ReversalTarget] (_None_, if it doesn't exist)
+    fn reversal_target(&self, id: TransactionID) -> Option<ReversalTarget>
+    {
+        let Some(Record::Transaction {
+            sender_id,
+            receiver_id,
+            token_id,
+            amount,
+            info,
+        }) = self.transaction(id)
+        else
+        {
+            return None;
+        };
+        Some(ReversalTarget {
+            sender_id:      *sender_id,
+            receiver_id:    *receiver_id,
+            token_id:       *token_id,
+            amount:         *amount,
+            reference:      info.reference.clone(),
+            reverses_id:    info.reverses_id,
+            reversed_by_id: self.reversed_by.get(&id).copied(),
+        })
+    }
+
+    /// New total of the transaction, that has been recorded with the idempotency _key_ within the _retention_ window
+    /// (_None_, if there is none)
+    fn repeated_transaction(
+        &self,
+        key: &str,
+        participants: Participants,
+        amount: TokenAmount,
+        retention: TimeDelta,
+    ) -> Result<Option<TokenAmount>, Error>
+    {
+        let Some(&(id, new_total, keyed_at)) = self.idempotency_keys.get(key)
+        else
+        {
+            return Ok(None);
+        };
+        if keyed_at.is_none_or(|keyed_at| keyed_at < sql_common::idempotency_cutoff(retention))
+        {
+            return Ok(None);
+        }
+        let Some(Record::Transaction {
+            sender_id,
+            receiver_id,
+            token_id,
+            amount: previous_amount,
+            ..
+        }) = self.transaction(id)
+        else
+        {
+            return Ok(None);
+        };
+
+        let previous = KeyedTransaction {
+            sender_id: *sender_id,
+            receiver_id: *receiver_id,
+            token_id: *token_id,
+            amount: *previous_amount,
+            new_total,
+        };
+        sql_common::repeated_total(key, &previous, participants, amount).map(Some)
+    }
+
+    /// [HistoryEntry] of a transaction record with the given ID (_None_ for other records)
+    fn history_entry(&self, id: DbPk, record: &Record) -> Option<HistoryEntry>
+    {
+        let Record::Transaction {
+            sender_id,
+            receiver_id,
+            token_id,
+            amount,
+            info,
+        } = record
+        else
+        {
+            return None;
+        };
+        Some(HistoryEntry {
+            id,
+            created_at: info.created_at,
+            sender_id: *sender_id,
+            sender_name: self.users[sender_id].clone(),
+            receiver_id: *receiver_id,
+            receiver_name: self.users[receiver_id].clone(),
+            token_id: *token_id,
+            token_name: self.tokens[token_id].clone(),
+            amount: *amount,
+            memo: info.memo.clone(),
+            reference: info.reference.clone(),
+            actor_id: info.actor_id,
+            actor_name: info.actor_id.map(|actor_id| self.users[&actor_id].clone()),
+            reverses_id: info.reverses_id,
+            reversed_by_id: self.reversed_by.get(&id).copied(),
+        })
+    }
+
+    fn next_id<T>(map: &BTreeMap<DbPk, T>) -> DbPk
+    {
+        map.last_key_value().map_or(1, |(&id, _)| id + 1)
+    }
+
+    fn verify_user_id(&self, id: UserID) -> Result<UserID, Error>
+    {
+        match self.users.contains_key(&id)
+        {
+            true => Ok(id),
+            false => Err(Error::UserNotFound(Identifier::Id(id))),
+        }
+    }
+
+    fn verify_token_id(&self, id: TokenID) -> Result<TokenID, Error>
+    {
+        match self.tokens.contains_key(&id)
+        {
+            true => Ok(id),
+            false => Err(Error::TokenNotFound(Identifier::Id(id))),
+        }
+    }
+
+    fn find_user(&self, name: &str) -> Option<UserID>
+    {
+        self.user_keys.get(&self.names.key(name)).copied()
+    }
+
+    fn find_token(&self, name: &str) -> Option<TokenID>
+    {
+        self.token_keys.get(&self.names.key(name)).copied()
+    }
+
+    // Users and tokens, that have to be created, are applied right away (so a second lookup of the same name finds them)
+    // and are collected in "created". They only get written to the file together with the transaction.
+    fn resolve_user(&mut self, user: UserQueryModeWithCreation<'_>, created: &mut Vec<Record>) -> Result<UserID, Error>
+    {
+        match user
+        {
+            UserQueryModeWithCreation::ById(id) => self.verify_user_id(id),
+            UserQueryModeWithCreation::ByName(name) => self
+                .find_user(name)
+                .ok_or_else(|| Error::UserNotFound(Identifier::Name(name.to_string()))),
+            UserQueryModeWithCreation::ByNameOrCreate(name) => match self.find_user(name)
+            {
+                Some(id) => Ok(id),
+                None =>
+                {
+                    let id = Self::next_id(&self.users);
+                    let record = Record::User {
+                        id,
+                        name: self.names.stored(name).to_string(),
+                    };
+                    self.apply(record.clone())?;
+                    created.push(record);
+                    Ok(id)
+                }
+            },
+        }
+    }
+
+    fn resolve_token(
+        &mut self,
+        token: TokenQueryModeWithCreation<'_>,
+        owner_id: Option<UserID>,
+        created: &mut Vec<Record>,
+    ) -> Result<TokenID, Error>
+    {
+        match token
+        {
+            TokenQueryModeWithCreation::ById(id) => self.verify_token_id(id),
+            TokenQueryModeWithCreation::ByName(name) => self
+                .find_token(name)
+                .ok_or_else(|| Error::TokenNotFound(Identifier::Name(name.to_string()))),
+            TokenQueryModeWithCreation::ByNameOrCreate(name) => match self.find_token(name)
+            {
+                Some(id) => Ok(id),
+                None =>
+                {
+                    let id = Self::next_id(&self.tokens);
+                    let record = Record::Token {
+                        id,
+                        name: self.names.stored(name).to_string(),
+                        owner_id,
+                    };
+                    self.apply(record.clone())?;
+                    created.push(record);
+                    Ok(id)
+                }
+            },
+        }
+    }
+
+    /// Resolves all participants of a transaction. Returns their IDs and the records of all users and tokens,
+    /// that have been created in the process.
+    fn resolve_transaction(
+        &mut self,
+        sender: UserQueryModeWithCreation<'_>,
+        receiver: UserQueryModeWithCreation<'_>,
+        token: TokenQueryModeWithCreation<'_>,
+    ) -> Result<(Participants, Vec<Record>), Error>
+    {
+        let mut created = Vec::new();
+        let mut resolve = || -> Result<_, Error> {
+            let sender_id = self.resolve_user(sender, &mut created)?;
+            Ok((
+                sender_id,
+                self.resolve_user(receiver, &mut created)?,
+                self.resolve_token(token, Some(sender_id), &mut created)?,
+            ))
+        };
+
+        match resolve()
+        {
+            Ok(ids) => Ok((ids, created)),
+            Err(err) =>
+            {
+                self.discard(created);
+                Err(err)
+            }
+        }
+    }
+
+    /// Undoes the in-memory part of [Ledger::resolve_user] and [Ledger::resolve_token]
+    fn discard(&mut self, created: Vec<Record>)
+    {
+        for record in created
+        {
+            match record
+            {
+                Record::User { id, name } =>
+                {
+                    self.user_keys.remove(&self.names.key(&name));
+                    self.users.remove(&id);
+                }
+                Record::Token { id, name, .. } =>
+                {
+                    self.token_keys.remove(&self.names.key(&name));
+                    self.token_owners.remove(&id);
+                    self.token_details.remove(&id);
+                    self.tokens.remove(&id);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Resolves the participants of a transaction (see [PersistanceLayer::transaction]), that happens at _created_at_
+    /// (or now), and checks it. Returns the new total and the records to write: the created users and tokens, which
+    /// are already applied, and the transaction itself, which isn't (nothing for a repeated idempotency key).
+    /// Created users and tokens are discarded on failure.
+    fn transfer(
+        &mut self,
+        leg: &TransferLeg<'_>,
+        created_at: Option<DateTime<Utc>>,
+        retention: TimeDelta,
+    ) -> Result<(TokenAmount, Vec<Record>), Error>
+    {
+        let (amount, details) = (leg.amount, &leg.details);
+        let ((sender_id, receiver_id, token_id), mut records) = self.resolve_transaction(leg.sender, leg.receiver, leg.token)?;
+        if let Some(key) = &details.idempotency_key
+        {
+            // Repeated participants already existed, so only a failed check may have to discard new ones
+            match self.repeated_transaction(key, (sender_id, receiver_id, token_id), amount, retention)
+            {
+                Ok(None) => (),
+                Ok(Some(total)) => return Ok((total, records)),
+                Err(err) =>
+                {
+                    self.discard(records);
+                    return Err(err);
+                }
+            }
+        }
+
+        let check = || -> Result<(TokenAmount, Option<UserID>), Error> {
+            let actor_id = details.actor.map(|actor| self.resolve_strict_user(actor)).transpose()?;
+            // Only those, who could issue the points anyway, create them on an owned token
+            let policy = match (
+                self.overdraft_policy(token_id),
+                OverdraftPolicy::debited(sender_id, receiver_id, amount)?,
+            )
+            {
+                (OverdraftPolicy::Free, Some((debited_id, _))) if self.check_right(TokenRight::Overdraw, debited_id, token_id).is_err() =>
+                {
+                    OverdraftPolicy::BalanceBacked
+                }
+                (policy, _) => policy,
+            };
+            if let Some((debited_id, debit)) = policy.limited_debit(sender_id, receiver_id, amount)?
+            {
+                policy.check(self.balance(debited_id, token_id)?, debit)?;
+            }
+            Ok((self.new_total(sender_id, receiver_id, token_id, amount)?, actor_id))
+        };
+        let (new_total, actor_id) = match check()
+        {
+            Ok(checked) => checked,
+            Err(err) =>
+            {
+                self.discard(records);
+                return Err(err);
+            }
+        };
+
+        let now = Utc::now();
+        records.push(Record::Transaction {
+            sender_id,
+            receiver_id,
+            token_id,
+            amount,
+            info: TransactionInfo {
+                created_at: Some(created_at.unwrap_or(now)),
+                memo: details.memo.clone(),
+                reference: details.reference.clone(),
+                actor_id: actor_id.filter(|&actor_id| actor_id != sender_id),
+                idempotency_key: details.idempotency_key.clone(),
+                keyed_at: created_at.and(details.idempotency_key.as_ref()).map(|_| now),
+                ..TransactionInfo::default()
+            },
+        });
+
+        Ok((new_total, records))
+    }
+
+    /// Applies the legs one after the other (so every leg sees the balances after the previous ones) and writes them
+    /// at once. Returns the new totals. On failure, the in-memory state has to be reloaded.
+    fn batch(&mut self, legs: &[TransferLeg<'_>], retention: TimeDelta) -> Result<Vec<TokenAmount>, Error>
+    {
+        let mut totals = Vec::with_capacity(legs.len());
+        let mut records = Vec::new();
+        for leg in legs
+        {
+            let (new_total, leg_records) = self.applied_transfer(leg, None, retention)?;
+            totals.push(new_total);
+            records.extend(leg_records);
+        }
+        self.write(&records)?;
+        Ok(totals)
+    }
+
+    /// [Ledger::transfer], with the transaction applied right away (but not written)
+    fn applied_transfer(
+        &mut self,
+        leg: &TransferLeg<'_>,
+        created_at: Option<DateTime<Utc>>,
+        retention: TimeDelta,
+    ) -> Result<(TokenAmount, Vec<Record>), Error>
+    {
+        let (new_total, records) = self.transfer(leg, created_at, retention)?;
+        for record in records.iter().filter(|record| matches!(record, Record::Transaction { .. }))
+        {
+            self.apply(record.clone())?;
+        }
+        Ok((new_total, records))
+    }
+
+    /// Imports the records one after the other (see [PersistanceLayer::import]) and writes them at once, if it's no
+    /// dry run and none of them failed. Otherwise, the in-memory state has to be reloaded.
+    fn import(&mut self, records: &[ImportRecord<'_>], dry_run: bool, retention: TimeDelta) -> Result<ImportReport, Error>
+    {
+        let mut report = ImportReport::default();
+        let mut imported = Vec::new();
+        for (index, record) in records.iter().enumerate()
+        {
+            match self.import_record(record, retention)
+            {
+                Ok(records) => imported.extend(records),
+                Err(err) => report.add_failure(index, err)?,
+            }
+        }
+
+        for record in &imported
+        {
+            match record
+            {
+                Record::User { .. } => report.users += 1,
+                Record::Token { .. } => report.tokens += 1,
+                Record::Transaction { .. } => report.transactions += 1,
+                _ => (),
+            }
+        }
+        if !dry_run && report.failures.is_empty()
+        {
+            self.write(&imported)?;
+            report.committed = true;
+        }
+        Ok(report)
+    }
+
+    /// Applies a single record of an import. Returns the records to write. Failed records leave no trace.
+    fn import_record(&mut self, record: &ImportRecord<'_>, retention: TimeDelta) -> Result<Vec<Record>, Error>
+    {
+        let mut created = Vec::new();
+        match record
+        {
+            ImportRecord::User { name } =>
+            {
+                self.resolve_user(UserQueryModeWithCreation::ByNameOrCreate(name), &mut created)?;
+            }
+            ImportRecord::Token { name, owner } =>
+            {
+                let mut resolve = || -> Result<_, Error> {
+                    let owner_id = owner
+                        .map(|owner| self.resolve_user(UserQueryModeWithCreation::ByNameOrCreate(owner), &mut created))
+                        .transpose()?;
+                    self.resolve_token(TokenQueryModeWithCreation::ByNameOrCreate(name), owner_id, &mut created)
+                };
+                if let Err(err) = resolve()
+                {
+                    self.discard(created);
+                    return Err(err);
+                }
+            }
+            ImportRecord::Transaction { leg, created_at } => created = self.applied_transfer(leg, *created_at, retention)?.1,
+        }
+        Ok(created)
+    }
+
+    fn resolve_strict_user(&self, user: UserQueryModeStrict<'_>) -> Result<UserID, Error>
+    {
+        match user
+        {
+            UserQueryModeStrict::ById(id) => self.verify_user_id(id),
+            UserQueryModeStrict::ByName(name) => self
+                .find_user(name)
+                .ok_or_else(|| Error::UserNotFound(Identifier::Name(name.to_string()))),
+        }
+    }
+
+    fn resolve_strict_token(&self, token: TokenQueryModeStrict<'_>) -> Result<TokenID, Error>
+    {
+        match token
+        {
+            TokenQueryModeStrict::ById(id) => self.verify_token_id(id),
+            TokenQueryModeStrict::ByName(name) => self
+                .find_token(name)
+                .ok_or_else(|| Error::TokenNotFound(Identifier::Name(name.to_string()))),
+        }
+    }
+
+    /// Details of all tokens, that are kept by "dedupe" (_kept_token_ maps merged tokens to the kept ones).
+    /// Metadata keys of merged tokens, that the kept token doesn't have, are added to it.
+    fn merged_token_details(&self, kept_token: &HashMap<TokenID, TokenID>) -> BTreeMap<TokenID, TokenDetails>
+    {
+        let mut details: BTreeMap<TokenID, TokenDetails> = self
+            .token_details
+            .iter()
+            .filter(|(id, _)| !kept_token.contains_key(id))
+            .map(|(&id, details)| (id, details.clone()))
+            .collect();
+        for (id, merged) in self.token_details.iter().filter(|(id, _)| kept_token.contains_key(id))
+        {
+            let kept = details.entry(kept_token[id]).or_default();
+            for (key, value) in &merged.metadata
+            {
+                kept.metadata.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+        details
+    }
+
+    /// Balance rows matching the filter, grouped by receiver (_by_receiver_) or by token
+    fn balance_rows(&self, filter: impl Fn(UserID, TokenID) -> bool, by_receiver: bool) -> Vec<BalanceRow>
+    {
+        self.balances
+            .iter()
+            .filter(|(&(receiver_id, token_id, _), _)| filter(receiver_id, token_id))
+            .map(|(&(receiver_id, token_id, sender_id), &amount)| {
+                let (group_id, group_name) = match by_receiver
+                {
+                    true => (receiver_id, self.user(receiver_id).name),
+                    false => (token_id, self.token(token_id).name),
+                };
+                BalanceRow {
+                    group_id,
+                    group_name,
+                    sender_id,
+                    sender_name: self.user(sender_id).name,
+                    amount,
+                    reversed: self.reversed.get(&(receiver_id, token_id, sender_id)).copied().unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+}
+
+// In-memory equivalents of the ORDER BY clauses in "sql_common"
+enum SortKey
+{
+    Group,
+    Sender,
+    Amount,
+}
+
+fn by_sender(a: &BalanceRow, b: &BalanceRow) -> Ordering
+{
+    (&a.sender_name, a.sender_id).cmp(&(&b.sender_name, b.sender_id))
+}
+
+fn sort_balance_rows(rows: &mut [BalanceRow], order: Order, key: SortKey)
+{
+    let directed = |ordering: Ordering| match order
+    {
+        Order::Asc => ordering,
+        Order::Desc => ordering.reverse(),
+    };
+
+    match key
+    {
+        SortKey::Group => rows.sort_by(|a, b| {
+            directed((&a.group_name, a.group_id).cmp(&(&b.group_name, b.group_id)))
+                .then(b.amount.cmp(&a.amount))
+                .then_with(|| by_sender(a, b))
+        }),
+        SortKey::Sender => rows.sort_by(|a, b| directed(by_sender(a, b))),
+        SortKey::Amount => rows.sort_by(|a, b| directed(a.amount.cmp(&b.amount)).then_with(|| by_sender(a, b))),
+    }
+}
+
+#[async_trait]
+impl PersistanceLayer for DataFile
+{
+    // The file format has no schema migrations. Files of newer format versions are rejected while loading.
+    async fn migration_status(&self) -> Result<Vec<MigrationInfo>, Error>
+    {
+        Ok(Vec::new())
+    }
+
+    async fn migrate_up(&self) -> Result<(), Error>
+    {
+        Ok(())
+    }
+
+    async fn migrate_down(&self) -> Result<Option<MigrationInfo>, Error>
+    {
+        Ok(None)
+    }
+
+    async fn dedupe(&self, dry_run: bool) -> Result<DedupeReport, Error>
+    {
+        let mut ledger = self.ledger();
+
+        let users = ledger.users.keys().map(|&id| ledger.user(id)).collect();
+        let tokens = ledger.tokens.keys().map(|&id| ledger.token(id)).collect();
+        let report = DedupeReport {
+            users:  names::duplicates(users, &ledger.names, |user| (user.id, &user.name)),
+            tokens: names::duplicates(tokens, &ledger.names, |token| (token.id, &token.name)),
+        };
+        if dry_run
+        {
+            return Ok(report);
+        }
+
+        let kept_user: HashMap<UserID, UserID> = report
+            .users
+            .iter()
+            .flat_map(|group| group.merged.iter().map(|user| (user.id, group.kept.id)))
+            .collect();
+        let kept_token: HashMap<TokenID, TokenID> = report
+            .tokens
+            .iter()
+            .flat_map(|group| group.merged.iter().map(|token| (token.id, group.kept.id)))
+            .collect();
+
+        // Rebuild the whole state without the merged users and tokens, then rewrite the file from it
+        let users: Vec<Record> = ledger
+            .users
+            .iter()
+            .filter(|(id, _)| !kept_user.contains_key(id))
+            .map(|(&id, name)| Record::User { id, name: name.clone() })
+            .collect();
+        let tokens: Vec<Record> = ledger
+            .tokens
+            .iter()
+            .filter(|(id, _)| !kept_token.contains_key(id))
+            .map(|(&id, name)| Record::Token {
+                id,
+                name: name.clone(),
+                owner_id: ledger
+                    .token_owners
+                    .get(&id)
+                    .map(|owner_id| kept_user.get(owner_id).copied().unwrap_or(*owner_id)),
+            })
+            .chain(ledger.minters.iter().map(|&(token_id, user_id)| Record::Minter {
+                token_id: kept_token.get(&token_id).copied().unwrap_or(token_id),
+                user_id:  kept_user.get(&user_id).copied().unwrap_or(user_id),
+                allowed:  true,
+            }))
+            .chain(
+                ledger
+                    .merged_token_details(&kept_token)
+                    .into_iter()
+                    .map(|(token_id, details)| Record::TokenDetails { token_id, details }),
+            )
+            .chain(
+                ledger
+                    .credit_limits
+                    .iter()
+                    .filter(|(id, _)| !kept_token.contains_key(id))
+                    .map(|(&token_id, &limit)| Record::OverdraftPolicy {
+                        token_id,
+                        credit_limit: Some(limit),
+                    }),
+            )
+            .collect();
+        let transactions: Vec<Record> = std::mem::take(&mut ledger.transactions)
+            .into_iter()
+            .map(|record| match record
+            {
+                Record::Transaction {
+                    sender_id,
+                    receiver_id,
+                    token_id,
+                    amount,
+                    info,
+                } =>
+                {
+                    let sender_id = kept_user.get(&sender_id).copied().unwrap_or(sender_id);
+                    let actor_id = info.actor_id.map(|actor_id| kept_user.get(&actor_id).copied().unwrap_or(actor_id));
+                    Record::Transaction {
+                        sender_id,
+                        receiver_id: kept_user.get(&receiver_id).copied().unwrap_or(receiver_id),
+                        token_id: kept_token.get(&token_id).copied().unwrap_or(token_id),
+                        amount,
+                        info: TransactionInfo {
+                            actor_id: actor_id.filter(|&actor_id| actor_id != sender_id),
+                            ..info
+                        },
+                    }
+                }
+                other => other,
+            })
+            .collect();
+
+        ledger.clear();
+        let rebuilt = users
+            .into_iter()
+            .chain(tokens)
+            .chain(transactions)
+            .try_for_each(|record| ledger.apply(record))
+            .and_then(|()| ledger.compact());
+        if let Err(err) = rebuilt
+        {
+            // Go back to the state of the untouched file
+            ledger.reload()?;
+            return Err(err);
+        }
+
+        Ok(report)
+    }
+
+    async fn create_user(&self, name: &str) -> Result<User, Error>
+    {
+        let mut ledger = self.ledger();
+        let name = ledger.names.stored(name);
+        if ledger.find_user(name).is_some()
+        {
+            return Err(Error::DuplicateName(name.to_string()));
+        }
+        let id = Ledger::next_id(&ledger.users);
+        ledger.append(vec![Record::User {
+            id,
+            name: name.to_string(),
+        }])?;
+        Ok(ledger.user(id))
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>, Error>
+    {
+        let ledger = self.ledger();
+        Ok(ledger.users.keys().map(|&id| ledger.user(id)).collect())
+    }
+
+    async fn query_user(&self, name: &str, matching: NameMatch) -> Result<Vec<User>, Error>
+    {
+        let ledger = self.ledger();
+        if matching == NameMatch::Exact
+        {
+            return Ok(ledger.find_user(name).map(|id| ledger.user(id)).into_iter().collect());
+        }
+        Ok(ledger
+            .users
+            .iter()
+            .filter(|(_, other)| matching.matches(other, name))
+            .map(|(&id, _)| ledger.user(id))
+            .collect())
+    }
+
+    async fn create_token(&self, name: &str, owner: Option<UserQueryModeStrict<'_>>) -> Result<Token, Error>
+    {
+        let mut ledger = self.ledger();
+        let name = ledger.names.stored(name);
+        let owner_id = owner.map(|owner| ledger.resolve_strict_user(owner)).transpose()?;
+        if ledger.find_token(name).is_some()
+        {
+            return Err(Error::DuplicateName(name.to_string()));
+        }
+        let id = Ledger::next_id(&ledger.tokens);
+        ledger.append(vec![Record::Token {
+            id,
+            name: name.to_string(),
+            owner_id,
+        }])?;
+        Ok(ledger.token(id))
+    }
+
+    async fn get_all_tokens(&self) -> Result<Vec<Token>, Error>
+    {
+        let ledger = self.ledger();
+        Ok(ledger.tokens.keys().map(|&id| ledger.token(id)).collect())
+    }
+
+    async fn query_token(&self, name: &str, matching: NameMatch) -> Result<Vec<Token>, Error>
+    {
+        let ledger = self.ledger();
+        if matching == NameMatch::Exact
+        {
+            return Ok(ledger.find_token(name).map(|id| ledger.token(id)).into_iter().collect());
+        }
+        Ok(ledger
+            .tokens
+            .iter()
+            .filter(|(_, other)| matching.matches(other, name))
+            .map(|(&id, _)| ledger.token(id))
+            .collect())
+    }
+
+    async fn get_token(&self, token: TokenQueryModeStrict<'_>) -> Result<Token, Error>
+    {
+        let ledger = self.ledger();
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        Ok(ledger.token(token_id))
+    }
+
+    async fn edit_token(&self, actor: UserQueryModeStrict<'_>, token: TokenQueryModeStrict<'_>, edit: &TokenEdit) -> Result<Token, Error>
+    {
+        let mut ledger = self.ledger();
+        let actor_id = ledger.resolve_strict_user(actor)?;
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        ledger.check_right(TokenRight::Edit, actor_id, token_id)?;
+        let mut token = ledger.token(token_id);
+        if edit.changes_decimals(&token) && ledger.balances.keys().any(|&(_, token, _)| token == token_id)
+        {
+            return Err(Error::DecimalsInUse(Identifier::Id(token_id)));
+        }
+        edit.apply(&mut token);
+        ledger.append(vec![Record::TokenDetails {
+            token_id,
+            details: TokenDetails {
+                description: token.description.clone(),
+                symbol:      token.symbol.clone(),
+                decimals:    token.decimals,
+                metadata:    token.metadata.clone(),
+            },
+        }])?;
+        Ok(token)
+    }
+
+    async fn set_token_owner(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        owner: UserQueryModeStrict<'_>,
+    ) -> Result<(), Error>
+    {
+        let mut ledger = self.ledger();
+        let actor_id = ledger.resolve_strict_user(actor)?;
+        let token_id = ledger.resolve_strict_token(token)?;
+        let owner_id = ledger.resolve_strict_user(owner)?;
+
+        ledger.check_right(TokenRight::Transfer, actor_id, token_id)?;
+        ledger.append(vec![Record::TokenOwner { token_id, owner_id }])
+    }
+
+    async fn set_minter(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        user: UserQueryModeStrict<'_>,
+        allowed: bool,
+    ) -> Result<(), Error>
+    {
+        let mut ledger = self.ledger();
+        let actor_id = ledger.resolve_strict_user(actor)?;
+        let token_id = ledger.resolve_strict_token(token)?;
+        let user_id = ledger.resolve_strict_user(user)?;
+
+        ledger.check_right(TokenRight::Delegate, actor_id, token_id)?;
+        ledger.append(vec![Record::Minter {
+            token_id,
+            user_id,
+            allowed,
+        }])
+    }
+
+    async fn get_minters(&self, token: TokenQueryModeStrict<'_>) -> Result<Vec<User>, Error>
+    {
+        let ledger = self.ledger();
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        Ok(ledger
+            .minters
+            .range((token_id, UserID::MIN)..=(token_id, UserID::MAX))
+            .map(|&(_, user_id)| ledger.user(user_id))
+            .collect())
+    }
+
+    async fn issue(
+        &self,
+        issuer: UserQueryModeStrict<'_>,
+        holder: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        amount: TokenAmount,
+    ) -> Result<TokenAmount, Error>
+    {
+        let mut ledger = self.ledger();
+        let issuer_id = ledger.resolve_strict_user(issuer)?;
+        let holder_id = ledger.resolve_strict_user(holder)?;
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        sql_common::check_issue(issuer_id, holder_id)?;
+        ledger.check_right(TokenRight::Issue, issuer_id, token_id)?;
+        // Minting debits the issuer only, who isn't subject to the policy
+        let policy = match amount > 0
+        {
+            true => OverdraftPolicy::Free,
+            false => ledger.overdraft_policy(token_id),
+        };
+        if let Some((debited_id, debit)) = policy.limited_debit(issuer_id, holder_id, amount)?
+        {
+            policy.check(ledger.balance(debited_id, token_id)?, debit)?;
+        }
+        ledger.new_total(issuer_id, holder_id, token_id, amount)?;
+
+        ledger.append(vec![Record::Transaction {
+            sender_id: issuer_id,
+            receiver_id: holder_id,
+            token_id,
+            amount,
+            info: TransactionInfo {
+                created_at: Some(Utc::now()),
+                ..TransactionInfo::default()
+            },
+        }])?;
+        ledger.balance(holder_id, token_id)
+    }
+
+    async fn get_overdraft_policy(&self, token: TokenQueryModeStrict<'_>) -> Result<OverdraftPolicy, Error>
+    {
+        let ledger = self.ledger();
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        Ok(ledger.overdraft_policy(token_id))
+    }
+
+    async fn set_overdraft_policy(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        policy: OverdraftPolicy,
+    ) -> Result<(), Error>
+    {
+        let mut ledger = self.ledger();
+        let actor_id = ledger.resolve_strict_user(actor)?;
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        ledger.check_right(TokenRight::Edit, actor_id, token_id)?;
+        ledger.append(vec![Record::OverdraftPolicy {
+            token_id,
+            credit_limit: policy.credit_limit(),
+        }])
+    }
+
+    async fn get_balance(&self, user: UserQueryModeStrict<'_>, token: TokenQueryModeStrict<'_>) -> Result<TokenAmount, Error>
+    {
+        let ledger = self.ledger();
+        let user_id = ledger.resolve_strict_user(user)?;
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        ledger.balance(user_id, token_id)
+    }
+
+    async fn get_current_total(
+        &self,
+        sender: UserQueryModeStrict<'_>,
+        receiver: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+    ) -> Result<Option<TokenAmount>, Error>
+    {
+        let ledger = self.ledger();
+        let sender_id = ledger.resolve_strict_user(sender)?;
+        let receiver_id = ledger.resolve_strict_user(receiver)?;
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        Ok(ledger.balances.get(&(receiver_id, token_id, sender_id)).copied())
+    }
+
+    async fn transaction(
+        &self,
+        sender: UserQueryModeWithCreation<'_>,
+        receiver: UserQueryModeWithCreation<'_>,
+        token: TokenQueryModeWithCreation<'_>,
+        amount: TokenAmount,
+        details: &TransactionDetails<'_>,
+    ) -> Result<TokenAmount, Error>
+    {
+        let mut ledger = self.ledger();
+
+        let leg = TransferLeg {
+            sender,
+            receiver,
+            token,
+            amount,
+            details: details.clone(),
+        };
+        let (new_total, records) = ledger.transfer(&leg, None, self.key_retention)?;
+        if let Err(err) = ledger.write(&records)
+        {
+            ledger.discard(records);
+            return Err(err);
+        }
+        for record in records
+        {
+            ledger.apply(record)?;
+        }
+
+        Ok(new_total)
+    }
+
+    async fn batch_transaction(&self, legs: &[TransferLeg<'_>]) -> Result<Vec<TokenAmount>, Error>
+    {
+        let mut ledger = self.ledger();
+
+        match ledger.batch(legs, self.key_retention)
+        {
+            Ok(totals) => Ok(totals),
+            Err(err) =>
+            {
+                // Go back to the state of the untouched file
+                ledger.reload()?;
+                Err(err)
+            }
+        }
+    }
+
+    async fn import(&self, records: &[ImportRecord<'_>], dry_run: bool) -> Result<ImportReport, Error>
+    {
+        let mut ledger = self.ledger();
+
+        let result = ledger.import(records, dry_run, self.key_retention);
+        if !matches!(result, Ok(ImportReport { committed: true, .. }))
+        {
+            // Go back to the state of the untouched file
+            ledger.reload()?;
+        }
+        result
+    }
+
+    async fn reverse_transaction(&self, id: TransactionID, reason: &str) -> Result<HistoryEntry, Error>
+    {
+        let mut ledger = self.ledger();
+
+        let (target, amount) = sql_common::check_reversal(id, ledger.reversal_target(id))?;
+        let policy = ledger.overdraft_policy(target.token_id);
+        if let Some((debited_id, debit)) = policy.limited_debit(target.sender_id, target.receiver_id, amount)?
+        {
+            policy.check(ledger.balance(debited_id, target.token_id)?, debit)?;
+        }
+        // Everything is checked before the record gets written
+        ledger.new_total(target.sender_id, target.receiver_id, target.token_id, amount)?;
+
+        ledger.append(vec![Record::Transaction {
+            sender_id: target.sender_id,
+            receiver_id: target.receiver_id,
+            token_id: target.token_id,
+            amount,
+            info: TransactionInfo {
+                created_at: Some(Utc::now()),
+                memo: Some(reason.to_string()),
+                reference: target.reference,
+                actor_id: None,
+                reverses_id: Some(id),
+                ..TransactionInfo::default()
+            },
+        }])?;
+
+        let reversal = ledger
+            .transactions
+            .last()
+            .and_then(|record| ledger.history_entry(ledger.last_transaction_id(), record));
+        reversal.ok_or_else(|| storage_error(format!("reversal of transaction {id} is missing")))
+    }
+
+    async fn query_history(
+        &self,
+        filter: &HistoryFilter<'_>,
+        order: Order,
+        after: Option<HistoryCursor>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, Error>
+    {
+        let ledger = self.ledger();
+        let sender_id = filter.sender.map(|sender| ledger.resolve_strict_user(sender)).transpose()?;
+        let receiver_id = filter.receiver.map(|receiver| ledger.resolve_strict_user(receiver)).transpose()?;
+        let token_id = filter.token.map(|token| ledger.resolve_strict_token(token)).transpose()?;
+        let matches = |filter: Option<DbPk>, id: DbPk| filter.is_none_or(|filter| filter == id);
+        let happened = |entry: &HistoryEntry, condition: &dyn Fn(DateTime<Utc>) -> bool| entry.created_at.is_some_and(condition);
+
+        // Transactions are identified by their position in the log (which compaction preserves)
+        let mut entries: Vec<HistoryEntry> = ledger
+            .transactions
+            .iter()
+            .zip(1..)
+            .filter_map(|(record, id)| ledger.history_entry(id, record))
+            .filter(|entry| {
+                matches(sender_id, entry.sender_id)
+                    && matches(receiver_id, entry.receiver_id)
+                    && matches(token_id, entry.token_id)
+                    && filter.min_amount.is_none_or(|min_amount| entry.amount >= min_amount)
+                    && filter.max_amount.is_none_or(|max_amount| entry.amount <= max_amount)
+                    && filter.since.is_none_or(|since| happened(entry, &|time| time >= since))
+                    && filter.until.is_none_or(|until| happened(entry, &|time| time < until))
+                    && filter
+                        .memo
+                        .as_ref()
+                        .is_none_or(|memo| entry.memo.as_ref().is_some_and(|text| NameMatch::Substring.matches(text, memo)))
+            })
+            .collect();
+
+        entries.sort_by_key(HistoryCursor::after);
+        if order == Order::Desc
+        {
+            entries.reverse();
+        }
+        let is_after = |entry: &HistoryEntry, after: HistoryCursor| match order
+        {
+            Order::Asc => HistoryCursor::after(entry) > after,
+            Order::Desc => HistoryCursor::after(entry) < after,
+        };
+        Ok(entries
+            .into_iter()
+            .filter(|entry| after.is_none_or(|after| is_after(entry, after)))
+            .take(limit)
+            .collect())
+    }
+
+    async fn list_user_token(
+        &self,
+        receiver: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        order: Order,
+        order_by: Option<OrderBySenderOrAmount>,
+    ) -> Result<Vec<RelativeUserTokenAmountEntry>, Error>
+    {
+        let ledger = self.ledger();
+        let receiver_id = ledger.resolve_strict_user(receiver)?;
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        let mut rows = ledger.balance_rows(|receiver, token| receiver == receiver_id && token == token_id, false);
+        let key = match order_by.unwrap_or(OrderBySenderOrAmount::Amount)
+        {
+            OrderBySenderOrAmount::Sender => SortKey::Sender,
+            OrderBySenderOrAmount::Amount => SortKey::Amount,
+        };
+        sort_balance_rows(&mut rows, order, key);
+
+        Ok(rows.into_iter().map(|row| row.entry()).collect())
+    }
+
+    async fn list_tokens_by_user(
+        &self,
+        receiver: UserQueryModeStrict<'_>,
+        order: Order,
+        order_by: Option<OrderByTokenOrSenderOrAmount>,
+    ) -> Result<Vec<RelativeTokenAmountEntry>, Error>
+    {
+        let ledger = self.ledger();
+        let receiver_id = ledger.resolve_strict_user(receiver)?;
+
+        let mut rows = ledger.balance_rows(|receiver, _| receiver == receiver_id, false);
+        let key = match order_by.unwrap_or(OrderByTokenOrSenderOrAmount::Amount)
+        {
+            OrderByTokenOrSenderOrAmount::Token => SortKey::Group,
+            OrderByTokenOrSenderOrAmount::Sender => SortKey::Sender,
+            OrderByTokenOrSenderOrAmount::Amount => SortKey::Amount,
+        };
+        sort_balance_rows(&mut rows, order, key);
+
+        Ok(group_balance_rows(rows)
+            .into_iter()
+            .map(|(first, amount_by_sender)| RelativeTokenAmountEntry {
+                token: ledger.token(first.group_id),
+                amount_by_sender,
+            })
+            .collect())
+    }
+
+    async fn list_users_by_token(
+        &self,
+        token: TokenQueryModeStrict<'_>,
+        order: Order,
+        order_by: Option<OrderByReceiverOrSenderOrAmount>,
+    ) -> Result<Vec<RelativeUserAmountEntry>, Error>
+    {
+        let ledger = self.ledger();
+        let token_id = ledger.resolve_strict_token(token)?;
+
+        let mut rows = ledger.balance_rows(|_, token| token == token_id, true);
+        let key = match order_by.unwrap_or(OrderByReceiverOrSenderOrAmount::Amount)
+        {
+            OrderByReceiverOrSenderOrAmount::Receiver => SortKey::Group,
+            OrderByReceiverOrSenderOrAmount::Sender => SortKey::Sender,
+            OrderByReceiverOrSenderOrAmount::Amount => SortKey::Amount,
+        };
+        sort_balance_rows(&mut rows, order, key);
+
+        Ok(group_balance_rows(rows)
+            .into_iter()
+            .map(|(first, amount_by_sender)| RelativeUserAmountEntry {
+                receiver: User {
+                    id:   first.group_id,
+                    name: first.group_name,
+                },
+                amount_by_sender,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,1036 @@
+use super::{
+    persistance_layer::*,
+    sql_common::{
+        self, group_balance_rows, BalanceRow, HistoryIds, KeyedTransaction, Participants, ReversalTarget, RowCounts, SqlLedger,
+        TransactionNote, HISTORY_SELECT, REVERSAL_TARGET_QUERY, REVERSED_COLUMN, ROW_COUNTS_QUERY, TOKEN_COLUMNS,
+    },
+    CoreConfig, DedupeReport, Error, HistoryCursor, HistoryFilter, Identifier, ImportRecord, ImportReport, NameMatch, NameNormalization,
+    Order, OrderByReceiverOrSenderOrAmount, OrderBySenderOrAmount, OrderByTokenOrSenderOrAmount, OverdraftPolicy, TokenAmount, TokenEdit,
+    TokenID, TokenQueryModeStrict, TokenQueryModeWithCreation, TokenRight, TransactionDetails, TransactionID, TransferLeg, UserID,
+    UserQueryModeStrict, UserQueryModeWithCreation,
+};
+use async_trait::async_trait;
+use chrono::TimeDelta;
+use sqlx::{
+    migrate::{MigrateDatabase, Migrator},
+    postgres::PgPoolOptions,
+    PgConnection, PgPool, Postgres,
+};
+use std::collections::HashMap;
+
+/// Migrations of the "migrations_postgres" directory, embedded at compile time
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations_postgres");
+
+/// Persistance layer for a (possibly shared) PostgreSQL database.
+///
+/// "User" is a reserved word in PostgreSQL, so the user table has to be quoted in every query.
+///
+/// Several instances might share the database, so write transactions lock the rows their checks depend on: the
+/// debited user, the token and the idempotency key (see [SqlLedger]). Names are unique by their key.
+#[derive(Debug)]
+pub struct DataPostgres
+{
+    connection_pool: PgPool,
+    names:           NameNormalization,
+    key_retention:   TimeDelta,
+}
+
+impl DataPostgres
+{
+    pub async fn new(config: &CoreConfig) -> Result<DataPostgres, Error>
+    {
+        let url = &config.database_url;
+        if config.create_if_missing
+            && !Postgres::database_exists(url)
+                .await
+                .map_err(|err| Error::BackendUnavailable(Box::new(err)))?
+        {
+            Postgres::create_database(url).await?;
+        }
+
+        let connection_pool = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect(url)
+            .await
+            .map_err(|err| Error::BackendUnavailable(Box::new(err)))?;
+
+        sql_common::check_schema_version(&MIGRATOR, &connection_pool).await?;
+
+        let data = DataPostgres {
+            connection_pool,
+            names: config.names,
+            key_retention: config.idempotency_retention(),
+        };
+        if config.run_migrations
+        {
+            data.migrate_up().await?;
+        }
+        Ok(data)
+    }
+
+    /// Fills in the missing name keys (of rows created before names were unique). Rows, whose key is already taken,
+    /// are left without one, until they get merged by [PersistanceLayer::dedupe].
+    async fn assign_missing_name_keys(&self) -> Result<(), Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+        // Whether a key is taken must not change, until the keys are assigned
+        sqlx::query(r#"LOCK TABLE "User", Token IN EXCLUSIVE MODE"#)
+            .execute(&mut *tx)
+            .await?;
+
+        let users: Vec<User> = sqlx::query_as(r#"SELECT id, name FROM "User" WHERE name_key IS NULL ORDER BY id"#)
+            .fetch_all(&mut *tx)
+            .await?;
+        for user in users
+        {
+            sqlx::query(r#"UPDATE "User" SET name_key = $1 WHERE id = $2 AND NOT EXISTS (SELECT 1 FROM "User" WHERE name_key = $1)"#)
+                .bind(self.names.key(&user.name))
+                .bind(user.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        let tokens: Vec<(TokenID, String)> = sqlx::query_as("SELECT id, name FROM Token WHERE name_key IS NULL ORDER BY id")
+            .fetch_all(&mut *tx)
+            .await?;
+        for (id, name) in tokens
+        {
+            sqlx::query("UPDATE Token SET name_key = $1 WHERE id = $2 AND NOT EXISTS (SELECT 1 FROM Token WHERE name_key = $1)")
+                .bind(self.names.key(&name))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // ============================================ Transaction helpers ============================================
+    // These work on a plain connection, so they can be used inside of an already running SQL transaction.
+
+    /// A user, that has been created concurrently, is used instead of a new one
+    async fn get_or_create_user_by_name(&self, conn: &mut PgConnection, name: &str, create_if_missing: bool) -> Result<UserID, Error>
+    {
+        let name = self.names.stored(name);
+        let key = self.names.key(name);
+        let select = r#"SELECT id FROM "User" WHERE name_key = $1"#;
+        if let Some(id) = sqlx::query_scalar(select).bind(&key).fetch_optional(&mut *conn).await?
+        {
+            return Ok(id);
+        }
+        if !create_if_missing
+        {
+            return Err(Error::UserNotFound(Identifier::Name(name.to_string())));
+        }
+
+        let id = sqlx::query_scalar(r#"INSERT INTO "User"(name, name_key) VALUES ($1, $2) ON CONFLICT (name_key) DO NOTHING RETURNING id"#)
+            .bind(name)
+            .bind(&key)
+            .fetch_optional(&mut *conn)
+            .await?;
+        match id
+        {
+            Some(id) => Ok(id),
+            None => Ok(sqlx::query_scalar(select).bind(&key).fetch_one(&mut *conn).await?),
+        }
+    }
+
+    /// A missing token gets created with the owner _owner_id_, if _create_if_missing_ is set. A token, that has been
+    /// created concurrently, is used instead of a new one.
+    async fn get_or_create_token_by_name(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+        create_if_missing: bool,
+        owner_id: Option<UserID>,
+    ) -> Result<TokenID, Error>
+    {
+        let name = self.names.stored(name);
+        let key = self.names.key(name);
+        let select = "SELECT id FROM Token WHERE name_key = $1";
+        if let Some(id) = sqlx::query_scalar(select).bind(&key).fetch_optional(&mut *conn).await?
+        {
+            return Ok(id);
+        }
+        if !create_if_missing
+        {
+            return Err(Error::TokenNotFound(Identifier::Name(name.to_string())));
+        }
+
+        let id = sqlx::query_scalar(
+            "INSERT INTO Token(name, name_key, owner_id) VALUES ($1, $2, $3) ON CONFLICT (name_key) DO NOTHING RETURNING id",
+        )
+        .bind(name)
+        .bind(&key)
+        .bind(owner_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+        match id
+        {
+            Some(id) => Ok(id),
+            None => Ok(sqlx::query_scalar(select).bind(&key).fetch_one(&mut *conn).await?),
+        }
+    }
+
+    async fn verify_user_id(conn: &mut PgConnection, id: UserID) -> Result<UserID, Error>
+    {
+        sqlx::query_scalar(r#"SELECT id FROM "User" WHERE id = $1"#)
+            .bind(id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .ok_or(Error::UserNotFound(Identifier::Id(id)))
+    }
+
+    async fn verify_token_id(conn: &mut PgConnection, id: TokenID) -> Result<TokenID, Error>
+    {
+        sqlx::query_scalar("SELECT id FROM Token WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .ok_or(Error::TokenNotFound(Identifier::Id(id)))
+    }
+
+    /// Adds the metadata to the tokens
+    async fn with_metadata(conn: &mut PgConnection, mut tokens: Vec<Token>) -> Result<Vec<Token>, Error>
+    {
+        let ids: Vec<TokenID> = tokens.iter().map(|token| token.id).collect();
+        let rows = sqlx::query_as("SELECT token_id, key, value FROM token_metadata WHERE token_id = ANY($1)")
+            .bind(ids)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        sql_common::attach_metadata(&mut tokens, rows);
+        Ok(tokens)
+    }
+
+    async fn token(conn: &mut PgConnection, token_id: TokenID) -> Result<Token, Error>
+    {
+        let token = sqlx::query_as(&format!("SELECT {TOKEN_COLUMNS} FROM Token WHERE id = $1"))
+            .bind(token_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        Ok(Self::with_metadata(conn, vec![token]).await?.remove(0))
+    }
+
+    /// Fails with [Error::NotAuthorized], unless the user has the right for the token. Otherwise, the token stays
+    /// locked until the end of the transaction, so the right can't be taken away in the meantime.
+    async fn check_right(conn: &mut PgConnection, right: TokenRight, user_id: UserID, token_id: TokenID) -> Result<(), Error>
+    {
+        let (owner_id, is_minter): (Option<UserID>, bool) = sqlx::query_as(
+            "SELECT owner_id, EXISTS (SELECT 1 FROM token_minter WHERE token_id = $1 AND user_id = $2)
+             FROM Token
+             WHERE id = $1
+             FOR NO KEY UPDATE",
+        )
+        .bind(token_id)
+        .bind(user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+        right.check(user_id, token_id, owner_id, is_minter)
+    }
+}
+
+#[async_trait]
+impl SqlLedger for DataPostgres
+{
+    type Database = Postgres;
+
+    fn names(&self) -> &NameNormalization
+    {
+        &self.names
+    }
+
+    async fn resolve_user(&self, conn: &mut PgConnection, user: UserQueryModeWithCreation<'_>) -> Result<UserID, Error>
+    {
+        match user
+        {
+            UserQueryModeWithCreation::ById(id) => Self::verify_user_id(conn, id).await,
+            UserQueryModeWithCreation::ByName(name) => self.get_or_create_user_by_name(conn, name, false).await,
+            UserQueryModeWithCreation::ByNameOrCreate(name) => self.get_or_create_user_by_name(conn, name, true).await,
+        }
+    }
+
+    async fn resolve_token(
+        &self,
+        conn: &mut PgConnection,
+        token: TokenQueryModeWithCreation<'_>,
+        owner_id: Option<UserID>,
+    ) -> Result<TokenID, Error>
+    {
+        match token
+        {
+            TokenQueryModeWithCreation::ById(id) => Self::verify_token_id(conn, id).await,
+            TokenQueryModeWithCreation::ByName(name) => self.get_or_create_token_by_name(conn, name, false, None).await,
+            TokenQueryModeWithCreation::ByNameOrCreate(name) => self.get_or_create_token_by_name(conn, name, true, owner_id).await,
+        }
+    }
+
+    /// Shares the lock of the token with other transactions, so it can't be edited until this one ends
+    async fn overdraft_policy(conn: &mut PgConnection, token_id: TokenID) -> Result<OverdraftPolicy, Error>
+    {
+        let credit_limit = sqlx::query_scalar("SELECT credit_limit FROM Token WHERE id = $1 FOR SHARE")
+            .bind(token_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        Ok(OverdraftPolicy::from_credit_limit(credit_limit))
+    }
+
+    async fn has_right(conn: &mut PgConnection, right: TokenRight, user_id: UserID, token_id: TokenID) -> Result<bool, Error>
+    {
+        let (owner_id, is_minter): (Option<UserID>, bool) = sqlx::query_as(
+            "SELECT owner_id, EXISTS (SELECT 1 FROM token_minter WHERE token_id = $1 AND user_id = $2) FROM Token WHERE id = $1",
+        )
+        .bind(token_id)
+        .bind(user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+        Ok(right.allows(user_id, owner_id, is_minter))
+    }
+
+    async fn balance(conn: &mut PgConnection, user_id: UserID, token_id: TokenID) -> Result<TokenAmount, Error>
+    {
+        let totals: Vec<(UserID, UserID, TokenAmount)> = sqlx::query_as(
+            "SELECT receiver_id, sender_id, current_total FROM user_balance WHERE token_id = $2 AND (receiver_id = $1 OR sender_id = $1)",
+        )
+        .bind(user_id)
+        .bind(token_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        sql_common::balance(user_id, totals)
+    }
+
+    async fn current_total(conn: &mut PgConnection, (sender_id, receiver_id, token_id): Participants)
+        -> Result<Option<TokenAmount>, Error>
+    {
+        sqlx::query_scalar("SELECT current_total FROM user_balance WHERE sender_id = $1 AND receiver_id = $2 AND token_id = $3")
+            .bind(sender_id)
+            .bind(receiver_id)
+            .bind(token_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(Error::from)
+    }
+
+    /// "FOR UPDATE" would also block the key share lock, that the foreign keys of every new transaction take on their
+    /// users, so two users sending to each other would deadlock
+    async fn lock_debited(conn: &mut PgConnection, user_id: UserID) -> Result<(), Error>
+    {
+        sqlx::query(r#"SELECT id FROM "User" WHERE id = $1 FOR NO KEY UPDATE"#)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_history(
+        conn: &mut PgConnection,
+        (sender_id, receiver_id, token_id): Participants,
+        amount: TokenAmount,
+        note: TransactionNote<'_>,
+    ) -> Result<TransactionID, Error>
+    {
+        Ok(sqlx::query_scalar(
+            "INSERT INTO transaction_history(sender_id, receiver_id, token_id, amount, memo, reference, actor_id, reverses_id, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, COALESCE($9, now()))
+             RETURNING id",
+        )
+        .bind(sender_id)
+        .bind(receiver_id)
+        .bind(token_id)
+        .bind(amount)
+        .bind(note.memo)
+        .bind(note.reference)
+        .bind(note.actor_id)
+        .bind(note.reverses_id)
+        .bind(note.created_at)
+        .fetch_one(&mut *conn)
+        .await?)
+    }
+
+    /// The key doesn't have a row yet, that could be locked, so it's locked by a hash of it instead
+    async fn keyed_transaction(&self, conn: &mut PgConnection, key: &str) -> Result<Option<KeyedTransaction>, Error>
+    {
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+            .bind(key)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("DELETE FROM transaction_idempotency WHERE created_at < $1")
+            .bind(sql_common::idempotency_cutoff(self.key_retention))
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(sqlx::query_as(
+            "SELECT history.sender_id, history.receiver_id, history.token_id, history.amount, keyed.new_total
+             FROM transaction_idempotency AS keyed
+             JOIN transaction_history AS history ON history.id = keyed.transaction_id
+             WHERE keyed.idempotency_key = $1",
+        )
+        .bind(key)
+        .fetch_optional(&mut *conn)
+        .await?)
+    }
+
+    async fn insert_idempotency_key(
+        conn: &mut PgConnection,
+        key: &str,
+        transaction_id: TransactionID,
+        new_total: TokenAmount,
+    ) -> Result<(), Error>
+    {
+        sqlx::query("INSERT INTO transaction_idempotency(idempotency_key, transaction_id, new_total) VALUES ($1, $2, $3)")
+            .bind(key)
+            .bind(transaction_id)
+            .bind(new_total)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn row_counts(conn: &mut PgConnection) -> Result<RowCounts, Error>
+    {
+        Ok(sqlx::query_as(ROW_COUNTS_QUERY).fetch_one(&mut *conn).await?)
+    }
+
+    async fn all_users(conn: &mut PgConnection) -> Result<Vec<User>, Error>
+    {
+        Ok(sqlx::query_as(r#"SELECT id, name FROM "User""#).fetch_all(&mut *conn).await?)
+    }
+
+    async fn all_tokens(conn: &mut PgConnection) -> Result<Vec<Token>, Error>
+    {
+        Ok(sqlx::query_as(&format!("SELECT {TOKEN_COLUMNS} FROM Token"))
+            .fetch_all(&mut *conn)
+            .await?)
+    }
+
+    async fn merge_user(conn: &mut PgConnection, kept_id: UserID, merged_id: UserID) -> Result<(), Error>
+    {
+        for query in [
+            "UPDATE transaction_history SET sender_id = $1 WHERE sender_id = $2",
+            "UPDATE transaction_history SET receiver_id = $1 WHERE receiver_id = $2",
+            "UPDATE transaction_history SET actor_id = NULLIF($1, sender_id) WHERE actor_id = $2",
+            "UPDATE Token SET owner_id = $1 WHERE owner_id = $2",
+            "INSERT INTO token_minter(token_id, user_id) SELECT token_id, $1 FROM token_minter WHERE user_id = $2
+             ON CONFLICT DO NOTHING",
+        ]
+        {
+            sqlx::query(query).bind(kept_id).bind(merged_id).execute(&mut *conn).await?;
+        }
+        sqlx::query("DELETE FROM token_minter WHERE user_id = $1")
+            .bind(merged_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(r#"DELETE FROM "User" WHERE id = $1"#)
+            .bind(merged_id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn merge_token(conn: &mut PgConnection, kept_id: TokenID, merged_id: TokenID) -> Result<(), Error>
+    {
+        for query in [
+            "UPDATE transaction_history SET token_id = $1 WHERE token_id = $2",
+            "INSERT INTO token_minter(token_id, user_id) SELECT $1, user_id FROM token_minter WHERE token_id = $2
+             ON CONFLICT DO NOTHING",
+            "INSERT INTO token_metadata(token_id, key, value) SELECT $1, key, value FROM token_metadata WHERE token_id = $2
+             ON CONFLICT DO NOTHING",
+        ]
+        {
+            sqlx::query(query).bind(kept_id).bind(merged_id).execute(&mut *conn).await?;
+        }
+        for query in [
+            "DELETE FROM token_minter WHERE token_id = $1",
+            "DELETE FROM token_metadata WHERE token_id = $1",
+            "DELETE FROM Token WHERE id = $1",
+        ]
+        {
+            sqlx::query(query).bind(merged_id).execute(&mut *conn).await?;
+        }
+        Ok(())
+    }
+
+    async fn clear_balances(conn: &mut PgConnection) -> Result<(), Error>
+    {
+        sqlx::query("DELETE FROM user_balance").execute(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn rebuild_balances(conn: &mut PgConnection) -> Result<(), Error>
+    {
+        sqlx::query(
+            "INSERT INTO user_balance(sender_id, receiver_id, token_id, current_total)
+             SELECT sender_id, receiver_id, token_id, SUM(amount)
+             FROM transaction_history
+             GROUP BY sender_id, receiver_id, token_id",
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    async fn clear_name_keys(conn: &mut PgConnection) -> Result<(), Error>
+    {
+        sqlx::query(r#"UPDATE "User" SET name_key = NULL"#).execute(&mut *conn).await?;
+        sqlx::query("UPDATE Token SET name_key = NULL").execute(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn set_user_name_key(conn: &mut PgConnection, user_id: UserID, key: &str) -> Result<(), Error>
+    {
+        sqlx::query(r#"UPDATE "User" SET name_key = $1 WHERE id = $2"#)
+            .bind(key)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_token_name_key(conn: &mut PgConnection, token_id: TokenID, key: &str) -> Result<(), Error>
+    {
+        sqlx::query("UPDATE Token SET name_key = $1 WHERE id = $2")
+            .bind(key)
+            .bind(token_id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PersistanceLayer for DataPostgres
+{
+    async fn migration_status(&self) -> Result<Vec<MigrationInfo>, Error>
+    {
+        sql_common::migration_status(&MIGRATOR, &self.connection_pool).await
+    }
+
+    async fn migrate_up(&self) -> Result<(), Error>
+    {
+        sql_common::migrate_up(&MIGRATOR, &self.connection_pool).await?;
+        self.assign_missing_name_keys().await
+    }
+
+    async fn migrate_down(&self) -> Result<Option<MigrationInfo>, Error>
+    {
+        sql_common::migrate_down(&MIGRATOR, &self.connection_pool).await
+    }
+
+    async fn dedupe(&self, dry_run: bool) -> Result<DedupeReport, Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+        // Merging touches nearly everything, so the others have to wait (reading is still possible)
+        sqlx::query(r#"LOCK TABLE "User", Token, transaction_history IN EXCLUSIVE MODE"#)
+            .execute(&mut *tx)
+            .await?;
+
+        self.merge_duplicates(tx, dry_run).await
+    }
+
+    async fn create_user(&self, name: &str) -> Result<User, Error>
+    {
+        let name = self.names.stored(name);
+        let id = sqlx::query_scalar(r#"INSERT INTO "User"(name, name_key) VALUES ($1, $2) RETURNING id"#)
+            .bind(name)
+            .bind(self.names.key(name))
+            .fetch_one(&self.connection_pool)
+            .await
+            .map_err(|err| sql_common::duplicate_name_error(err, name))?;
+
+        Ok(User {
+            id,
+            name: name.to_string(),
+        })
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>, Error>
+    {
+        Ok(sqlx::query_as(r#"SELECT id, name FROM "User" ORDER BY id"#)
+            .fetch_all(&self.connection_pool)
+            .await?)
+    }
+
+    async fn query_user(&self, name: &str, matching: NameMatch) -> Result<Vec<User>, Error>
+    {
+        let query = format!(
+            r#"SELECT id, name FROM "User" WHERE {} ORDER BY id"#,
+            sql_common::name_condition(matching, "$1")
+        );
+        Ok(sqlx::query_as(&query)
+            .bind(sql_common::name_parameter(name, matching, &self.names))
+            .fetch_all(&self.connection_pool)
+            .await?)
+    }
+
+    async fn create_token(&self, name: &str, owner: Option<UserQueryModeStrict<'_>>) -> Result<Token, Error>
+    {
+        let name = self.names.stored(name);
+        let mut conn = self.connection_pool.acquire().await?;
+        let owner_id = match owner
+        {
+            Some(owner) => Some(self.resolve_user(&mut conn, owner.into()).await?),
+            None => None,
+        };
+
+        sqlx::query_as(&format!(
+            "INSERT INTO Token(name, name_key, owner_id) VALUES ($1, $2, $3) RETURNING {TOKEN_COLUMNS}"
+        ))
+        .bind(name)
+        .bind(self.names.key(name))
+        .bind(owner_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|err| sql_common::duplicate_name_error(err, name))
+    }
+
+    async fn get_all_tokens(&self) -> Result<Vec<Token>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let tokens = sqlx::query_as(&format!("SELECT {TOKEN_COLUMNS} FROM Token ORDER BY id"))
+            .fetch_all(&mut *conn)
+            .await?;
+
+        Self::with_metadata(&mut conn, tokens).await
+    }
+
+    async fn query_token(&self, name: &str, matching: NameMatch) -> Result<Vec<Token>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let query = format!(
+            "SELECT {TOKEN_COLUMNS} FROM Token WHERE {} ORDER BY id",
+            sql_common::name_condition(matching, "$1")
+        );
+        let tokens = sqlx::query_as(&query)
+            .bind(sql_common::name_parameter(name, matching, &self.names))
+            .fetch_all(&mut *conn)
+            .await?;
+
+        Self::with_metadata(&mut conn, tokens).await
+    }
+
+    async fn get_token(&self, token: TokenQueryModeStrict<'_>) -> Result<Token, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        Self::token(&mut conn, token_id).await
+    }
+
+    async fn edit_token(&self, actor: UserQueryModeStrict<'_>, token: TokenQueryModeStrict<'_>, edit: &TokenEdit) -> Result<Token, Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+        let actor_id = self.resolve_user(&mut tx, actor.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+
+        Self::check_right(&mut tx, TokenRight::Edit, actor_id, token_id).await?;
+        let mut token = Self::token(&mut tx, token_id).await?;
+        if edit.changes_decimals(&token)
+            && sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM transaction_history WHERE token_id = $1)")
+                .bind(token_id)
+                .fetch_one(&mut *tx)
+                .await?
+        {
+            return Err(Error::DecimalsInUse(Identifier::Id(token_id)));
+        }
+        edit.apply(&mut token);
+
+        sqlx::query("UPDATE Token SET description = $1, symbol = $2, decimals = $3 WHERE id = $4")
+            .bind(&token.description)
+            .bind(&token.symbol)
+            .bind(i32::from(token.decimals))
+            .bind(token_id)
+            .execute(&mut *tx)
+            .await?;
+        for (key, value) in &edit.metadata
+        {
+            let query = match value
+            {
+                Some(value) => sqlx::query(
+                    "INSERT INTO token_metadata(token_id, key, value) VALUES ($1, $2, $3)
+                     ON CONFLICT (token_id, key) DO UPDATE SET value = excluded.value",
+                )
+                .bind(token_id)
+                .bind(key)
+                .bind(value),
+                None => sqlx::query("DELETE FROM token_metadata WHERE token_id = $1 AND key = $2")
+                    .bind(token_id)
+                    .bind(key),
+            };
+            query.execute(&mut *tx).await?;
+        }
+
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    async fn set_token_owner(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        owner: UserQueryModeStrict<'_>,
+    ) -> Result<(), Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+        let actor_id = self.resolve_user(&mut tx, actor.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+        let owner_id = self.resolve_user(&mut tx, owner.into()).await?;
+
+        Self::check_right(&mut tx, TokenRight::Transfer, actor_id, token_id).await?;
+        sqlx::query("UPDATE Token SET owner_id = $1 WHERE id = $2")
+            .bind(owner_id)
+            .bind(token_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_minter(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        user: UserQueryModeStrict<'_>,
+        allowed: bool,
+    ) -> Result<(), Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+        let actor_id = self.resolve_user(&mut tx, actor.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+        let user_id = self.resolve_user(&mut tx, user.into()).await?;
+
+        Self::check_right(&mut tx, TokenRight::Delegate, actor_id, token_id).await?;
+        let query = match allowed
+        {
+            true => "INSERT INTO token_minter(token_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            false => "DELETE FROM token_minter WHERE token_id = $1 AND user_id = $2",
+        };
+        sqlx::query(query).bind(token_id).bind(user_id).execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_minters(&self, token: TokenQueryModeStrict<'_>) -> Result<Vec<User>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        Ok(sqlx::query_as(
+            r#"SELECT "User".id, "User".name
+               FROM token_minter
+               JOIN "User" ON "User".id = token_minter.user_id
+               WHERE token_minter.token_id = $1
+               ORDER BY "User".id"#,
+        )
+        .bind(token_id)
+        .fetch_all(&mut *conn)
+        .await?)
+    }
+
+    async fn issue(
+        &self,
+        issuer: UserQueryModeStrict<'_>,
+        holder: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        amount: TokenAmount,
+    ) -> Result<TokenAmount, Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+        let issuer_id = self.resolve_user(&mut tx, issuer.into()).await?;
+        let holder_id = self.resolve_user(&mut tx, holder.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+
+        sql_common::check_issue(issuer_id, holder_id)?;
+        Self::check_right(&mut tx, TokenRight::Issue, issuer_id, token_id).await?;
+        // Minting debits the issuer only, who isn't subject to the policy
+        let policy = match amount > 0
+        {
+            true => OverdraftPolicy::Free,
+            false => Self::overdraft_policy(&mut tx, token_id).await?,
+        };
+        Self::insert_transaction(
+            &mut tx,
+            (issuer_id, holder_id, token_id),
+            amount,
+            policy,
+            TransactionNote::default(),
+        )
+        .await?;
+        let balance = Self::balance(&mut tx, holder_id, token_id).await?;
+
+        tx.commit().await?;
+        Ok(balance)
+    }
+
+    async fn get_overdraft_policy(&self, token: TokenQueryModeStrict<'_>) -> Result<OverdraftPolicy, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        Self::overdraft_policy(&mut conn, token_id).await
+    }
+
+    async fn set_overdraft_policy(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        policy: OverdraftPolicy,
+    ) -> Result<(), Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+        let actor_id = self.resolve_user(&mut tx, actor.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+
+        Self::check_right(&mut tx, TokenRight::Edit, actor_id, token_id).await?;
+        sqlx::query("UPDATE Token SET credit_limit = $1 WHERE id = $2")
+            .bind(policy.credit_limit())
+            .bind(token_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_balance(&self, user: UserQueryModeStrict<'_>, token: TokenQueryModeStrict<'_>) -> Result<TokenAmount, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let user_id = self.resolve_user(&mut conn, user.into()).await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        Self::balance(&mut conn, user_id, token_id).await
+    }
+
+    async fn get_current_total(
+        &self,
+        sender: UserQueryModeStrict<'_>,
+        receiver: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+    ) -> Result<Option<TokenAmount>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let sender_id = self.resolve_user(&mut conn, sender.into()).await?;
+        let receiver_id = self.resolve_user(&mut conn, receiver.into()).await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        Self::current_total(&mut conn, (sender_id, receiver_id, token_id)).await
+    }
+
+    async fn transaction(
+        &self,
+        sender: UserQueryModeWithCreation<'_>,
+        receiver: UserQueryModeWithCreation<'_>,
+        token: TokenQueryModeWithCreation<'_>,
+        amount: TokenAmount,
+        details: &TransactionDetails<'_>,
+    ) -> Result<TokenAmount, Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+        let leg = TransferLeg {
+            sender,
+            receiver,
+            token,
+            amount,
+            details: details.clone(),
+        };
+        let new_total = self.transfer(&mut tx, &leg, None).await?;
+        tx.commit().await?;
+
+        Ok(new_total)
+    }
+
+    async fn batch_transaction(&self, legs: &[TransferLeg<'_>]) -> Result<Vec<TokenAmount>, Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+
+        // Every leg sees the balances after the previous ones, and any failure rolls back all of them
+        let mut totals = Vec::with_capacity(legs.len());
+        for leg in legs
+        {
+            totals.push(self.transfer(&mut tx, leg, None).await?);
+        }
+        tx.commit().await?;
+
+        Ok(totals)
+    }
+
+    async fn import(&self, records: &[ImportRecord<'_>], dry_run: bool) -> Result<ImportReport, Error>
+    {
+        let tx = self.connection_pool.begin().await?;
+        self.import_records(tx, records, dry_run).await
+    }
+
+    async fn reverse_transaction(&self, id: TransactionID, reason: &str) -> Result<HistoryEntry, Error>
+    {
+        let mut tx = self.connection_pool.begin().await?;
+        // Concurrent reversals of the same transaction wait for this one, and then find it reversed
+        sqlx::query("SELECT id FROM transaction_history WHERE id = $1 FOR NO KEY UPDATE")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        let target: Option<ReversalTarget> = sqlx::query_as(&format!("{REVERSAL_TARGET_QUERY} WHERE history.id = $1"))
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let (target, amount) = sql_common::check_reversal(id, target)?;
+
+        let policy = Self::overdraft_policy(&mut tx, target.token_id).await?;
+        let participants = (target.sender_id, target.receiver_id, target.token_id);
+        Self::insert_transaction(&mut tx, participants, amount, policy, target.note(id, reason)).await?;
+        let reversal = sqlx::query_as(&format!("{HISTORY_SELECT} WHERE history.reverses_id = $1"))
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(reversal)
+    }
+
+    async fn query_history(
+        &self,
+        filter: &HistoryFilter<'_>,
+        order: Order,
+        after: Option<HistoryCursor>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let mut ids = HistoryIds::default();
+        if let Some(sender) = filter.sender
+        {
+            ids.sender_id = Some(self.resolve_user(&mut conn, sender.into()).await?);
+        }
+        if let Some(receiver) = filter.receiver
+        {
+            ids.receiver_id = Some(self.resolve_user(&mut conn, receiver.into()).await?);
+        }
+        if let Some(token) = filter.token
+        {
+            ids.token_id = Some(self.resolve_token(&mut conn, token.into(), None).await?);
+        }
+
+        sql_common::history_query(ids, filter, order, after, limit, |time| time)
+            .build_query_as()
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(Error::from)
+    }
+
+    async fn list_user_token(
+        &self,
+        receiver: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        order: Order,
+        order_by: Option<OrderBySenderOrAmount>,
+    ) -> Result<Vec<RelativeUserTokenAmountEntry>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let receiver_id = self.resolve_user(&mut conn, receiver.into()).await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        let order_by = sql_common::order_by_sender_or_amount(order, order_by);
+        let query = format!(
+            r#"SELECT token.id AS group_id, token.name AS group_name, sender.id AS sender_id, sender.name AS sender_name,
+                      balance.current_total AS amount, {REVERSED_COLUMN}
+               FROM user_balance AS balance
+               JOIN "User" AS sender ON sender.id = balance.sender_id
+               JOIN Token AS token ON token.id = balance.token_id
+               WHERE balance.receiver_id = $1 AND balance.token_id = $2
+               ORDER BY {order_by}"#
+        );
+
+        let rows: Vec<BalanceRow> = sqlx::query_as(&query)
+            .bind(receiver_id)
+            .bind(token_id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| row.entry()).collect())
+    }
+
+    async fn list_tokens_by_user(
+        &self,
+        receiver: UserQueryModeStrict<'_>,
+        order: Order,
+        order_by: Option<OrderByTokenOrSenderOrAmount>,
+    ) -> Result<Vec<RelativeTokenAmountEntry>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let receiver_id = self.resolve_user(&mut conn, receiver.into()).await?;
+
+        let order_by = sql_common::order_by_token_or_sender_or_amount(order, order_by);
+        let query = format!(
+            r#"SELECT token.id AS group_id, token.name AS group_name, sender.id AS sender_id, sender.name AS sender_name,
+                      balance.current_total AS amount, {REVERSED_COLUMN}
+               FROM user_balance AS balance
+               JOIN "User" AS sender ON sender.id = balance.sender_id
+               JOIN Token AS token ON token.id = balance.token_id
+               WHERE balance.receiver_id = $1
+               ORDER BY {order_by}"#
+        );
+
+        let rows: Vec<BalanceRow> = sqlx::query_as(&query).bind(receiver_id).fetch_all(&mut *conn).await?;
+
+        let tokens = sqlx::query_as(&format!(
+            "SELECT {TOKEN_COLUMNS} FROM Token WHERE id IN (SELECT token_id FROM user_balance WHERE receiver_id = $1)"
+        ))
+        .bind(receiver_id)
+        .fetch_all(&mut *conn)
+        .await?;
+        let mut tokens: HashMap<TokenID, Token> = Self::with_metadata(&mut conn, tokens)
+            .await?
+            .into_iter()
+            .map(|token| (token.id, token))
+            .collect();
+
+        Ok(group_balance_rows(rows)
+            .into_iter()
+            .filter_map(|(first, amount_by_sender)| {
+                Some(RelativeTokenAmountEntry {
+                    token: tokens.remove(&first.group_id)?,
+                    amount_by_sender,
+                })
+            })
+            .collect())
+    }
+
+    async fn list_users_by_token(
+        &self,
+        token: TokenQueryModeStrict<'_>,
+        order: Order,
+        order_by: Option<OrderByReceiverOrSenderOrAmount>,
+    ) -> Result<Vec<RelativeUserAmountEntry>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        let order_by = sql_common::order_by_receiver_or_sender_or_amount(order, order_by);
+        let query = format!(
+            r#"SELECT receiver.id AS group_id, receiver.name AS group_name, sender.id AS sender_id, sender.name AS sender_name,
+                      balance.current_total AS amount, {REVERSED_COLUMN}
+               FROM user_balance AS balance
+               JOIN "User" AS sender ON sender.id = balance.sender_id
+               JOIN "User" AS receiver ON receiver.id = balance.receiver_id
+               WHERE balance.token_id = $1
+               ORDER BY {order_by}"#
+        );
+
+        let rows: Vec<BalanceRow> = sqlx::query_as(&query).bind(token_id).fetch_all(&mut *conn).await?;
+
+        Ok(group_balance_rows(rows)
+            .into_iter()
+            .map(|(first, amount_by_sender)| RelativeUserAmountEntry {
+                receiver: User {
+                    id:   first.group_id,
+                    name: first.group_name,
+                },
+                amount_by_sender,
+            })
+            .collect())
+    }
+}
@@ -1,187 +1,1081 @@
 use super::{
-    persistance_layer::*, Order, OrderByReceiverOrSenderOrAmount, OrderBySenderOrAmount, OrderByTokenOrSenderOrAmount, TokenAmount,
-    TokenQueryModeStrict, UserQueryModeStrict,
+    persistance_layer::*,
+    sql_common::{
+        self, group_balance_rows, BalanceRow, HistoryIds, KeyedTransaction, Participants, ReversalTarget, RowCounts, SqlLedger,
+        TransactionNote, HISTORY_SELECT, REVERSAL_TARGET_QUERY, REVERSED_COLUMN, ROW_COUNTS_QUERY, TOKEN_COLUMNS,
+    },
+    CoreConfig, DedupeReport, Error, HistoryCursor, HistoryFilter, Identifier, ImportRecord, ImportReport, JournalMode, NameMatch,
+    NameNormalization, Order, OrderByReceiverOrSenderOrAmount, OrderBySenderOrAmount, OrderByTokenOrSenderOrAmount, OverdraftPolicy,
+    TokenAmount, TokenEdit, TokenID, TokenQueryModeStrict, TokenQueryModeWithCreation, TokenRight, TransactionDetails, TransactionID,
+    TransferLeg, UserID, UserQueryModeStrict, UserQueryModeWithCreation,
 };
-use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+use async_trait::async_trait;
+use chrono::TimeDelta;
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    Sqlite, SqliteConnection, SqlitePool,
+};
+use std::{collections::HashMap, str::FromStr};
+
+/// Migrations of the "migrations" directory, embedded at compile time
+static MIGRATOR: Migrator = sqlx::migrate!();
 
 #[derive(Debug)]
 pub struct DataSQLite
 {
     connection_pool: SqlitePool,
+    names:           NameNormalization,
+    key_retention:   TimeDelta,
 }
 
-const MAX_CONNECTIONS: u32 = 5;
-
 impl DataSQLite
 {
-    pub async fn new() -> DataSQLite
+    pub async fn new(config: &CoreConfig) -> Result<DataSQLite, Error>
     {
-        let pool = SqlitePoolOptions::new()
-            .max_connections(MAX_CONNECTIONS)
-            .connect("sqlite://var/data.db" /* TODO */)
-            .await;
+        let options = SqliteConnectOptions::from_str(&config.database_url)
+            .map_err(|err| Error::Config(format!("invalid SQLite URL \"{}\": {err}", config.database_url)))?
+            .create_if_missing(config.create_if_missing)
+            .busy_timeout(config.busy_timeout())
+            .journal_mode(match config.journal_mode
+            {
+                JournalMode::Delete => SqliteJournalMode::Delete,
+                JournalMode::Truncate => SqliteJournalMode::Truncate,
+                JournalMode::Persist => SqliteJournalMode::Persist,
+                JournalMode::Memory => SqliteJournalMode::Memory,
+                JournalMode::Wal => SqliteJournalMode::Wal,
+                JournalMode::Off => SqliteJournalMode::Off,
+            });
 
-        let connection_pool = match pool
-        {
-            Ok(pool) => pool,
-            Err(err) => panic!("SQLite error: {}", err),
+        let connection_pool = SqlitePoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect_with(options)
+            .await
+            .map_err(|err| Error::BackendUnavailable(Box::new(err)))?;
+
+        Self::with_pool(connection_pool, config).await
+    }
+
+    /// Fresh, fully migrated database, that only lives as long as this instance.
+    pub async fn new_in_memory() -> Result<DataSQLite, Error>
+    {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")?;
+
+        // Every connection to "sqlite::memory:" would open a separate database, and an in-memory database is gone
+        // as soon as its connection closes. So there is exactly one connection, which is never closed by the pool.
+        let connection_pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await
+            .map_err(|err| Error::BackendUnavailable(Box::new(err)))?;
+
+        Self::with_pool(connection_pool, &CoreConfig::default()).await
+    }
+
+    async fn with_pool(connection_pool: SqlitePool, config: &CoreConfig) -> Result<DataSQLite, Error>
+    {
+        sql_common::check_schema_version(&MIGRATOR, &connection_pool).await?;
+
+        let data = DataSQLite {
+            connection_pool,
+            names: config.names,
+            key_retention: config.idempotency_retention(),
         };
+        if config.run_migrations
+        {
+            data.migrate_up().await?;
+        }
+        Ok(data)
+    }
+
+    /// Fills in the missing name keys (of rows created before names were unique). Rows, whose key is already taken,
+    /// are left without one, until they get merged by [PersistanceLayer::dedupe].
+    async fn assign_missing_name_keys(&self) -> Result<(), Error>
+    {
+        let mut tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+
+        let users = sqlx::query_as!(User, r#"SELECT id AS "id!", name FROM User WHERE name_key IS NULL ORDER BY id"#)
+            .fetch_all(&mut *tx)
+            .await?;
+        for user in users
+        {
+            let key = self.names.key(&user.name);
+            sqlx::query!(
+                "UPDATE User SET name_key = ? WHERE id = ? AND NOT EXISTS (SELECT 1 FROM User WHERE name_key = ?)",
+                key,
+                user.id,
+                key
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let tokens = sqlx::query!(r#"SELECT id AS "id!", name FROM Token WHERE name_key IS NULL ORDER BY id"#)
+            .fetch_all(&mut *tx)
+            .await?;
+        for token in tokens
+        {
+            let key = self.names.key(&token.name);
+            sqlx::query!(
+                "UPDATE Token SET name_key = ? WHERE id = ? AND NOT EXISTS (SELECT 1 FROM Token WHERE name_key = ?)",
+                key,
+                token.id,
+                key
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
 
-        DataSQLite { connection_pool }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // ============================================ Transaction helpers ============================================
+    // These work on a plain connection, so they can be used inside of an already running SQL transaction.
+
+    async fn get_or_create_user_by_name(&self, conn: &mut SqliteConnection, name: &str, create_if_missing: bool) -> Result<UserID, Error>
+    {
+        let name = self.names.stored(name);
+        let key = self.names.key(name);
+        let id = sqlx::query_scalar!(r#"SELECT id AS "id!" FROM User WHERE name_key = ?"#, key)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+        match id
+        {
+            Some(id) => Ok(id),
+            None if create_if_missing => Ok(
+                sqlx::query_scalar!("INSERT INTO User(name, name_key) VALUES (?, ?) RETURNING id", name, key)
+                    .fetch_one(&mut *conn)
+                    .await?,
+            ),
+            None => Err(Error::UserNotFound(Identifier::Name(name.to_string()))),
+        }
+    }
+
+    /// A missing token gets created with the owner _owner_id_, if _create_if_missing_ is set
+    async fn get_or_create_token_by_name(
+        &self,
+        conn: &mut SqliteConnection,
+        name: &str,
+        create_if_missing: bool,
+        owner_id: Option<UserID>,
+    ) -> Result<TokenID, Error>
+    {
+        let name = self.names.stored(name);
+        let key = self.names.key(name);
+        let id = sqlx::query_scalar!(r#"SELECT id AS "id!" FROM Token WHERE name_key = ?"#, key)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+        match id
+        {
+            Some(id) => Ok(id),
+            None if create_if_missing => Ok(sqlx::query_scalar!(
+                "INSERT INTO Token(name, name_key, owner_id) VALUES (?, ?, ?) RETURNING id",
+                name,
+                key,
+                owner_id
+            )
+            .fetch_one(&mut *conn)
+            .await?),
+            None => Err(Error::TokenNotFound(Identifier::Name(name.to_string()))),
+        }
+    }
+
+    async fn verify_user_id(conn: &mut SqliteConnection, id: UserID) -> Result<UserID, Error>
+    {
+        sqlx::query_scalar!("SELECT id FROM User WHERE id = ?", id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .ok_or(Error::UserNotFound(Identifier::Id(id)))
+    }
+
+    async fn verify_token_id(conn: &mut SqliteConnection, id: TokenID) -> Result<TokenID, Error>
+    {
+        sqlx::query_scalar!("SELECT id FROM Token WHERE id = ?", id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .ok_or(Error::TokenNotFound(Identifier::Id(id)))
+    }
+
+    /// Adds the metadata to the tokens
+    async fn with_metadata(conn: &mut SqliteConnection, mut tokens: Vec<Token>) -> Result<Vec<Token>, Error>
+    {
+        let ids =
+            serde_json::to_string(&tokens.iter().map(|token| token.id).collect::<Vec<_>>()).map_err(|err| Error::Storage(Box::new(err)))?;
+        let rows = sqlx::query_as("SELECT token_id, key, value FROM token_metadata WHERE token_id IN (SELECT value FROM json_each(?))")
+            .bind(ids)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        sql_common::attach_metadata(&mut tokens, rows);
+        Ok(tokens)
+    }
+
+    async fn token(conn: &mut SqliteConnection, token_id: TokenID) -> Result<Token, Error>
+    {
+        let query = format!("SELECT {TOKEN_COLUMNS} FROM Token WHERE id = ?");
+        let token = sqlx::query_as(&query).bind(token_id).fetch_one(&mut *conn).await?;
+        Ok(Self::with_metadata(conn, vec![token]).await?.remove(0))
+    }
+
+    /// Owner of the token and whether the user is one of its minters
+    async fn rights(conn: &mut SqliteConnection, user_id: UserID, token_id: TokenID) -> Result<(Option<UserID>, bool), Error>
+    {
+        let token = sqlx::query!(
+            r#"SELECT owner_id, EXISTS (SELECT 1 FROM token_minter WHERE token_id = ?1 AND user_id = ?2) AS "is_minter!: bool"
+               FROM Token
+               WHERE id = ?1"#,
+            token_id,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        Ok((token.owner_id, token.is_minter))
+    }
+
+    /// Fails with [Error::NotAuthorized], unless the user has the right for the token
+    async fn check_right(conn: &mut SqliteConnection, right: TokenRight, user_id: UserID, token_id: TokenID) -> Result<(), Error>
+    {
+        let (owner_id, is_minter) = Self::rights(conn, user_id, token_id).await?;
+        right.check(user_id, token_id, owner_id, is_minter)
     }
 }
 
+/// Write transactions start with "BEGIN IMMEDIATE", which already keeps all other writers out until they end
+#[async_trait]
+impl SqlLedger for DataSQLite
+{
+    type Database = Sqlite;
+
+    fn names(&self) -> &NameNormalization
+    {
+        &self.names
+    }
+
+    async fn resolve_user(&self, conn: &mut SqliteConnection, user: UserQueryModeWithCreation<'_>) -> Result<UserID, Error>
+    {
+        match user
+        {
+            UserQueryModeWithCreation::ById(id) => Self::verify_user_id(conn, id).await,
+            UserQueryModeWithCreation::ByName(name) => self.get_or_create_user_by_name(conn, name, false).await,
+            UserQueryModeWithCreation::ByNameOrCreate(name) => self.get_or_create_user_by_name(conn, name, true).await,
+        }
+    }
+
+    async fn resolve_token(
+        &self,
+        conn: &mut SqliteConnection,
+        token: TokenQueryModeWithCreation<'_>,
+        owner_id: Option<UserID>,
+    ) -> Result<TokenID, Error>
+    {
+        match token
+        {
+            TokenQueryModeWithCreation::ById(id) => Self::verify_token_id(conn, id).await,
+            TokenQueryModeWithCreation::ByName(name) => self.get_or_create_token_by_name(conn, name, false, None).await,
+            TokenQueryModeWithCreation::ByNameOrCreate(name) => self.get_or_create_token_by_name(conn, name, true, owner_id).await,
+        }
+    }
+
+    async fn overdraft_policy(conn: &mut SqliteConnection, token_id: TokenID) -> Result<OverdraftPolicy, Error>
+    {
+        let credit_limit = sqlx::query_scalar!(
+            r#"SELECT credit_limit AS "credit_limit: TokenAmount" FROM Token WHERE id = ?"#,
+            token_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        Ok(OverdraftPolicy::from_credit_limit(credit_limit))
+    }
+
+    async fn has_right(conn: &mut SqliteConnection, right: TokenRight, user_id: UserID, token_id: TokenID) -> Result<bool, Error>
+    {
+        let (owner_id, is_minter) = Self::rights(conn, user_id, token_id).await?;
+        Ok(right.allows(user_id, owner_id, is_minter))
+    }
+
+    async fn balance(conn: &mut SqliteConnection, user_id: UserID, token_id: TokenID) -> Result<TokenAmount, Error>
+    {
+        let totals = sqlx::query!(
+            "SELECT receiver_id, sender_id, current_total FROM user_balance WHERE token_id = ?2 AND (receiver_id = ?1 OR sender_id = ?1)",
+            user_id,
+            token_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        sql_common::balance(
+            user_id,
+            totals
+                .into_iter()
+                .map(|total| (total.receiver_id, total.sender_id, total.current_total)),
+        )
+    }
+
+    async fn current_total(
+        conn: &mut SqliteConnection,
+        (sender_id, receiver_id, token_id): Participants,
+    ) -> Result<Option<TokenAmount>, Error>
+    {
+        sqlx::query_scalar!(
+            r#"SELECT current_total AS "current_total: TokenAmount"
+               FROM user_balance
+               WHERE sender_id = ? AND receiver_id = ? AND token_id = ?"#,
+            sender_id,
+            receiver_id,
+            token_id
+        )
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn lock_debited(_conn: &mut SqliteConnection, _user_id: UserID) -> Result<(), Error>
+    {
+        Ok(())
+    }
+
+    async fn insert_history(
+        conn: &mut SqliteConnection,
+        (sender_id, receiver_id, token_id): Participants,
+        amount: TokenAmount,
+        note: TransactionNote<'_>,
+    ) -> Result<TransactionID, Error>
+    {
+        let created_at = note.created_at.map(sql_common::sqlite_time);
+        Ok(sqlx::query_scalar!(
+            r#"INSERT INTO transaction_history(sender_id, receiver_id, token_id, amount, memo, reference, actor_id, reverses_id, created_at)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?, COALESCE(?, strftime('%Y-%m-%dT%H:%M:%fZ', 'now')))
+               RETURNING id AS "id!""#,
+            sender_id,
+            receiver_id,
+            token_id,
+            amount,
+            note.memo,
+            note.reference,
+            note.actor_id,
+            note.reverses_id,
+            created_at
+        )
+        .fetch_one(&mut *conn)
+        .await?)
+    }
+
+    async fn keyed_transaction(&self, conn: &mut SqliteConnection, key: &str) -> Result<Option<KeyedTransaction>, Error>
+    {
+        let cutoff = sql_common::sqlite_time(sql_common::idempotency_cutoff(self.key_retention));
+        sqlx::query!("DELETE FROM transaction_idempotency WHERE created_at < ?", cutoff)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(sqlx::query_as!(
+            KeyedTransaction,
+            "SELECT history.sender_id, history.receiver_id, history.token_id, history.amount, keyed.new_total
+             FROM transaction_idempotency AS keyed
+             JOIN transaction_history AS history ON history.id = keyed.transaction_id
+             WHERE keyed.idempotency_key = ?",
+            key
+        )
+        .fetch_optional(&mut *conn)
+        .await?)
+    }
+
+    async fn insert_idempotency_key(
+        conn: &mut SqliteConnection,
+        key: &str,
+        transaction_id: TransactionID,
+        new_total: TokenAmount,
+    ) -> Result<(), Error>
+    {
+        sqlx::query!(
+            "INSERT INTO transaction_idempotency(idempotency_key, transaction_id, new_total) VALUES (?, ?, ?)",
+            key,
+            transaction_id,
+            new_total
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    async fn row_counts(conn: &mut SqliteConnection) -> Result<RowCounts, Error>
+    {
+        Ok(sqlx::query_as(ROW_COUNTS_QUERY).fetch_one(&mut *conn).await?)
+    }
+
+    async fn all_users(conn: &mut SqliteConnection) -> Result<Vec<User>, Error>
+    {
+        Ok(sqlx::query_as!(User, "SELECT id, name FROM User").fetch_all(&mut *conn).await?)
+    }
+
+    async fn all_tokens(conn: &mut SqliteConnection) -> Result<Vec<Token>, Error>
+    {
+        Ok(sqlx::query_as(&format!("SELECT {TOKEN_COLUMNS} FROM Token"))
+            .fetch_all(&mut *conn)
+            .await?)
+    }
+
+    async fn merge_user(conn: &mut SqliteConnection, kept_id: UserID, merged_id: UserID) -> Result<(), Error>
+    {
+        sqlx::query!(
+            "UPDATE transaction_history SET sender_id = ? WHERE sender_id = ?",
+            kept_id,
+            merged_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!(
+            "UPDATE transaction_history SET receiver_id = ? WHERE receiver_id = ?",
+            kept_id,
+            merged_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!(
+            "UPDATE transaction_history SET actor_id = NULLIF(?1, sender_id) WHERE actor_id = ?2",
+            kept_id,
+            merged_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!("UPDATE Token SET owner_id = ? WHERE owner_id = ?", kept_id, merged_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO token_minter(token_id, user_id) SELECT token_id, ? FROM token_minter WHERE user_id = ?",
+            kept_id,
+            merged_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!("DELETE FROM token_minter WHERE user_id = ?", merged_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!("DELETE FROM User WHERE id = ?", merged_id).execute(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn merge_token(conn: &mut SqliteConnection, kept_id: TokenID, merged_id: TokenID) -> Result<(), Error>
+    {
+        sqlx::query!("UPDATE transaction_history SET token_id = ? WHERE token_id = ?", kept_id, merged_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO token_minter(token_id, user_id) SELECT ?, user_id FROM token_minter WHERE token_id = ?",
+            kept_id,
+            merged_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!("DELETE FROM token_minter WHERE token_id = ?", merged_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO token_metadata(token_id, key, value) SELECT ?, key, value FROM token_metadata WHERE token_id = ?",
+            kept_id,
+            merged_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!("DELETE FROM token_metadata WHERE token_id = ?", merged_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!("DELETE FROM Token WHERE id = ?", merged_id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_balances(conn: &mut SqliteConnection) -> Result<(), Error>
+    {
+        sqlx::query!("DELETE FROM user_balance").execute(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn rebuild_balances(conn: &mut SqliteConnection) -> Result<(), Error>
+    {
+        sqlx::query!(
+            "INSERT INTO user_balance(sender_id, receiver_id, token_id, current_total)
+             SELECT sender_id, receiver_id, token_id, SUM(amount)
+             FROM transaction_history
+             GROUP BY sender_id, receiver_id, token_id"
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    async fn clear_name_keys(conn: &mut SqliteConnection) -> Result<(), Error>
+    {
+        sqlx::query!("UPDATE User SET name_key = NULL").execute(&mut *conn).await?;
+        sqlx::query!("UPDATE Token SET name_key = NULL").execute(&mut *conn).await?;
+        Ok(())
+    }
+
+    async fn set_user_name_key(conn: &mut SqliteConnection, user_id: UserID, key: &str) -> Result<(), Error>
+    {
+        sqlx::query!("UPDATE User SET name_key = ? WHERE id = ?", key, user_id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_token_name_key(conn: &mut SqliteConnection, token_id: TokenID, key: &str) -> Result<(), Error>
+    {
+        sqlx::query!("UPDATE Token SET name_key = ? WHERE id = ?", key, token_id)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
 impl PersistanceLayer for DataSQLite
 {
-    async fn create_user(&self, name: &str) -> Result<User, sqlx::Error>
+    async fn migration_status(&self) -> Result<Vec<MigrationInfo>, Error>
     {
-        sqlx::query_as!(User, "INSERT INTO User(name) VALUES (?) RETURNING *", name)
-            .fetch_one(&self.connection_pool)
-            .await
+        sql_common::migration_status(&MIGRATOR, &self.connection_pool).await
     }
 
-    async fn get_all_users(&self) -> Result<Vec<User>, sqlx::Error>
+    async fn migrate_up(&self) -> Result<(), Error>
     {
-        // SELECT * FROM user
-        Ok(vec![User {
-            id:   974,
-            name: "John Doe".to_string(),
-        }])
+        sql_common::migrate_up(&MIGRATOR, &self.connection_pool).await?;
+        self.assign_missing_name_keys().await
     }
 
-    async fn query_user(&self, _name: &str) -> Result<Vec<User>, sqlx::Error>
+    async fn migrate_down(&self) -> Result<Option<MigrationInfo>, Error>
     {
-        // SELECT * FROM user WHERE name LIKE '%:name%'
-        Ok(vec![User {
-            id:   974,
-            name: "John Doe".to_string(),
-        }])
+        sql_common::migrate_down(&MIGRATOR, &self.connection_pool).await
     }
 
-    async fn create_token(&self, name: &str) -> Result<Token, sqlx::Error>
+    async fn dedupe(&self, dry_run: bool) -> Result<DedupeReport, Error>
     {
-        sqlx::query_as!(Token, "INSERT INTO Token(name) VALUES (?) RETURNING *", name)
+        let tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+        self.merge_duplicates(tx, dry_run).await
+    }
+
+    async fn create_user(&self, name: &str) -> Result<User, Error>
+    {
+        let name = self.names.stored(name);
+        let key = self.names.key(name);
+        sqlx::query_as!(User, "INSERT INTO User(name, name_key) VALUES (?, ?) RETURNING id, name", name, key)
             .fetch_one(&self.connection_pool)
             .await
+            .map_err(|err| sql_common::duplicate_name_error(err, name))
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>, Error>
+    {
+        sqlx::query_as!(User, "SELECT id, name FROM User ORDER BY id")
+            .fetch_all(&self.connection_pool)
+            .await
+            .map_err(Error::from)
+    }
+
+    async fn query_user(&self, name: &str, matching: NameMatch) -> Result<Vec<User>, Error>
+    {
+        let query = format!(
+            "SELECT id, name FROM User WHERE {} ORDER BY id",
+            sql_common::name_condition(matching, "?")
+        );
+        Ok(sqlx::query_as(&query)
+            .bind(sql_common::name_parameter(name, matching, &self.names))
+            .fetch_all(&self.connection_pool)
+            .await?)
+    }
+
+    async fn create_token(&self, name: &str, owner: Option<UserQueryModeStrict<'_>>) -> Result<Token, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let owner_id = match owner
+        {
+            Some(owner) => Some(self.resolve_user(&mut conn, owner.into()).await?),
+            None => None,
+        };
+
+        let name = self.names.stored(name);
+        let key = self.names.key(name);
+        let id = sqlx::query_scalar!(
+            "INSERT INTO Token(name, name_key, owner_id) VALUES (?, ?, ?) RETURNING id",
+            name,
+            key,
+            owner_id
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|err| sql_common::duplicate_name_error(err, name))?;
+
+        Self::token(&mut conn, id).await
+    }
+
+    async fn get_all_tokens(&self) -> Result<Vec<Token>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let query = format!("SELECT {TOKEN_COLUMNS} FROM Token ORDER BY id");
+        let tokens = sqlx::query_as(&query).fetch_all(&mut *conn).await?;
+
+        Self::with_metadata(&mut conn, tokens).await
+    }
+
+    async fn query_token(&self, name: &str, matching: NameMatch) -> Result<Vec<Token>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let query = format!(
+            "SELECT {TOKEN_COLUMNS} FROM Token WHERE {} ORDER BY id",
+            sql_common::name_condition(matching, "?")
+        );
+        let tokens = sqlx::query_as(&query)
+            .bind(sql_common::name_parameter(name, matching, &self.names))
+            .fetch_all(&mut *conn)
+            .await?;
+
+        Self::with_metadata(&mut conn, tokens).await
+    }
+
+    async fn get_token(&self, token: TokenQueryModeStrict<'_>) -> Result<Token, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        Self::token(&mut conn, token_id).await
+    }
+
+    async fn edit_token(&self, actor: UserQueryModeStrict<'_>, token: TokenQueryModeStrict<'_>, edit: &TokenEdit) -> Result<Token, Error>
+    {
+        let mut tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+        let actor_id = self.resolve_user(&mut tx, actor.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+
+        Self::check_right(&mut tx, TokenRight::Edit, actor_id, token_id).await?;
+        let mut token = Self::token(&mut tx, token_id).await?;
+        if edit.changes_decimals(&token)
+            && sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM transaction_history WHERE token_id = ?) AS "in_use!: bool""#,
+                token_id
+            )
+            .fetch_one(&mut *tx)
+            .await?
+        {
+            return Err(Error::DecimalsInUse(Identifier::Id(token_id)));
+        }
+        edit.apply(&mut token);
+
+        sqlx::query!(
+            "UPDATE Token SET description = ?, symbol = ?, decimals = ? WHERE id = ?",
+            token.description,
+            token.symbol,
+            token.decimals,
+            token_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        for (key, value) in &edit.metadata
+        {
+            match value
+            {
+                Some(value) =>
+                {
+                    sqlx::query!(
+                        "INSERT INTO token_metadata(token_id, key, value) VALUES (?, ?, ?)
+                     ON CONFLICT (token_id, key) DO UPDATE SET value = excluded.value",
+                        token_id,
+                        key,
+                        value
+                    )
+                    .execute(&mut *tx)
+                    .await?
+                }
+                None =>
+                {
+                    sqlx::query!("DELETE FROM token_metadata WHERE token_id = ? AND key = ?", token_id, key)
+                        .execute(&mut *tx)
+                        .await?
+                }
+            };
+        }
+
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    async fn set_token_owner(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        owner: UserQueryModeStrict<'_>,
+    ) -> Result<(), Error>
+    {
+        let mut tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+        let actor_id = self.resolve_user(&mut tx, actor.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+        let owner_id = self.resolve_user(&mut tx, owner.into()).await?;
+
+        Self::check_right(&mut tx, TokenRight::Transfer, actor_id, token_id).await?;
+        sqlx::query!("UPDATE Token SET owner_id = ? WHERE id = ?", owner_id, token_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_minter(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        user: UserQueryModeStrict<'_>,
+        allowed: bool,
+    ) -> Result<(), Error>
+    {
+        let mut tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+        let actor_id = self.resolve_user(&mut tx, actor.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+        let user_id = self.resolve_user(&mut tx, user.into()).await?;
+
+        Self::check_right(&mut tx, TokenRight::Delegate, actor_id, token_id).await?;
+        match allowed
+        {
+            true =>
+            {
+                sqlx::query!(
+                    "INSERT OR IGNORE INTO token_minter(token_id, user_id) VALUES (?, ?)",
+                    token_id,
+                    user_id
+                )
+                .execute(&mut *tx)
+                .await?
+            }
+            false =>
+            {
+                sqlx::query!("DELETE FROM token_minter WHERE token_id = ? AND user_id = ?", token_id, user_id)
+                    .execute(&mut *tx)
+                    .await?
+            }
+        };
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_minters(&self, token: TokenQueryModeStrict<'_>) -> Result<Vec<User>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        sqlx::query_as!(
+            User,
+            r#"SELECT User.id AS "id!", User.name
+               FROM token_minter
+               JOIN User ON User.id = token_minter.user_id
+               WHERE token_minter.token_id = ?
+               ORDER BY User.id"#,
+            token_id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(Error::from)
     }
 
-    async fn get_all_tokens(&self) -> Result<Vec<Token>, sqlx::Error>
+    async fn issue(
+        &self,
+        issuer: UserQueryModeStrict<'_>,
+        holder: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        amount: TokenAmount,
+    ) -> Result<TokenAmount, Error>
+    {
+        let mut tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+        let issuer_id = self.resolve_user(&mut tx, issuer.into()).await?;
+        let holder_id = self.resolve_user(&mut tx, holder.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+
+        sql_common::check_issue(issuer_id, holder_id)?;
+        Self::check_right(&mut tx, TokenRight::Issue, issuer_id, token_id).await?;
+        // Minting debits the issuer only, who isn't subject to the policy
+        let policy = match amount > 0
+        {
+            true => OverdraftPolicy::Free,
+            false => Self::overdraft_policy(&mut tx, token_id).await?,
+        };
+        Self::insert_transaction(
+            &mut tx,
+            (issuer_id, holder_id, token_id),
+            amount,
+            policy,
+            TransactionNote::default(),
+        )
+        .await?;
+        let balance = Self::balance(&mut tx, holder_id, token_id).await?;
+
+        tx.commit().await?;
+        Ok(balance)
+    }
+
+    async fn get_overdraft_policy(&self, token: TokenQueryModeStrict<'_>) -> Result<OverdraftPolicy, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        Self::overdraft_policy(&mut conn, token_id).await
+    }
+
+    async fn set_overdraft_policy(
+        &self,
+        actor: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        policy: OverdraftPolicy,
+    ) -> Result<(), Error>
     {
-        // SELECT * FROM token
-        Ok(vec![Token {
-            id:   863,
-            name: "my-token".to_string(),
-        }])
+        let mut tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+        let actor_id = self.resolve_user(&mut tx, actor.into()).await?;
+        let token_id = self.resolve_token(&mut tx, token.into(), None).await?;
+
+        Self::check_right(&mut tx, TokenRight::Edit, actor_id, token_id).await?;
+        let credit_limit = policy.credit_limit();
+        sqlx::query!("UPDATE Token SET credit_limit = ? WHERE id = ?", credit_limit, token_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
     }
 
-    async fn query_token(&self, _name: &str) -> Result<Vec<Token>, sqlx::Error>
+    async fn get_balance(&self, user: UserQueryModeStrict<'_>, token: TokenQueryModeStrict<'_>) -> Result<TokenAmount, Error>
     {
-        // SELECT * FROM token WHERE name LIKE '%:name%'
-        Ok(vec![Token {
-            id:   863,
-            name: "my-token".to_string(),
-        }])
+        let mut conn = self.connection_pool.acquire().await?;
+        let user_id = self.resolve_user(&mut conn, user.into()).await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        Self::balance(&mut conn, user_id, token_id).await
     }
 
     /// A return value of _None_ means, there are no transactions present.
     /// _Some(0)_ means, all found transactions sum up to zero.
     async fn get_current_total(
         &self,
-        _sender: UserQueryModeStrict<'_>,
-        _receiver: UserQueryModeStrict<'_>,
-        _token: TokenQueryModeStrict<'_>,
-    ) -> Result<Option<TokenAmount>, sqlx::Error>
+        sender: UserQueryModeStrict<'_>,
+        receiver: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+    ) -> Result<Option<TokenAmount>, Error>
     {
-        // SELECT current_total FROM user_balance WHERE sender_id = :sender_id, receiver_id = :receiver_id, token_id = :token_id
-        Ok(Some(1337))
+        let mut conn = self.connection_pool.acquire().await?;
+        let sender_id = self.resolve_user(&mut conn, sender.into()).await?;
+        let receiver_id = self.resolve_user(&mut conn, receiver.into()).await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        Self::current_total(&mut conn, (sender_id, receiver_id, token_id)).await
     }
 
     async fn transaction(
         &self,
-        _sender: UserQueryModeStrict<'_>,
-        _receiver: UserQueryModeStrict<'_>,
-        _token: TokenQueryModeStrict<'_>,
-        _amount: TokenAmount,
-    ) -> Result<TokenAmount, sqlx::Error>
+        sender: UserQueryModeWithCreation<'_>,
+        receiver: UserQueryModeWithCreation<'_>,
+        token: TokenQueryModeWithCreation<'_>,
+        amount: TokenAmount,
+        details: &TransactionDetails<'_>,
+    ) -> Result<TokenAmount, Error>
+    {
+        // "BEGIN IMMEDIATE" takes the write lock right away. A deferred transaction would only take a read lock for the
+        // lookups and could fail with SQLITE_BUSY, once a concurrent transaction has written in the meantime.
+        let mut tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+        let leg = TransferLeg {
+            sender,
+            receiver,
+            token,
+            amount,
+            details: details.clone(),
+        };
+        let new_total = self.transfer(&mut tx, &leg, None).await?;
+        tx.commit().await?;
+
+        Ok(new_total)
+    }
+
+    async fn batch_transaction(&self, legs: &[TransferLeg<'_>]) -> Result<Vec<TokenAmount>, Error>
+    {
+        let mut tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+
+        // Every leg sees the balances after the previous ones, and any failure rolls back all of them
+        let mut totals = Vec::with_capacity(legs.len());
+        for leg in legs
+        {
+            totals.push(self.transfer(&mut tx, leg, None).await?);
+        }
+        tx.commit().await?;
+
+        Ok(totals)
+    }
+
+    async fn import(&self, records: &[ImportRecord<'_>], dry_run: bool) -> Result<ImportReport, Error>
+    {
+        let tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+        self.import_records(tx, records, dry_run).await
+    }
+
+    async fn reverse_transaction(&self, id: TransactionID, reason: &str) -> Result<HistoryEntry, Error>
+    {
+        let mut tx = self.connection_pool.begin_with("BEGIN IMMEDIATE").await?;
+
+        let target: Option<ReversalTarget> = sqlx::query_as(&format!("{REVERSAL_TARGET_QUERY} WHERE history.id = ?"))
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let (target, amount) = sql_common::check_reversal(id, target)?;
+
+        let policy = Self::overdraft_policy(&mut tx, target.token_id).await?;
+        let participants = (target.sender_id, target.receiver_id, target.token_id);
+        Self::insert_transaction(&mut tx, participants, amount, policy, target.note(id, reason)).await?;
+        let reversal = sqlx::query_as(&format!("{HISTORY_SELECT} WHERE history.reverses_id = ?"))
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(reversal)
+    }
+
+    async fn query_history(
+        &self,
+        filter: &HistoryFilter<'_>,
+        order: Order,
+        after: Option<HistoryCursor>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, Error>
     {
-        // INSERT INTO transaction_history(sender_id, receiver_id, token_id, amount) VALUES(:sender_id, :receiver_id, :token_id, :amount)
-        Ok(420)
+        let mut conn = self.connection_pool.acquire().await?;
+        let mut ids = HistoryIds::default();
+        if let Some(sender) = filter.sender
+        {
+            ids.sender_id = Some(self.resolve_user(&mut conn, sender.into()).await?);
+        }
+        if let Some(receiver) = filter.receiver
+        {
+            ids.receiver_id = Some(self.resolve_user(&mut conn, receiver.into()).await?);
+        }
+        if let Some(token) = filter.token
+        {
+            ids.token_id = Some(self.resolve_token(&mut conn, token.into(), None).await?);
+        }
+
+        sql_common::history_query(ids, filter, order, after, limit, sql_common::sqlite_time)
+            .build_query_as()
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(Error::from)
     }
 
     async fn list_user_token(
         &self,
-        _receiver: UserQueryModeStrict<'_>,
-        _token: TokenQueryModeStrict<'_>,
-        _order: Order,
-        _order_by: Option<OrderBySenderOrAmount>,
-    ) -> Result<Vec<RelativeUserTokenAmountEntry>, sqlx::Error>
-    {
-        // SELECT sender.*, balance.mount
-        // FROM user_balance AS balance
-        // JOIN user AS sender ON sender.id = balance.sender_id
-        // WHERE receiver_id = :receiver_id, token_id = :token_id
-        // ORDER BY :order_by :order
-        Ok(vec![RelativeUserTokenAmountEntry {
-            sender: User {
-                id:   1,
-                name: "Jane Doe".to_string(),
-            },
-            amount: 4321,
-        }])
+        receiver: UserQueryModeStrict<'_>,
+        token: TokenQueryModeStrict<'_>,
+        order: Order,
+        order_by: Option<OrderBySenderOrAmount>,
+    ) -> Result<Vec<RelativeUserTokenAmountEntry>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let receiver_id = self.resolve_user(&mut conn, receiver.into()).await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        let order_by = sql_common::order_by_sender_or_amount(order, order_by);
+        let query = format!(
+            "SELECT token.id AS group_id, token.name AS group_name, sender.id AS sender_id, sender.name AS sender_name,
+                    balance.current_total AS amount, {REVERSED_COLUMN}
+             FROM user_balance AS balance
+             JOIN User AS sender ON sender.id = balance.sender_id
+             JOIN Token AS token ON token.id = balance.token_id
+             WHERE balance.receiver_id = ? AND balance.token_id = ?
+             ORDER BY {order_by}"
+        );
+
+        let rows: Vec<BalanceRow> = sqlx::query_as(&query)
+            .bind(receiver_id)
+            .bind(token_id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| row.entry()).collect())
     }
 
     async fn list_tokens_by_user(
         &self,
-        _receiver: UserQueryModeStrict<'_>,
-        _order: Order,
-        _order_by: Option<OrderByTokenOrSenderOrAmount>,
-    ) -> Result<Vec<RelativeTokenAmountEntry>, sqlx::Error>
-    {
-        // SELECT sender.*, token.*, balance.amount
-        // FROM user_balance AS balance
-        // JOIN user AS sender ON sender.id = balance.sender_id
-        // WHERE receiver_id = :receiver_id
-        // ORDER BY :order_by :order
-        Ok(vec![RelativeTokenAmountEntry {
-            token:            Token {
-                id:   2,
-                name: "yet-another-token".to_string(),
-            },
-            amount_by_sender: vec![RelativeUserTokenAmountEntry {
-                sender: User {
-                    id:   1,
-                    name: "Jane Doe".to_string(),
-                },
-                amount: 4321,
-            }],
-        }])
+        receiver: UserQueryModeStrict<'_>,
+        order: Order,
+        order_by: Option<OrderByTokenOrSenderOrAmount>,
+    ) -> Result<Vec<RelativeTokenAmountEntry>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let receiver_id = self.resolve_user(&mut conn, receiver.into()).await?;
+
+        let order_by = sql_common::order_by_token_or_sender_or_amount(order, order_by);
+        let query = format!(
+            "SELECT token.id AS group_id, token.name AS group_name, sender.id AS sender_id, sender.name AS sender_name,
+                    balance.current_total AS amount, {REVERSED_COLUMN}
+             FROM user_balance AS balance
+             JOIN User AS sender ON sender.id = balance.sender_id
+             JOIN Token AS token ON token.id = balance.token_id
+             WHERE balance.receiver_id = ?
+             ORDER BY {order_by}"
+        );
+
+        let rows: Vec<BalanceRow> = sqlx::query_as(&query).bind(receiver_id).fetch_all(&mut *conn).await?;
+
+        let query = format!("SELECT {TOKEN_COLUMNS} FROM Token WHERE id IN (SELECT token_id FROM user_balance WHERE receiver_id = ?)");
+        let tokens = sqlx::query_as(&query).bind(receiver_id).fetch_all(&mut *conn).await?;
+        let mut tokens: HashMap<TokenID, Token> = Self::with_metadata(&mut conn, tokens)
+            .await?
+            .into_iter()
+            .map(|token| (token.id, token))
+            .collect();
+
+        Ok(group_balance_rows(rows)
+            .into_iter()
+            .filter_map(|(first, amount_by_sender)| {
+                Some(RelativeTokenAmountEntry {
+                    token: tokens.remove(&first.group_id)?,
+                    amount_by_sender,
+                })
+            })
+            .collect())
     }
 
     async fn list_users_by_token(
         &self,
-        _token: TokenQueryModeStrict<'_>,
-        _order: Order,
-        _order_by: Option<OrderByReceiverOrSenderOrAmount>,
-    ) -> Result<Vec<RelativeUserAmountEntry>, sqlx::Error>
-    {
-        // SELECT sender.*, receiver.*, balance.amount
-        // FROM user_balance AS balance
-        // JOIN user AS sender ON sender.id = balance.sender_id
-        // JOIN user AS receiver ON receiver.id = balance.receiver_id
-        // WHERE balance.token_id = :token_id
-        // ORDER BY :order_by :order
-        Ok(vec![RelativeUserAmountEntry {
-            receiver:         User {
-                id:   5,
-                name: "John doe".to_string(),
-            },
-            amount_by_sender: vec![RelativeUserTokenAmountEntry {
-                sender: User {
-                    id:   1,
-                    name: "Max Mustermann".to_string(),
+        token: TokenQueryModeStrict<'_>,
+        order: Order,
+        order_by: Option<OrderByReceiverOrSenderOrAmount>,
+    ) -> Result<Vec<RelativeUserAmountEntry>, Error>
+    {
+        let mut conn = self.connection_pool.acquire().await?;
+        let token_id = self.resolve_token(&mut conn, token.into(), None).await?;
+
+        let order_by = sql_common::order_by_receiver_or_sender_or_amount(order, order_by);
+        let query = format!(
+            "SELECT receiver.id AS group_id, receiver.name AS group_name, sender.id AS sender_id, sender.name AS sender_name,
+                    balance.current_total AS amount, {REVERSED_COLUMN}
+             FROM user_balance AS balance
+             JOIN User AS sender ON sender.id = balance.sender_id
+             JOIN User AS receiver ON receiver.id = balance.receiver_id
+             WHERE balance.token_id = ?
+             ORDER BY {order_by}"
+        );
+
+        let rows: Vec<BalanceRow> = sqlx::query_as(&query).bind(token_id).fetch_all(&mut *conn).await?;
+
+        Ok(group_balance_rows(rows)
+            .into_iter()
+            .map(|(first, amount_by_sender)| RelativeUserAmountEntry {
+                receiver: User {
+                    id:   first.group_id,
+                    name: first.group_name,
                 },
-                amount: 4321,
-            }],
-        }])
+                amount_by_sender,
+            })
+            .collect())
     }
 }
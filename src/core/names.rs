@@ -0,0 +1,125 @@
+use super::persistance_layer::DbPk;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
+
+/// Rules, that decide whether two user (or token) names are considered the same.
+/// Names are unique with respect to these rules.
+///
+/// After changing them for an existing database, run the "dedupe" command, which merges users and tokens whose names
+/// became equal and updates the stored name keys.
+#[derive(Copy, Clone, PartialEq, Eq, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct NameNormalization
+{
+    /// "Alice" and "alice" are the same name
+    pub case_insensitive: bool,
+    /// Unicode compatibility normalization (NFKC), e.g. "ｂｏｂ" and "bob" are the same name
+    pub unicode_nfkc:     bool,
+    /// Leading and trailing whitespace is ignored
+    pub trim:             bool,
+}
+
+impl Default for NameNormalization
+{
+    fn default() -> Self
+    {
+        NameNormalization {
+            case_insensitive: true,
+            unicode_nfkc:     true,
+            trim:             true,
+        }
+    }
+}
+
+impl NameNormalization
+{
+    /// The name as it is stored: without leading and trailing whitespace, if that is ignored
+    pub fn stored<'a>(&self, name: &'a str) -> &'a str
+    {
+        if self.trim
+        {
+            name.trim()
+        }
+        else
+        {
+            name
+        }
+    }
+
+    /// Key, that has to be unique among all user (or token) names
+    pub fn key(&self, name: &str) -> String
+    {
+        let name = self.stored(name);
+        let name: String = if self.unicode_nfkc
+        {
+            name.nfkc().collect()
+        }
+        else
+        {
+            name.to_string()
+        };
+        if self.case_insensitive
+        {
+            name.to_lowercase()
+        }
+        else
+        {
+            name
+        }
+    }
+}
+
+/// Users (or tokens) with the same name, that have been merged into the one with the lowest ID
+#[derive(Debug, Serialize)]
+pub struct MergedNames<T>
+{
+    pub kept:   T,
+    pub merged: Vec<T>,
+}
+
+/// Result of [Core::dedupe](super::Core::dedupe)
+#[derive(Debug, Serialize)]
+pub struct DedupeReport
+{
+    pub users:  Vec<MergedNames<super::persistance_layer::User>>,
+    pub tokens: Vec<MergedNames<super::persistance_layer::Token>>,
+}
+
+/// Groups rows by the normalized name. Only groups with more than one row are returned, each one ordered by ID.
+pub(super) fn duplicates<T>(
+    mut rows: Vec<T>,
+    normalization: &NameNormalization,
+    id_and_name: impl Fn(&T) -> (DbPk, &str),
+) -> Vec<MergedNames<T>>
+{
+    rows.sort_by_key(|row| id_and_name(row).0);
+
+    let mut groups: Vec<Vec<T>> = Vec::new();
+    let mut index_by_key: HashMap<String, usize> = HashMap::new();
+    for row in rows
+    {
+        let key = normalization.key(id_and_name(&row).1);
+        match index_by_key.get(&key)
+        {
+            Some(&index) => groups[index].push(row),
+            None =>
+            {
+                index_by_key.insert(key, groups.len());
+                groups.push(vec![row]);
+            }
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            let merged = group.split_off(1);
+            MergedNames {
+                kept: group.remove(0),
+                merged,
+            }
+        })
+        .collect()
+}
@@ -1,73 +1,237 @@
 use super::*;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
 
 /// SQLite requires i64 according to SQLX type mapping.
 /// Other persistance layer implementations might need to accommodate this.
 pub type DbPk = i64;
 
-// WORKAROUND {AsyncPersistanceLayerTraitObject}:
-//      Since async in trait objects is not a thing yet in rust, this enum will wrap all implementations for now
-#[derive(Debug)]
-pub enum DbImplementors
-{
-    SQLite(DataSQLite),
-}
-
-#[derive(Debug)]
+#[derive(Debug, sqlx::FromRow, serde::Serialize)]
 pub struct User
 {
     pub id:   DbPk,
     pub name: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, sqlx::FromRow, serde::Serialize)]
 pub struct Token
 {
-    pub id:   DbPk,
-    pub name: String,
+    pub id:          DbPk,
+    pub name:        String,
+    /// Issuer of the token, who may mint and burn it (_None_, if nobody has claimed the token yet)
+    pub owner_id:    Option<UserID>,
+    pub owner_name:  Option<String>,
+    pub description: Option<String>,
+    /// Short symbol, that follows amounts, e.g. "KDS" or "⭐"
+    pub symbol:      Option<String>,
+    /// Amounts are stored in the smallest unit and displayed with this many decimal places
+    #[sqlx(try_from = "i32")]
+    pub decimals:    u8,
+    /// Arbitrary key/value pairs (stored separately, so they aren't part of the token row)
+    #[sqlx(skip)]
+    pub metadata:    BTreeMap<String, String>,
 }
 
-#[derive(Debug)]
+impl Token
+{
+    /// Amount in the display format of the token, e.g. "12.50 KDS" for 1250 with 2 decimals
+    pub fn format_amount(&self, amount: TokenAmount) -> String
+    {
+        let mut text = DecimalAmount {
+            digits: amount,
+            scale:  self.decimals,
+        }
+        .to_string();
+        if let Some(symbol) = &self.symbol
+        {
+            text.push(' ');
+            text.push_str(symbol);
+        }
+        text
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
 pub struct RelativeUserAmountEntry
 {
     pub receiver:         User,
     pub amount_by_sender: Vec<RelativeUserTokenAmountEntry>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde::Serialize)]
 pub struct RelativeTokenAmountEntry
 {
     pub token:            Token,
     pub amount_by_sender: Vec<RelativeUserTokenAmountEntry>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde::Serialize)]
 pub struct RelativeUserTokenAmountEntry
 {
-    pub sender: User,
-    pub amount: TokenAmount,
+    pub sender:   User,
+    pub amount:   TokenAmount,
+    /// Sum of the reversed transactions of the relation (already compensated in _amount_)
+    pub reversed: TokenAmount,
+}
+
+/// One transaction of the ledger
+#[derive(Clone, Debug, sqlx::FromRow, serde::Serialize)]
+pub struct HistoryEntry
+{
+    pub id:             DbPk,
+    /// UTC time of the transaction (_None_ for transactions of JSON-lines files, that were written before it was kept)
+    pub created_at:     Option<DateTime<Utc>>,
+    pub sender_id:      UserID,
+    pub sender_name:    String,
+    pub receiver_id:    UserID,
+    pub receiver_name:  String,
+    pub token_id:       TokenID,
+    pub token_name:     String,
+    pub amount:         TokenAmount,
+    pub memo:           Option<String>,
+    pub reference:      Option<String>,
+    /// User, who recorded the transaction on behalf of the sender
+    pub actor_id:       Option<UserID>,
+    pub actor_name:     Option<String>,
+    /// The transaction compensated by this one (see [Core::reverse_transaction])
+    pub reverses_id:    Option<TransactionID>,
+    /// The transaction, that compensates this one
+    pub reversed_by_id: Option<TransactionID>,
 }
 
-pub trait PersistanceLayer
+#[derive(Debug, serde::Serialize)]
+pub struct MigrationInfo
 {
-    // INSERT INTO user(name) VALUES(:name)
+    pub version:     i64,
+    pub description: String,
+    pub applied:     bool,
+}
+
+/// Storage backend of [Core]. The trait is object safe, so [Core] can hold any implementation as a trait object
+/// (see [Core::with_persistance_layer]). Implementations should use `#[async_trait]` as well.
+#[async_trait]
+pub trait PersistanceLayer: Send + Sync
+{
+    // All migrations known to this program and whether they have been applied to the database
+    async fn migration_status(&self) -> Result<Vec<MigrationInfo>, Error>;
+
+    // Apply all pending migrations
+    async fn migrate_up(&self) -> Result<(), Error>;
+
+    // Revert the most recently applied migration (if any)
+    async fn migrate_down(&self) -> Result<Option<MigrationInfo>, Error>;
+
+    // BEGIN
+    // SELECT id, name FROM user
+    // (merge duplicates: UPDATE transaction_history, DELETE FROM user, rebuild user_balance)
+    // UPDATE user SET name_key = :key WHERE id = :id
+    // (same for token)
+    // COMMIT
+    async fn dedupe(&self, dry_run: bool) -> Result<DedupeReport, Error>;
+
+    // INSERT INTO user(name, name_key) VALUES(:name, :key)
     // LAST ID
-    async fn create_user(&self, name: &str) -> Result<User, sqlx::Error>;
+    async fn create_user(&self, name: &str) -> Result<User, Error>;
 
     // SELECT * FROM user
-    async fn get_all_users(&self) -> Result<Vec<User>, sqlx::Error>;
+    async fn get_all_users(&self) -> Result<Vec<User>, Error>;
 
-    // SELECT * FROM user WHERE name LIKE '%:name%'
-    async fn query_user(&self, name: &str) -> Result<Vec<User>, sqlx::Error>;
+    // SELECT * FROM user WHERE name = :name ORDER BY id
+    // (or "lower(name) = lower(:name)" or "name LIKE ':name%'" or "name LIKE '%:name%'", depending on the match mode)
+    async fn query_user(&self, name: &str, matching: NameMatch) -> Result<Vec<User>, Error>;
 
-    // INSERT INTO token(name) VALUES(:name)
+    // INSERT INTO token(name, name_key, owner_id) VALUES(:name, :key, :owner_id)
     // LAST ID
-    async fn create_token(&self, name: &str) -> Result<Token, sqlx::Error>;
+    async fn create_token(&self, name: &str, owner: Option<UserQueryModeStrict<'_>>) -> Result<Token, Error>;
 
     // SELECT * FROM token
-    async fn get_all_tokens(&self) -> Result<Vec<Token>, sqlx::Error>;
+    async fn get_all_tokens(&self) -> Result<Vec<Token>, Error>;
+
+    // SELECT * FROM token WHERE name = :name ORDER BY id
+    // (or "lower(name) = lower(:name)" or "name LIKE ':name%'" or "name LIKE '%:name%'", depending on the match mode)
+    async fn query_token(&self, name: &str, matching: NameMatch) -> Result<Vec<Token>, Error>;
 
-    // SELECT * FROM token WHERE name LIKE '%:name%'
-    async fn query_token(&self, name: &str) -> Result<Vec<Token>, sqlx::Error>;
+    // SELECT * FROM token WHERE id = :token_id
+    // SELECT key, value FROM token_metadata WHERE token_id = :token_id
+    async fn get_token(&self, _token: TokenQueryModeStrict<'_>) -> Result<Token, Error>;
+
+    // BEGIN
+    // (fail, unless :actor_id is the owner or there is no owner yet, like set_token_owner)
+    // UPDATE token SET description = :description, symbol = :symbol, decimals = :decimals WHERE id = :token_id
+    // INSERT INTO token_metadata(token_id, key, value) VALUES(:token_id, :key, :value) ON CONFLICT DO UPDATE
+    // (or DELETE FROM token_metadata WHERE token_id = :token_id AND key = :key, for removed keys)
+    // COMMIT
+    //
+    // Returns the token after the changes (see get_token)
+    async fn edit_token(
+        &self,
+        _actor: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _edit: &TokenEdit,
+    ) -> Result<Token, Error>;
+
+    // BEGIN
+    // SELECT owner_id FROM token WHERE id = :token_id
+    // (fail, unless :actor_id is the owner or there is no owner yet)
+    // UPDATE token SET owner_id = :owner_id WHERE id = :token_id
+    // COMMIT
+    async fn set_token_owner(
+        &self,
+        _actor: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _owner: UserQueryModeStrict<'_>,
+    ) -> Result<(), Error>;
+
+    // BEGIN
+    // (fail, unless :actor_id is the owner, like set_token_owner)
+    // INSERT INTO token_minter(token_id, user_id) VALUES(:token_id, :user_id)
+    // (or DELETE FROM token_minter WHERE token_id = :token_id AND user_id = :user_id, if not _allowed_)
+    // COMMIT
+    async fn set_minter(
+        &self,
+        _actor: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _user: UserQueryModeStrict<'_>,
+        _allowed: bool,
+    ) -> Result<(), Error>;
+
+    // SELECT user.* FROM token_minter JOIN user ON user.id = token_minter.user_id WHERE token_id = :token_id ORDER BY user.id
+    async fn get_minters(&self, _token: TokenQueryModeStrict<'_>) -> Result<Vec<User>, Error>;
+
+    // BEGIN
+    // (fail, if :issuer_id = :holder_id)
+    // (fail, unless :issuer_id is the owner or a minter of the token)
+    // (negative amounts only: check the overdraft policy for the holder, like transaction)
+    // INSERT INTO transaction_history(sender_id, receiver_id, token_id, amount) VALUES(:issuer_id, :holder_id, :token_id, :amount)
+    // COMMIT
+    //
+    // Returns the new balance of the holder (see get_balance)
+    async fn issue(
+        &self,
+        _issuer: UserQueryModeStrict<'_>,
+        _holder: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _amount: TokenAmount,
+    ) -> Result<TokenAmount, Error>;
+
+    // SELECT credit_limit FROM token WHERE id = :token_id
+    async fn get_overdraft_policy(&self, _token: TokenQueryModeStrict<'_>) -> Result<OverdraftPolicy, Error>;
+
+    // BEGIN
+    // (fail, unless :actor_id is the owner or there is no owner yet, like edit_token)
+    // UPDATE token SET credit_limit = :credit_limit WHERE id = :token_id
+    // COMMIT
+    async fn set_overdraft_policy(
+        &self,
+        _actor: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _policy: OverdraftPolicy,
+    ) -> Result<(), Error>;
+
+    // SELECT SUM(current_total WHERE receiver_id = :user_id) - SUM(current_total WHERE sender_id = :user_id)
+    // FROM user_balance WHERE token_id = :token_id
+    async fn get_balance(&self, _user: UserQueryModeStrict<'_>, _token: TokenQueryModeStrict<'_>) -> Result<TokenAmount, Error>;
 
     // SELECT current_total FROM user_balance WHERE sender_id = :sender_id, receiver_id = :receiver_id, token_id = :token_id
     async fn get_current_total(
@@ -75,43 +239,104 @@ pub trait PersistanceLayer
         _sender: UserQueryModeStrict<'_>,
         _receiver: UserQueryModeStrict<'_>,
         _token: TokenQueryModeStrict<'_>,
-    ) -> Result<Option<TokenAmount>, sqlx::Error>;
+    ) -> Result<Option<TokenAmount>, Error>;
 
-    // INSERT INTO transaction_history(sender_id, receiver_id, token_id, amount) VALUES(:sender_id, :receiver_id, :token_id, :amount)
+    // BEGIN
+    // (resolve or create sender, receiver and token, which is owned by the sender)
+    // (with an idempotency key: DELETE the expired keys and return the new total of the key, if it is still known)
+    // SELECT credit_limit FROM token WHERE id = :token_id
+    // (if limited: SELECT the balance of the debited user, see get_balance, and fail if it doesn't cover the amount)
+    // SELECT current_total FROM user_balance WHERE sender_id = :sender_id, receiver_id = :receiver_id, token_id = :token_id
+    // (resolve the acting user, if any)
+    // INSERT INTO transaction_history(sender_id, receiver_id, token_id, amount, memo, reference, actor_id)
+    //      VALUES(:sender_id, :receiver_id, :token_id, :amount, :memo, :reference, :actor_id)
+    // (with an idempotency key: INSERT INTO transaction_idempotency(idempotency_key, transaction_id, new_total))
+    // COMMIT
+    //
+    // Returns the previous total plus the transaction amount. All steps must happen atomically, so concurrent
+    // transactions can neither create duplicate users/tokens nor base their new total on a stale previous total.
     async fn transaction(
         &self,
-        _sender: UserQueryModeStrict,
-        _receiver: UserQueryModeStrict,
-        _token: TokenQueryModeStrict,
+        _sender: UserQueryModeWithCreation<'_>,
+        _receiver: UserQueryModeWithCreation<'_>,
+        _token: TokenQueryModeWithCreation<'_>,
         _amount: TokenAmount,
-    ) -> Result<TokenAmount, sqlx::Error>;
+        _details: &TransactionDetails<'_>,
+    ) -> Result<TokenAmount, Error>;
+
+    // BEGIN
+    // (every leg like transaction, in order)
+    // COMMIT
+    //
+    // Returns the new total of every leg. If any leg fails, nothing is recorded (including created users and tokens).
+    async fn batch_transaction(&self, _legs: &[TransferLeg<'_>]) -> Result<Vec<TokenAmount>, Error>;
+
+    // BEGIN
+    // (for every record: SAVEPOINT, resolve or create its users and tokens or record it like transaction, with its
+    // created_at, and RELEASE the savepoint, or ROLLBACK TO it on failure)
+    // COMMIT (ROLLBACK for a dry run or if any record failed)
+    //
+    // Storage errors are returned right away, all other errors are collected in the report.
+    async fn import(&self, _records: &[ImportRecord<'_>], _dry_run: bool) -> Result<ImportReport, Error>;
+
+    // BEGIN
+    // SELECT sender_id, receiver_id, token_id, amount, reference, reverses_id FROM transaction_history WHERE id = :id
+    // SELECT id FROM transaction_history WHERE reverses_id = :id
+    // (fail, if the transaction doesn't exist, is a reversal itself or has already been reversed)
+    // (check the overdraft policy and insert the negated amount like transaction does)
+    // INSERT INTO transaction_history(sender_id, receiver_id, token_id, amount, memo, reference, reverses_id)
+    //      VALUES(:sender_id, :receiver_id, :token_id, -:amount, :reason, :reference, :id)
+    // COMMIT
+    //
+    // Returns the reversal like query_history. The unique index on "reverses_id" rejects a concurrent second reversal.
+    async fn reverse_transaction(&self, _id: TransactionID, _reason: &str) -> Result<HistoryEntry, Error>;
+
+    // SELECT history.*, sender.name, receiver.name, token.name, actor.name, reversal.id
+    // FROM transaction_history AS history
+    // JOIN user AS sender ON sender.id = history.sender_id
+    // JOIN user AS receiver ON receiver.id = history.receiver_id
+    // JOIN token ON token.id = history.token_id
+    // LEFT JOIN user AS actor ON actor.id = history.actor_id
+    // LEFT JOIN transaction_history AS reversal ON reversal.reverses_id = history.id
+    // WHERE (:sender_id IS NULL OR history.sender_id = :sender_id) AND ... (the other fields of the filter alike)
+    //   AND (history.created_at, history.id) > (:after_created_at, :after_id) (< for the descending order)
+    // ORDER BY history.created_at :order, history.id :order
+    // LIMIT :limit
+    async fn query_history(
+        &self,
+        _filter: &HistoryFilter<'_>,
+        _order: Order,
+        _after: Option<HistoryCursor>,
+        _limit: usize,
+    ) -> Result<Vec<HistoryEntry>, Error>;
 
-    // SELECT sender.*, balance.mount
+    // SELECT sender.*, balance.current_total, (sum of the reversed amounts)
     // FROM user_balance AS balance
     // JOIN user AS sender ON sender.id = balance.sender_id
     // WHERE receiver_id = :receiver_id, token_id = :token_id
     // ORDER BY :order_by :order
     async fn list_user_token(
         &self,
-        _receiver: UserQueryModeStrict,
-        _token: TokenQueryModeStrict,
+        _receiver: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
         _order: Order,
         _order_by: Option<OrderBySenderOrAmount>,
-    ) -> Result<Vec<RelativeUserTokenAmountEntry>, sqlx::Error>;
+    ) -> Result<Vec<RelativeUserTokenAmountEntry>, Error>;
 
-    // SELECT sender.*, token.*, balance.amount
+    // SELECT sender.*, token.*, balance.current_total, (sum of the reversed amounts)
     // FROM user_balance AS balance
     // JOIN user AS sender ON sender.id = balance.sender_id
+    // JOIN token ON token.id = balance.token_id
     // WHERE receiver_id = :receiver_id
     // ORDER BY :order_by :order
     async fn list_tokens_by_user(
         &self,
-        _receiver: UserQueryModeStrict,
+        _receiver: UserQueryModeStrict<'_>,
         _order: Order,
         _order_by: Option<OrderByTokenOrSenderOrAmount>,
-    ) -> Result<Vec<RelativeTokenAmountEntry>, sqlx::Error>;
+    ) -> Result<Vec<RelativeTokenAmountEntry>, Error>;
 
-    // SELECT sender.*, receiver.*, balance.amount
+    // SELECT sender.*, receiver.*, balance.current_total, (sum of the reversed amounts)
     // FROM user_balance AS balance
     // JOIN user AS sender ON sender.id = balance.sender_id
     // JOIN user AS receiver ON receiver.id = balance.receiver_id
@@ -119,8 +344,8 @@ pub trait PersistanceLayer
     // ORDER BY :order_by :order
     async fn list_users_by_token(
         &self,
-        _token: TokenQueryModeStrict,
+        _token: TokenQueryModeStrict<'_>,
         _order: Order,
         _order_by: Option<OrderByReceiverOrSenderOrAmount>,
-    ) -> Result<Vec<RelativeUserAmountEntry>, sqlx::Error>;
+    ) -> Result<Vec<RelativeUserAmountEntry>, Error>;
 }
@@ -0,0 +1,831 @@
+//! Building blocks shared by the persistance layer implementations, mostly by the SQL based ones
+
+use super::{
+    names, persistance_layer::*, DedupeReport, Error, HistoryCursor, HistoryFilter, ImportRecord, ImportReport, NameMatch,
+    NameNormalization, Order, OrderByReceiverOrSenderOrAmount, OrderBySenderOrAmount, OrderByTokenOrSenderOrAmount, OverdraftPolicy,
+    TokenAmount, TokenID, TokenQueryModeWithCreation, TokenRight, TransactionID, TransferLeg, UserID, UserQueryModeWithCreation,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use sqlx::{
+    migrate::{AppliedMigration, Migrate, Migrator},
+    Connection, Database, Encode, FromRow, Pool, QueryBuilder, Transaction, Type,
+};
+use std::collections::HashMap;
+
+/// IDs of sender, receiver and token
+pub(super) type Participants = (UserID, UserID, TokenID);
+
+/// Flat result row of the balance listings, before it gets grouped into the nested result types.
+#[derive(FromRow)]
+pub(super) struct BalanceRow
+{
+    pub group_id:    DbPk,
+    pub group_name:  String,
+    pub sender_id:   UserID,
+    pub sender_name: String,
+    pub amount:      TokenAmount,
+    pub reversed:    TokenAmount,
+}
+
+impl BalanceRow
+{
+    pub fn sender(&self) -> User
+    {
+        User {
+            id:   self.sender_id,
+            name: self.sender_name.clone(),
+        }
+    }
+
+    pub fn entry(&self) -> RelativeUserTokenAmountEntry
+    {
+        RelativeUserTokenAmountEntry {
+            sender:   self.sender(),
+            amount:   self.amount,
+            reversed: self.reversed,
+        }
+    }
+}
+
+/// "reversed" column of the balance listings ([BalanceRow]): the original amounts of the reversed transactions of the
+/// relation "balance". The reversals carry the negated amounts.
+pub(super) const REVERSED_COLUMN: &str = "CAST(COALESCE((SELECT -SUM(reversal.amount) FROM transaction_history AS reversal
+                  WHERE reversal.reverses_id IS NOT NULL AND reversal.sender_id = balance.sender_id
+                    AND reversal.receiver_id = balance.receiver_id AND reversal.token_id = balance.token_id), 0) AS BIGINT)
+             AS reversed";
+
+/// Everything _user_id_ received minus everything the user sent, from the running totals of one token as
+/// (receiver, sender, total). Fails with [Error::Overflow], if the balance is out of range.
+pub(super) fn balance(user_id: UserID, totals: impl IntoIterator<Item = (UserID, UserID, TokenAmount)>) -> Result<TokenAmount, Error>
+{
+    // i128 can't overflow for any realistic number of totals, so only the result needs to be checked
+    let balance: i128 = totals
+        .into_iter()
+        .map(
+            |(receiver_id, sender_id, total)| match (receiver_id == user_id, sender_id == user_id)
+            {
+                (true, false) => i128::from(total),
+                (false, true) => -i128::from(total),
+                _ => 0,
+            },
+        )
+        .sum();
+    TokenAmount::try_from(balance).map_err(|_| Error::Overflow)
+}
+
+/// Running total of a relation after adding _amount_
+pub(super) fn new_total(previous_total: Option<TokenAmount>, amount: TokenAmount) -> Result<TokenAmount, Error>
+{
+    previous_total.unwrap_or_default().checked_add(amount).ok_or(Error::Overflow)
+}
+
+/// [TransactionDetails](super::TransactionDetails) with the acting user resolved, as they are inserted into
+/// "transaction_history" (_actor_id_ is _None_, if the sender acts on their own)
+#[derive(Copy, Clone, Default, Debug)]
+pub(super) struct TransactionNote<'a>
+{
+    pub actor_id:    Option<UserID>,
+    pub memo:        Option<&'a str>,
+    pub reference:   Option<&'a str>,
+    /// The transaction, that this one compensates (see [Core::reverse_transaction](super::Core::reverse_transaction))
+    pub reverses_id: Option<TransactionID>,
+    /// Time of imported transactions (see [Core::import](super::Core::import)), all others happen now
+    pub created_at:  Option<DateTime<Utc>>,
+}
+
+/// A transaction, that is about to be reversed
+#[derive(FromRow, Debug)]
+pub(super) struct ReversalTarget
+{
+    pub sender_id:      UserID,
+    pub receiver_id:    UserID,
+    pub token_id:       TokenID,
+    pub amount:         TokenAmount,
+    pub reference:      Option<String>,
+    pub reverses_id:    Option<TransactionID>,
+    pub reversed_by_id: Option<TransactionID>,
+}
+
+/// SELECT of [ReversalTarget] rows (followed by "WHERE history.id = ...")
+pub(super) const REVERSAL_TARGET_QUERY: &str =
+    "SELECT history.sender_id, history.receiver_id, history.token_id, history.amount, history.reference, history.reverses_id,
+            (SELECT reversal.id FROM transaction_history AS reversal WHERE reversal.reverses_id = history.id) AS reversed_by_id
+     FROM transaction_history AS history";
+
+/// Fails, if the issuer would mint or burn its own points, which doesn't change any balance
+pub(super) fn check_issue(issuer_id: UserID, holder_id: UserID) -> Result<(), Error>
+{
+    match issuer_id == holder_id
+    {
+        true => Err(Error::InvalidArgument(format!(
+            "user {issuer_id} can't mint or burn their own points"
+        ))),
+        false => Ok(()),
+    }
+}
+
+/// Transaction _id_ (_target_, if it exists) and the amount of its reversal. Fails, unless the transaction may be reversed.
+pub(super) fn check_reversal(id: TransactionID, target: Option<ReversalTarget>) -> Result<(ReversalTarget, TokenAmount), Error>
+{
+    let target = target.ok_or(Error::TransactionNotFound(id))?;
+    if let Some(reversal_id) = target.reversed_by_id
+    {
+        return Err(Error::AlreadyReversed { id, reversal_id });
+    }
+    if let Some(original_id) = target.reverses_id
+    {
+        return Err(Error::InvalidArgument(format!(
+            "transaction {id} is the reversal of transaction {original_id} and can't be reversed itself"
+        )));
+    }
+    let amount = target.amount.checked_neg().ok_or(Error::Overflow)?;
+    Ok((target, amount))
+}
+
+impl ReversalTarget
+{
+    /// Note of the reversal of this transaction (_id_): the reason becomes the memo, the reference is kept
+    pub fn note<'a>(&'a self, id: TransactionID, reason: &'a str) -> TransactionNote<'a>
+    {
+        TransactionNote {
+            actor_id:    None,
+            memo:        Some(reason),
+            reference:   self.reference.as_deref(),
+            reverses_id: Some(id),
+            created_at:  None,
+        }
+    }
+}
+
+/// Number of users, tokens and transactions, so an import can tell how many it has added
+#[derive(FromRow, Copy, Clone, Debug)]
+pub(super) struct RowCounts
+{
+    pub users:        i64,
+    pub tokens:       i64,
+    pub transactions: i64,
+}
+
+pub(super) const ROW_COUNTS_QUERY: &str = r#"SELECT (SELECT COUNT(*) FROM "User") AS users, (SELECT COUNT(*) FROM Token) AS tokens,
+                                                   (SELECT COUNT(*) FROM transaction_history) AS transactions"#;
+
+impl RowCounts
+{
+    /// Fills in the numbers of rows, that have been added since _before_
+    pub fn added_since(self, before: RowCounts, report: &mut ImportReport)
+    {
+        let added = |after: i64, before: i64| usize::try_from(after - before).unwrap_or_default();
+        report.users = added(self.users, before.users);
+        report.tokens = added(self.tokens, before.tokens);
+        report.transactions = added(self.transactions, before.transactions);
+    }
+}
+
+/// Earlier transaction with the same idempotency key
+#[derive(FromRow, Debug)]
+pub(super) struct KeyedTransaction
+{
+    pub sender_id:   UserID,
+    pub receiver_id: UserID,
+    pub token_id:    TokenID,
+    pub amount:      TokenAmount,
+    pub new_total:   TokenAmount,
+}
+
+/// Oldest creation time of idempotency keys, that are still remembered
+pub(super) fn idempotency_cutoff(retention: TimeDelta) -> DateTime<Utc>
+{
+    Utc::now().checked_sub_signed(retention).unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
+
+/// New total of the earlier transaction with the idempotency _key_ (_previous_), that is repeated by a transaction
+/// of _amount_ between _participants_. Fails, if the key has been used for another transaction.
+pub(super) fn repeated_total(
+    key: &str,
+    previous: &KeyedTransaction,
+    (sender_id, receiver_id, token_id): Participants,
+    amount: TokenAmount,
+) -> Result<TokenAmount, Error>
+{
+    match (previous.sender_id, previous.receiver_id, previous.token_id, previous.amount) == (sender_id, receiver_id, token_id, amount)
+    {
+        true => Ok(previous.new_total),
+        false => Err(Error::InvalidArgument(format!(
+            "idempotency key \"{key}\" has already been used for another transaction"
+        ))),
+    }
+}
+
+/// [HistoryFilter](super::HistoryFilter) with users and token resolved
+#[derive(Copy, Clone, Default, Debug)]
+pub(super) struct HistoryIds
+{
+    pub sender_id:   Option<UserID>,
+    pub receiver_id: Option<UserID>,
+    pub token_id:    Option<TokenID>,
+}
+
+/// SELECT of [HistoryEntry] rows (followed by a WHERE clause on "history")
+pub(super) const HISTORY_SELECT: &str = r#"SELECT history.id, history.created_at, history.sender_id, sender.name AS sender_name,
+        history.receiver_id, receiver.name AS receiver_name, history.token_id, token.name AS token_name,
+        history.amount, history.memo, history.reference, history.actor_id, actor.name AS actor_name,
+        history.reverses_id, reversal.id AS reversed_by_id
+    FROM transaction_history AS history
+    JOIN "User" AS sender ON sender.id = history.sender_id
+    JOIN "User" AS receiver ON receiver.id = history.receiver_id
+    JOIN Token AS token ON token.id = history.token_id
+    LEFT JOIN "User" AS actor ON actor.id = history.actor_id
+    LEFT JOIN transaction_history AS reversal ON reversal.reverses_id = history.id"#;
+
+/// SELECT of a page of [HistoryEntry] rows (the same for SQLite and PostgreSQL). _ids_ are the resolved users and
+/// token of _filter_. _time_ converts timestamps into the values, that are compared with "created_at".
+pub(super) fn history_query<'a, DB, Time>(
+    ids: HistoryIds,
+    filter: &HistoryFilter<'_>,
+    order: Order,
+    after: Option<HistoryCursor>,
+    limit: usize,
+    time: impl Fn(DateTime<Utc>) -> Time,
+) -> QueryBuilder<'a, DB>
+where
+    DB: Database,
+    DbPk: Encode<'a, DB> + Type<DB>,
+    String: Encode<'a, DB> + Type<DB>,
+    Time: 'a + Encode<'a, DB> + Type<DB>,
+{
+    let mut query = QueryBuilder::new(HISTORY_SELECT);
+    query.push(" WHERE TRUE");
+    for (column, id) in [
+        ("history.sender_id", ids.sender_id),
+        ("history.receiver_id", ids.receiver_id),
+        ("history.token_id", ids.token_id),
+    ]
+    {
+        if let Some(id) = id
+        {
+            query.push(format_args!(" AND {column} = ")).push_bind(id);
+        }
+    }
+    if let Some(amount) = filter.min_amount
+    {
+        query.push(" AND history.amount >= ").push_bind(amount);
+    }
+    if let Some(amount) = filter.max_amount
+    {
+        query.push(" AND history.amount <= ").push_bind(amount);
+    }
+    if let Some(since) = filter.since
+    {
+        query.push(" AND history.created_at >= ").push_bind(time(since));
+    }
+    if let Some(until) = filter.until
+    {
+        query.push(" AND history.created_at < ").push_bind(time(until));
+    }
+    if let Some(memo) = &filter.memo
+    {
+        query
+            .push(r" AND lower(history.memo) LIKE lower(")
+            .push_bind(name_pattern(memo, NameMatch::Substring))
+            .push(r") ESCAPE '\'");
+    }
+
+    let comparison = match order
+    {
+        Order::Asc => ">",
+        Order::Desc => "<",
+    };
+    match after.map(|cursor| (cursor.created_at, cursor.id))
+    {
+        Some((Some(created_at), id)) =>
+        {
+            query
+                .push(format_args!(" AND (history.created_at {comparison} "))
+                .push_bind(time(created_at))
+                .push(" OR (history.created_at = ")
+                .push_bind(time(created_at))
+                .push(format_args!(" AND history.id {comparison} "))
+                .push_bind(id)
+                .push("))");
+        }
+        // Transactions without a time come first, but only the JSON-lines files have any
+        Some((None, _)) if order == Order::Desc =>
+        {
+            query.push(" AND FALSE");
+        }
+        Some((None, _)) | None => (),
+    }
+
+    let order = order_sql(order);
+    query.push(format_args!(" ORDER BY history.created_at {order}, history.id {order} LIMIT "));
+    query.push_bind(DbPk::try_from(limit).unwrap_or(DbPk::MAX));
+    query
+}
+
+/// "created_at" as SQLite stores it (see the default of the column), so timestamps can be compared as text
+pub(super) fn sqlite_time(time: DateTime<Utc>) -> String
+{
+    time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+/// Columns of [Token] for "SELECT ... FROM Token" (the metadata has to be added by [attach_metadata])
+pub(super) const TOKEN_COLUMNS: &str =
+    r#"id, name, owner_id, (SELECT name FROM "User" WHERE "User".id = Token.owner_id) AS owner_name, description, symbol, decimals"#;
+
+/// Adds rows of the "token_metadata" table (token_id, key, value) to the tokens they belong to
+pub(super) fn attach_metadata(tokens: &mut [Token], rows: Vec<(TokenID, String, String)>)
+{
+    let index_by_id: HashMap<TokenID, usize> = tokens.iter().enumerate().map(|(index, token)| (token.id, index)).collect();
+    for (token_id, key, value) in rows
+    {
+        if let Some(&index) = index_by_id.get(&token_id)
+        {
+            tokens[index].metadata.insert(key, value);
+        }
+    }
+}
+
+fn order_sql(order: Order) -> &'static str
+{
+    match order
+    {
+        Order::Asc => "ASC",
+        Order::Desc => "DESC",
+    }
+}
+
+// ORDER BY clauses of the balance listings. They refer to the table aliases "balance", "sender", "receiver" and "token".
+// Without an explicit column, listings are ordered by amount.
+pub(super) fn order_by_sender_or_amount(order: Order, order_by: Option<OrderBySenderOrAmount>) -> String
+{
+    let order = order_sql(order);
+    match order_by.unwrap_or(OrderBySenderOrAmount::Amount)
+    {
+        OrderBySenderOrAmount::Sender => format!("sender.name {order}, sender.id {order}"),
+        OrderBySenderOrAmount::Amount => format!("balance.current_total {order}, sender.name, sender.id"),
+    }
+}
+
+pub(super) fn order_by_token_or_sender_or_amount(order: Order, order_by: Option<OrderByTokenOrSenderOrAmount>) -> String
+{
+    let order = order_sql(order);
+    match order_by.unwrap_or(OrderByTokenOrSenderOrAmount::Amount)
+    {
+        OrderByTokenOrSenderOrAmount::Token => format!("token.name {order}, token.id {order}, balance.current_total DESC"),
+        OrderByTokenOrSenderOrAmount::Sender => format!("sender.name {order}, sender.id {order}"),
+        OrderByTokenOrSenderOrAmount::Amount => format!("balance.current_total {order}, sender.name, sender.id"),
+    }
+}
+
+pub(super) fn order_by_receiver_or_sender_or_amount(order: Order, order_by: Option<OrderByReceiverOrSenderOrAmount>) -> String
+{
+    let order = order_sql(order);
+    match order_by.unwrap_or(OrderByReceiverOrSenderOrAmount::Amount)
+    {
+        OrderByReceiverOrSenderOrAmount::Receiver => format!("receiver.name {order}, receiver.id {order}, balance.current_total DESC"),
+        OrderByReceiverOrSenderOrAmount::Sender => format!("sender.name {order}, sender.id {order}"),
+        OrderByReceiverOrSenderOrAmount::Amount => format!("balance.current_total {order}, sender.name, sender.id"),
+    }
+}
+
+/// Maps the violation of the unique name key index to [Error::DuplicateName]
+pub(super) fn duplicate_name_error(err: sqlx::Error, name: &str) -> Error
+{
+    match err
+    {
+        sqlx::Error::Database(ref db_error) if db_error.is_unique_violation() => Error::DuplicateName(name.to_string()),
+        _ => Error::from(err),
+    }
+}
+
+/// WHERE condition on the "name" column for the given match mode. _parameter_ is the placeholder of the bound value,
+/// which has to be prepared with [name_parameter].
+pub(super) fn name_condition(matching: NameMatch, parameter: &str) -> String
+{
+    match matching
+    {
+        NameMatch::Exact => format!("name_key = {parameter}"),
+        NameMatch::IgnoreCase => format!("lower(name) = lower({parameter})"),
+        NameMatch::Prefix | NameMatch::Substring => format!(r"lower(name) LIKE lower({parameter}) ESCAPE '\'"),
+    }
+}
+
+/// Value to bind to the placeholder of [name_condition]. LIKE wildcards within the name are escaped.
+pub(super) fn name_pattern(name: &str, matching: NameMatch) -> String
+{
+    let escaped = || name.replace('\\', r"\\").replace('%', r"\%").replace('_', r"\_");
+    match matching
+    {
+        NameMatch::Exact | NameMatch::IgnoreCase => name.to_string(),
+        NameMatch::Prefix => format!("{}%", escaped()),
+        NameMatch::Substring => format!("%{}%", escaped()),
+    }
+}
+
+/// Value to bind to the placeholder of [name_condition]: the key of _name_ for [NameMatch::Exact], so it finds
+/// the same user or token as the name resolution does, and the [name_pattern] otherwise
+pub(super) fn name_parameter(name: &str, matching: NameMatch, names: &NameNormalization) -> String
+{
+    match matching
+    {
+        NameMatch::Exact => names.key(name),
+        _ => name_pattern(name, matching),
+    }
+}
+
+/// Splits rows into groups by `group_id`. Groups appear in the order of their first row,
+/// so the ORDER BY clause of the query determines the order of the groups as well as the order within each group.
+pub(super) fn group_balance_rows(rows: Vec<BalanceRow>) -> Vec<(BalanceRow, Vec<RelativeUserTokenAmountEntry>)>
+{
+    let mut groups: Vec<(BalanceRow, Vec<RelativeUserTokenAmountEntry>)> = Vec::new();
+    let mut index_by_id: HashMap<DbPk, usize> = HashMap::new();
+    for row in rows
+    {
+        let entry = row.entry();
+        match index_by_id.get(&row.group_id)
+        {
+            Some(&index) => groups[index].1.push(entry),
+            None =>
+            {
+                index_by_id.insert(row.group_id, groups.len());
+                groups.push((row, vec![entry]));
+            }
+        }
+    }
+    groups
+}
+
+// ============================================== Shared Write Logic ==============================================
+
+/// Connection of the database of an [SqlLedger]
+pub(super) type Conn<L> = <<L as SqlLedger>::Database as Database>::Connection;
+
+/// The queries, that the SQL based persistance layers implement for their database, and the logic built on top of
+/// them, which is the same for all of them (transactions, imports and deduplication).
+///
+/// Everything runs within an SQL transaction (_conn_), which the caller commits, unless a method takes it over.
+/// Concurrent writers are only held up by the rows they share: [SqlLedger::lock_debited] and
+/// [SqlLedger::keyed_transaction] lock what the checks depend on, and the trigger on "transaction_history" updates
+/// the running totals in place.
+#[async_trait]
+pub(super) trait SqlLedger: Send + Sync
+{
+    type Database: Database;
+
+    fn names(&self) -> &NameNormalization;
+
+    async fn resolve_user(&self, conn: &mut Conn<Self>, user: UserQueryModeWithCreation<'_>) -> Result<UserID, Error>;
+
+    /// Tokens, that have to be created, are owned by _owner_id_
+    async fn resolve_token(
+        &self,
+        conn: &mut Conn<Self>,
+        token: TokenQueryModeWithCreation<'_>,
+        owner_id: Option<UserID>,
+    ) -> Result<TokenID, Error>;
+
+    async fn overdraft_policy(conn: &mut Conn<Self>, token_id: TokenID) -> Result<OverdraftPolicy, Error>;
+
+    /// Whether the user has the right for the token, without locking anything for it
+    async fn has_right(conn: &mut Conn<Self>, right: TokenRight, user_id: UserID, token_id: TokenID) -> Result<bool, Error>;
+
+    async fn balance(conn: &mut Conn<Self>, user_id: UserID, token_id: TokenID) -> Result<TokenAmount, Error>;
+
+    async fn current_total(conn: &mut Conn<Self>, participants: Participants) -> Result<Option<TokenAmount>, Error>;
+
+    /// Keeps concurrent transactions from debiting the user, until this one ends
+    async fn lock_debited(conn: &mut Conn<Self>, user_id: UserID) -> Result<(), Error>;
+
+    /// Inserts the row into "transaction_history" (its trigger adds the amount to the running total)
+    async fn insert_history(
+        conn: &mut Conn<Self>,
+        participants: Participants,
+        amount: TokenAmount,
+        note: TransactionNote<'_>,
+    ) -> Result<TransactionID, Error>;
+
+    /// Earlier transaction with the idempotency _key_, that hasn't expired yet. Forgets all expired keys first and
+    /// keeps concurrent transactions from using _key_, until this one ends.
+    async fn keyed_transaction(&self, conn: &mut Conn<Self>, key: &str) -> Result<Option<KeyedTransaction>, Error>;
+
+    async fn insert_idempotency_key(
+        conn: &mut Conn<Self>,
+        key: &str,
+        transaction_id: TransactionID,
+        new_total: TokenAmount,
+    ) -> Result<(), Error>;
+
+    async fn row_counts(conn: &mut Conn<Self>) -> Result<RowCounts, Error>;
+
+    async fn all_users(conn: &mut Conn<Self>) -> Result<Vec<User>, Error>;
+
+    /// All tokens, without their metadata
+    async fn all_tokens(conn: &mut Conn<Self>) -> Result<Vec<Token>, Error>;
+
+    /// Moves the transactions, tokens and rights of user _merged_id_ to user _kept_id_ and deletes _merged_id_
+    async fn merge_user(conn: &mut Conn<Self>, kept_id: UserID, merged_id: UserID) -> Result<(), Error>;
+
+    /// Moves the transactions, minters and metadata of token _merged_id_ to token _kept_id_ and deletes _merged_id_
+    async fn merge_token(conn: &mut Conn<Self>, kept_id: TokenID, merged_id: TokenID) -> Result<(), Error>;
+
+    /// Deletes all running totals, so the users and tokens they refer to can be merged
+    async fn clear_balances(conn: &mut Conn<Self>) -> Result<(), Error>;
+
+    /// Calculates all running totals from the transaction history
+    async fn rebuild_balances(conn: &mut Conn<Self>) -> Result<(), Error>;
+
+    /// Removes the name keys of all users and tokens, so they can be assigned again
+    async fn clear_name_keys(conn: &mut Conn<Self>) -> Result<(), Error>;
+
+    async fn set_user_name_key(conn: &mut Conn<Self>, user_id: UserID, key: &str) -> Result<(), Error>;
+
+    async fn set_token_name_key(conn: &mut Conn<Self>, token_id: TokenID, key: &str) -> Result<(), Error>;
+
+    /// Inserts the transaction, if the [OverdraftPolicy] allows it. Returns its ID and the new total.
+    async fn insert_transaction(
+        conn: &mut Conn<Self>,
+        participants: Participants,
+        amount: TokenAmount,
+        policy: OverdraftPolicy,
+        note: TransactionNote<'_>,
+    ) -> Result<(TransactionID, TokenAmount), Error>
+    {
+        let (sender_id, receiver_id, token_id) = participants;
+        if let Some((debited_id, debit)) = policy.limited_debit(sender_id, receiver_id, amount)?
+        {
+            Self::lock_debited(conn, debited_id).await?;
+            policy.check(Self::balance(conn, debited_id, token_id).await?, debit)?;
+        }
+        // SQLite would silently turn an overflowing total into a floating point number
+        new_total(Self::current_total(conn, participants).await?, amount)?;
+
+        let id = Self::insert_history(conn, participants, amount, note).await?;
+        // Concurrent transactions of the same relation may have changed the total since it has been checked
+        let new_total = Self::current_total(conn, participants).await?.unwrap_or(amount);
+
+        Ok((id, new_total))
+    }
+
+    /// New total of the transaction, that has been recorded with the idempotency _key_ before (_None_, if the key is
+    /// new or has expired)
+    async fn repeated_transaction(
+        &self,
+        conn: &mut Conn<Self>,
+        key: &str,
+        participants: Participants,
+        amount: TokenAmount,
+    ) -> Result<Option<TokenAmount>, Error>
+    {
+        self.keyed_transaction(conn, key)
+            .await?
+            .map(|previous| repeated_total(key, &previous, participants, amount))
+            .transpose()
+    }
+
+    /// Resolves the participants and records one transaction (see [PersistanceLayer::transaction]). It happens at
+    /// _created_at_ or, without it, now.
+    async fn transfer(&self, conn: &mut Conn<Self>, leg: &TransferLeg<'_>, created_at: Option<DateTime<Utc>>)
+        -> Result<TokenAmount, Error>
+    {
+        let (amount, details) = (leg.amount, &leg.details);
+        let sender_id = self.resolve_user(conn, leg.sender).await?;
+        let receiver_id = self.resolve_user(conn, leg.receiver).await?;
+        let token_id = self.resolve_token(conn, leg.token, Some(sender_id)).await?;
+        let participants = (sender_id, receiver_id, token_id);
+        if let Some(key) = &details.idempotency_key
+        {
+            // Repeated participants already existed, so nothing has been created on the way
+            if let Some(total) = self.repeated_transaction(conn, key, participants, amount).await?
+            {
+                return Ok(total);
+            }
+        }
+
+        let actor_id = match details.actor
+        {
+            Some(actor) => Some(self.resolve_user(conn, actor.into()).await?),
+            None => None,
+        };
+        let note = TransactionNote {
+            actor_id: actor_id.filter(|&actor_id| actor_id != sender_id),
+            memo: details.memo.as_deref(),
+            reference: details.reference.as_deref(),
+            created_at,
+            ..TransactionNote::default()
+        };
+
+        let mut policy = Self::overdraft_policy(conn, token_id).await?;
+        // Only those, who could issue the points anyway, create them on an owned token
+        if let (OverdraftPolicy::Free, Some((debited_id, _))) = (policy, OverdraftPolicy::debited(sender_id, receiver_id, amount)?)
+        {
+            if !Self::has_right(conn, TokenRight::Overdraw, debited_id, token_id).await?
+            {
+                policy = OverdraftPolicy::BalanceBacked;
+            }
+        }
+        let (id, new_total) = Self::insert_transaction(conn, participants, amount, policy, note).await?;
+        if let Some(key) = &details.idempotency_key
+        {
+            Self::insert_idempotency_key(conn, key, id, new_total).await?;
+        }
+
+        Ok(new_total)
+    }
+
+    /// Imports a single record (see [PersistanceLayer::import])
+    async fn import_record(&self, conn: &mut Conn<Self>, record: &ImportRecord<'_>) -> Result<(), Error>
+    {
+        match record
+        {
+            ImportRecord::User { name } =>
+            {
+                self.resolve_user(conn, UserQueryModeWithCreation::ByNameOrCreate(name)).await?;
+            }
+            ImportRecord::Token { name, owner } =>
+            {
+                let owner_id = match owner
+                {
+                    Some(owner) => Some(self.resolve_user(conn, UserQueryModeWithCreation::ByNameOrCreate(owner)).await?),
+                    None => None,
+                };
+                self.resolve_token(conn, TokenQueryModeWithCreation::ByNameOrCreate(name), owner_id)
+                    .await?;
+            }
+            ImportRecord::Transaction { leg, created_at } =>
+            {
+                self.transfer(conn, leg, *created_at).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Imports the records (see [PersistanceLayer::import]) and commits _tx_, if it's no dry run and none of them failed
+    async fn import_records(
+        &self,
+        mut tx: Transaction<'static, Self::Database>,
+        records: &[ImportRecord<'_>],
+        dry_run: bool,
+    ) -> Result<ImportReport, Error>
+    {
+        let before = Self::row_counts(&mut tx).await?;
+
+        let mut report = ImportReport::default();
+        for (index, record) in records.iter().enumerate()
+        {
+            // Failed records are rolled back to their savepoint, so the remaining ones can still be validated
+            let mut savepoint = tx.begin().await?;
+            match self.import_record(&mut savepoint, record).await
+            {
+                Ok(()) => savepoint.commit().await?,
+                Err(err) =>
+                {
+                    savepoint.rollback().await?;
+                    report.add_failure(index, err)?;
+                }
+            }
+        }
+
+        Self::row_counts(&mut tx).await?.added_since(before, &mut report);
+        if !dry_run && report.failures.is_empty()
+        {
+            tx.commit().await?;
+            report.committed = true;
+        }
+
+        Ok(report)
+    }
+
+    /// Merges users and tokens with the same name key (see [PersistanceLayer::dedupe]) and commits _tx_, if it's no
+    /// dry run
+    async fn merge_duplicates(&self, mut tx: Transaction<'static, Self::Database>, dry_run: bool) -> Result<DedupeReport, Error>
+    {
+        let users = Self::all_users(&mut tx).await?;
+        let tokens = Self::all_tokens(&mut tx).await?;
+        let report = DedupeReport {
+            users:  names::duplicates(users, self.names(), |user| (user.id, &user.name)),
+            tokens: names::duplicates(tokens, self.names(), |token| (token.id, &token.name)),
+        };
+        if dry_run
+        {
+            return Ok(report);
+        }
+
+        // Balances refer to the merged users and tokens, so they are rebuilt from the updated transaction history
+        Self::clear_balances(&mut tx).await?;
+        for group in &report.users
+        {
+            for user in &group.merged
+            {
+                Self::merge_user(&mut tx, group.kept.id, user.id).await?;
+            }
+        }
+        for group in &report.tokens
+        {
+            for token in &group.merged
+            {
+                Self::merge_token(&mut tx, group.kept.id, token.id).await?;
+            }
+        }
+        Self::rebuild_balances(&mut tx).await?;
+
+        // Recalculate all keys, as the normalization might have changed since they were assigned
+        Self::clear_name_keys(&mut tx).await?;
+        for user in Self::all_users(&mut tx).await?
+        {
+            Self::set_user_name_key(&mut tx, user.id, &self.names().key(&user.name)).await?;
+        }
+        for token in Self::all_tokens(&mut tx).await?
+        {
+            Self::set_token_name_key(&mut tx, token.id, &self.names().key(&token.name)).await?;
+        }
+
+        tx.commit().await?;
+        Ok(report)
+    }
+}
+
+// ================================================ Schema Migrations ================================================
+async fn applied_migrations<DB>(pool: &Pool<DB>) -> Result<Vec<AppliedMigration>, Error>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    Ok(conn.list_applied_migrations().await?)
+}
+
+/// Refuse to work with a database, that has been migrated by a newer version of this program.
+pub(super) async fn check_schema_version<DB>(migrator: &Migrator, pool: &Pool<DB>) -> Result<(), Error>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let supported = migrator.iter().map(|migration| migration.version).max().unwrap_or_default();
+    let database = applied_migrations(pool)
+        .await?
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or_default();
+
+    if database > supported
+    {
+        return Err(Error::SchemaTooNew { database, supported });
+    }
+    Ok(())
+}
+
+pub(super) async fn migration_status<DB>(migrator: &Migrator, pool: &Pool<DB>) -> Result<Vec<MigrationInfo>, Error>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let applied = applied_migrations(pool).await?;
+
+    Ok(migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| MigrationInfo {
+            version:     migration.version,
+            description: migration.description.to_string(),
+            applied:     applied.iter().any(|applied| applied.version == migration.version),
+        })
+        .collect())
+}
+
+pub(super) async fn migrate_up<DB>(migrator: &Migrator, pool: &Pool<DB>) -> Result<(), Error>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    Ok(migrator.run(pool).await?)
+}
+
+pub(super) async fn migrate_down<DB>(migrator: &Migrator, pool: &Pool<DB>) -> Result<Option<MigrationInfo>, Error>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let mut applied: Vec<i64> = applied_migrations(pool).await?.iter().map(|migration| migration.version).collect();
+    applied.sort_unstable();
+
+    let Some(latest) = applied.pop()
+    else
+    {
+        return Ok(None);
+    };
+
+    // Reverts every migration newer than the target, which is only the latest one
+    migrator.undo(pool, applied.last().copied().unwrap_or_default()).await?;
+
+    Ok(migrator
+        .iter()
+        .find(|migration| migration.version == latest)
+        .map(|migration| MigrationInfo {
+            version:     migration.version,
+            description: migration.description.to_string(),
+            applied:     false,
+        }))
+}
@@ -0,0 +1,131 @@
+use crate::core::{TokenAmount, TransactionID, UserID};
+use std::fmt::{Display, Formatter};
+
+pub type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Error type of [Core](crate::core::Core) and all persistance layer implementations
+#[derive(Debug, thiserror::Error)]
+pub enum Error
+{
+    #[error("user {0} not found")]
+    UserNotFound(Identifier),
+
+    #[error("token {0} not found")]
+    TokenNotFound(Identifier),
+
+    #[error("transaction {0} not found")]
+    TransactionNotFound(TransactionID),
+
+    #[error("transaction {id} has already been reversed by transaction {reversal_id}")]
+    AlreadyReversed
+    {
+        id:          TransactionID,
+        reversal_id: TransactionID,
+    },
+
+    #[error("name \"{name}\" is ambiguous ({matches} matches)")]
+    AmbiguousName
+    {
+        name: String, matches: usize
+    },
+
+    #[error("name \"{0}\" is already taken")]
+    DuplicateName(String),
+
+    #[error("user {user} is not allowed to manage token {token}")]
+    NotAuthorized
+    {
+        user: Identifier, token: Identifier
+    },
+
+    #[error("insufficient balance ({available} available, {required} required)")]
+    InsufficientBalance
+    {
+        available: TokenAmount, required: TokenAmount
+    },
+
+    #[error("the decimals of token {0} can't change, because amounts of it have already been recorded")]
+    DecimalsInUse(Identifier),
+
+    #[error("amount out of range")]
+    Overflow,
+
+    #[error("database schema version {database} is newer than the latest version {supported} known to this program")]
+    SchemaTooNew
+    {
+        database: i64, supported: i64
+    },
+
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("storage backend unavailable: {0}")]
+    BackendUnavailable(#[source] BoxedError),
+
+    #[error("storage error: {0}")]
+    Storage(#[source] BoxedError),
+}
+
+impl From<sqlx::Error> for Error
+{
+    fn from(err: sqlx::Error) -> Self
+    {
+        match err
+        {
+            sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::WorkerCrashed =>
+            {
+                Error::BackendUnavailable(Box::new(err))
+            }
+            // Sums of amounts in SQL, e.g. while rebuilding balances ("numeric_value_out_of_range" in PostgreSQL)
+            sqlx::Error::Database(ref db_error)
+                if db_error.code().as_deref() == Some("22003") || db_error.message() == "integer overflow" =>
+            {
+                Error::Overflow
+            }
+            _ => Error::Storage(Box::new(err)),
+        }
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for Error
+{
+    fn from(err: sqlx::migrate::MigrateError) -> Self
+    {
+        match err
+        {
+            sqlx::migrate::MigrateError::Execute(err) => Error::from(err),
+            _ => Error::Storage(Box::new(err)),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error
+{
+    fn from(err: std::io::Error) -> Self
+    {
+        Error::Storage(Box::new(err))
+    }
+}
+
+/// How a user or token was referred to, e.g. in a lookup that failed (user and token IDs share the same type)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identifier
+{
+    Id(UserID),
+    Name(String),
+}
+
+impl Display for Identifier
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Identifier::Id(id) => write!(f, "#{id}"),
+            Identifier::Name(name) => write!(f, "\"{name}\""),
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use clap::Parser;
+use points_exchange_rs::cli::{
+    parse_import, parse_legs, Action, Args, ImportFormat, ImportRow, Listing, MigrateAction, NameOrId, OutputFormat,
+};
+use points_exchange_rs::core::{
+    DecimalAmount, HistoryCursor, Order, OverdraftPolicy, TokenEdit, UserQueryModeStrict, UserQueryModeWithCreation,
+};
+use points_exchange_rs::error::Error;
+
+#[test]
+fn name_or_id_distinguishes_ids_by_hash_prefix()
+{
+    assert_eq!("#12".parse(), Ok(NameOrId::Id(12)));
+    assert_eq!("12".parse(), Ok(NameOrId::Name("12".to_string())));
+    assert_eq!("alice".parse(), Ok(NameOrId::Name("alice".to_string())));
+    assert_eq!("#alice".parse(), Ok(NameOrId::Name("#alice".to_string())));
+}
+
+#[test]
+fn transaction_accepts_names_and_ids()
+{
+    let args = Args::try_parse_from(["cli_console", "tr", "alice", "#2", "kudos", "-3", "--create-missing"]).unwrap();
+    let Action::Transaction {
+        sender,
+        receiver,
+        token,
+        amount,
+        create_missing,
+        details,
+    } = args.command
+    else
+    {
+        panic!("expected a transaction");
+    };
+
+    assert_eq!(sender, NameOrId::Name("alice".to_string()));
+    assert_eq!(receiver, NameOrId::Id(2));
+    assert_eq!(token, NameOrId::Name("kudos".to_string()));
+    assert_eq!(amount, DecimalAmount::from(-3));
+    assert!(create_missing);
+    assert!(details.memo.is_none() && details.reference.is_none() && details.actor.is_none());
+
+    assert!(matches!(
+        sender.user_with_creation(true),
+        UserQueryModeWithCreation::ByNameOrCreate("alice")
+    ));
+    assert!(matches!(
+        sender.user_with_creation(false),
+        UserQueryModeWithCreation::ByName("alice")
+    ));
+    assert!(matches!(receiver.user_with_creation(true), UserQueryModeWithCreation::ById(2)));
+    assert!(matches!(receiver.user(), UserQueryModeStrict::ById(2)));
+}
+
+#[test]
+fn transaction_details_are_optional_flags()
+{
+    let args = Args::try_parse_from([
+        "cli_console",
+        "tr",
+        "alice",
+        "bob",
+        "kudos",
+        "5",
+        "--memo",
+        "thanks for the code review",
+        "--ref=PR-42",
+        "--as=#3",
+        "--idempotency-key=chat-1",
+    ])
+    .unwrap();
+    let Action::Transaction { details, .. } = args.command
+    else
+    {
+        panic!("expected a transaction");
+    };
+
+    let details = details.details();
+    assert_eq!(details.memo.as_deref(), Some("thanks for the code review"));
+    assert_eq!(details.reference.as_deref(), Some("PR-42"));
+    assert!(matches!(details.actor, Some(UserQueryModeStrict::ById(3))));
+    assert_eq!(details.idempotency_key.as_deref(), Some("chat-1"));
+}
+
+#[test]
+fn batch_legs_are_json_lines()
+{
+    let args = Args::try_parse_from(["cli_console", "tr-batch", "legs.jsonl", "--create-missing"]).unwrap();
+    assert!(matches!(args.command, Action::TrBatch { input: Some(input), create_missing: true } if input.ends_with("legs.jsonl")));
+    let args = Args::try_parse_from(["cli_console", "tr-batch"]).unwrap();
+    assert!(matches!(args.command, Action::TrBatch { input: None, .. }));
+
+    let text = r##"{"sender": "alice", "receiver": "#2", "token": "kudos", "amount": "2.5", "memo": "thanks", "ref": "PR-42"}
+
+{"sender": "alice", "receiver": "carol", "token": "#1", "amount": -3, "as": "#3", "idempotency_key": "chat-1"}
+"##;
+    let legs = parse_legs(text).unwrap();
+    assert_eq!(legs.len(), 2);
+    assert_eq!(legs[0].receiver, NameOrId::Id(2));
+    assert_eq!(legs[0].amount, "2.5".parse().unwrap());
+    assert_eq!(legs[0].details().reference.as_deref(), Some("PR-42"));
+    assert_eq!(legs[1].token, NameOrId::Id(1));
+    assert_eq!(legs[1].amount, DecimalAmount::from(-3));
+    assert!(matches!(legs[1].details().actor, Some(UserQueryModeStrict::ById(3))));
+
+    // Errors name the line
+    let result = parse_legs("\n{\"sender\": \"alice\", \"receiver\": \"bob\", \"token\": \"kudos\", \"amount\": \"2.x\"}");
+    assert!(matches!(result, Err(Error::InvalidArgument(message)) if message.starts_with("line 2:")));
+    let result = parse_legs(r#"{"sender": "alice", "receiver": "bob", "token": "kudos", "amount": 1, "memmo": "typo"}"#);
+    assert!(matches!(result, Err(Error::InvalidArgument(_))));
+}
+
+#[test]
+fn import_reads_csv_and_json_lines()
+{
+    let args = Args::try_parse_from(["cli_console", "import", "points.csv", "--dry-run"]).unwrap();
+    let Action::Import {
+        input,
+        input_format,
+        dry_run,
+    } = args.command
+    else
+    {
+        panic!("expected an import");
+    };
+    assert!(dry_run && input_format.is_none());
+    assert_eq!(ImportFormat::of(input.as_deref()), ImportFormat::Csv);
+    assert_eq!(ImportFormat::of(None), ImportFormat::Jsonl);
+
+    let csv = "type,name,owner,sender,receiver,token,amount,created_at,memo\r
+user,alice,,,,,,,\r
+\r
+token,kudos,carol,,,,,,\r
+transaction,,,alice,bob,kudos,2.5,2024-03-01,\"thanks, \"\"Bob\"\"\nsee you\"\r
+";
+    let rows = parse_import(csv, ImportFormat::Csv).unwrap();
+    assert_eq!(rows.iter().map(|(line, _)| *line).collect::<Vec<_>>(), [2, 4, 5]);
+    assert!(matches!(&rows[0].1, ImportRow::User { name } if name == "alice"));
+    assert!(matches!(&rows[1].1, ImportRow::Token { owner: Some(owner), .. } if owner == "carol"));
+    let ImportRow::Transaction {
+        amount, created_at, memo, ..
+    } = &rows[2].1
+    else
+    {
+        panic!("expected a transaction");
+    };
+    assert_eq!(*amount, "2.5".parse().unwrap());
+    assert_eq!(*created_at, Some("2024-03-01T00:00:00Z".parse().unwrap()));
+    assert_eq!(memo.as_deref(), Some("thanks, \"Bob\"\nsee you"));
+
+    let jsonl = r#"{"type": "transaction", "sender": "alice", "receiver": "bob", "token": "kudos", "amount": 3, "ref": "PR-42"}
+
+{"type": "user", "name": "bob"}"#;
+    let rows = parse_import(jsonl, ImportFormat::Jsonl).unwrap();
+    assert!(matches!(
+        &rows[0],
+        (
+            1,
+            ImportRow::Transaction {
+                created_at: None,
+                reference: Some(_),
+                ..
+            }
+        )
+    ));
+    assert!(matches!(&rows[1], (3, ImportRow::User { .. })));
+
+    // Errors name the line
+    for (text, format, line) in [
+        ("type,name\nuser,alice\nuser,bob,carol\n", ImportFormat::Csv, "line 3:"),
+        ("type,name\nuser,alice\nuser,\"bob\n", ImportFormat::Csv, "line 3:"),
+        ("type,name,amount\nuser,alice,\nuser,bob,3\n", ImportFormat::Csv, "line 3:"),
+        (
+            r#"{"type": "user", "name": "alice"}
+{"type": "group", "name": "admins"}"#,
+            ImportFormat::Jsonl,
+            "line 2:",
+        ),
+    ]
+    {
+        let result = parse_import(text, format);
+        assert!(matches!(result, Err(Error::InvalidArgument(message)) if message.starts_with(line)));
+    }
+}
+
+#[test]
+fn migrate_takes_an_action()
+{
+    let action = |name| match Args::try_parse_from(["cli_console", "migrate", name]).unwrap().command
+    {
+        Action::Migrate { action } => action,
+        _ => panic!("expected a migration"),
+    };
+    assert!(matches!(action("status"), MigrateAction::Status));
+    assert!(matches!(action("up"), MigrateAction::Up));
+    assert!(matches!(action("down"), MigrateAction::Down));
+
+    assert!(Args::try_parse_from(["cli_console", "migrate"]).is_err());
+    assert!(Args::try_parse_from(["cli_console", "migrate", "sideways"]).is_err());
+}
+
+#[test]
+fn reverse_requires_a_reason()
+{
+    let args = Args::try_parse_from(["cli_console", "reverse", "17", "--reason=extra zero"]).unwrap();
+    assert!(matches!(args.command, Action::Reverse { id: 17, ref reason } if reason == "extra zero"));
+
+    assert!(Args::try_parse_from(["cli_console", "reverse", "17"]).is_err());
+    assert!(Args::try_parse_from(["cli_console", "reverse", "#17", "--reason=typo"]).is_err());
+}
+
+#[test]
+fn history_parses_times_amounts_and_cursors()
+{
+    let args = Args::try_parse_from([
+        "cli_console",
+        "history",
+        "--receiver=bob",
+        "--min-amount=-1.5",
+        "--since=2025-04-14",
+        "--until=2025-04-21T09:30:00+02:00",
+        "--after=17@2025-04-15T10:00:00.123Z",
+        "-o",
+        "asc",
+    ])
+    .unwrap();
+    let Action::History { query } = args.command
+    else
+    {
+        panic!("expected a history query");
+    };
+
+    assert_eq!(query.receiver, Some(NameOrId::Name("bob".to_string())));
+    assert_eq!(query.min_amount, Some(DecimalAmount { digits: -15, scale: 1 }));
+    assert_eq!(query.since.unwrap().to_rfc3339(), "2025-04-14T00:00:00+00:00");
+    assert_eq!(query.until.unwrap().to_rfc3339(), "2025-04-21T07:30:00+00:00");
+    assert_eq!(query.order, Order::Asc);
+    assert_eq!(query.limit, 50);
+
+    let cursor = query.after.unwrap();
+    assert_eq!(cursor.id, 17);
+    assert_eq!(cursor.to_string(), "17@2025-04-15T10:00:00.123Z");
+    assert_eq!("17".parse::<HistoryCursor>().unwrap().created_at, None);
+
+    assert!(Args::try_parse_from(["cli_console", "history", "--since=last week"]).is_err());
+    assert!(Args::try_parse_from(["cli_console", "history", "--after=@2025-04-15T10:00:00Z"]).is_err());
+}
+
+#[test]
+fn fractional_amounts_are_scaled_to_the_token()
+{
+    let args = Args::try_parse_from(["cli_console", "tr", "alice", "bob", "coffee", "-2.25"]).unwrap();
+    let Action::Transaction { amount, .. } = args.command
+    else
+    {
+        panic!("expected a transaction");
+    };
+    assert_eq!(amount, DecimalAmount { digits: -225, scale: 2 });
+    assert_eq!(amount.scaled(2).unwrap(), -225);
+    assert_eq!(amount.scaled(4).unwrap(), -22500);
+    assert!(matches!(amount.scaled(1), Err(Error::InvalidArgument(_))));
+    assert!(matches!(amount.scaled(0), Err(Error::InvalidArgument(_))));
+
+    // Trailing zeros add no precision
+    let amount: DecimalAmount = "2.50".parse().unwrap();
+    assert_eq!(amount.scaled(1).unwrap(), 25);
+    assert_eq!(amount.to_string(), "2.5");
+    assert_eq!("7".parse::<DecimalAmount>().unwrap().scaled(0).unwrap(), 7);
+    assert_eq!("0.001".parse::<DecimalAmount>().unwrap().scaled(3).unwrap(), 1);
+
+    assert!(matches!(
+        "9223372036854775807".parse::<DecimalAmount>().unwrap().scaled(1),
+        Err(Error::Overflow)
+    ));
+    for invalid in [
+        "",
+        "-",
+        "2.",
+        ".5",
+        "2,5",
+        "1e3",
+        "2.2.5",
+        "0.0000000000000000001",
+        "99999999999999999999",
+    ]
+    {
+        assert!(invalid.parse::<DecimalAmount>().is_err(), "{invalid}");
+    }
+}
+
+fn sample_listing() -> Listing
+{
+    let mut listing = Listing::new(&["sender", "amount"]);
+    listing.push(vec!["bob, \"the builder\"".into(), 12.into()]);
+    listing.push(vec!["tab\tline\nend".into(), (-3).into()]);
+    listing
+}
+
+fn render(listing: &Listing, format: OutputFormat, header: bool) -> String
+{
+    let mut out = Vec::new();
+    listing.write(&mut out, format, header).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn listing_writes_machine_readable_formats()
+{
+    let listing = sample_listing();
+
+    assert_eq!(
+        render(&listing, OutputFormat::Csv, true),
+        "sender,amount\n\"bob, \"\"the builder\"\"\",12\n\"tab\tline\nend\",-3\n"
+    );
+    assert_eq!(
+        render(&listing, OutputFormat::Tsv, true),
+        "sender\tamount\nbob, \"the builder\"\t12\ntab\\tline\\nend\t-3\n"
+    );
+    assert_eq!(
+        render(&listing, OutputFormat::Jsonl, true),
+        "{\"sender\":\"bob, \\\"the builder\\\"\",\"amount\":12}\n{\"sender\":\"tab\\tline\\nend\",\"amount\":-3}\n"
+    );
+
+    let json: serde_json::Value = serde_json::from_str(&render(&listing, OutputFormat::Json, true)).unwrap();
+    assert_eq!(json[1]["amount"], -3);
+}
+
+#[test]
+fn listing_omits_header_on_request()
+{
+    let listing = sample_listing();
+
+    assert!(!render(&listing, OutputFormat::Csv, false).starts_with("sender"));
+    assert!(!render(&listing, OutputFormat::Table, false).contains("amount"));
+    assert!(render(&listing, OutputFormat::Table, true).starts_with("sender"));
+}
+
+#[test]
+fn format_is_a_global_option()
+{
+    let args = Args::try_parse_from(["cli_console", "user-list", "--format", "csv", "--no-header"]).unwrap();
+
+    assert_eq!(args.format, OutputFormat::Csv);
+    assert!(args.no_header);
+}
+
+#[test]
+fn overdraft_policy_round_trips_through_its_name()
+{
+    for policy in [
+        OverdraftPolicy::Free,
+        OverdraftPolicy::BalanceBacked,
+        OverdraftPolicy::CreditLimit(20),
+    ]
+    {
+        assert_eq!(policy.to_string().parse(), Ok(policy));
+    }
+    assert_eq!("credit-limit=5".parse(), Ok(OverdraftPolicy::CreditLimit(5)));
+    assert!("credit-limit(-5)".parse::<OverdraftPolicy>().is_err());
+    assert!("unlimited".parse::<OverdraftPolicy>().is_err());
+}
+
+#[test]
+fn changing_the_overdraft_policy_requires_the_owner()
+{
+    let args = Args::try_parse_from(["cli_console", "token-policy", "kudos", "balance-backed", "--as=alice"]).unwrap();
+    assert!(matches!(
+        args.command,
+        Action::TokenPolicy {
+            policy: Some(OverdraftPolicy::BalanceBacked),
+            actor: Some(NameOrId::Name(ref actor)),
+            ..
+        } if actor == "alice"
+    ));
+
+    assert!(Args::try_parse_from(["cli_console", "token-policy", "kudos"]).is_ok());
+    assert!(Args::try_parse_from(["cli_console", "token-policy", "kudos", "balance-backed"]).is_err());
+}
+
+#[test]
+fn token_edit_collects_changes()
+{
+    let args = Args::try_parse_from([
+        "cli_console",
+        "token-edit",
+        "kudos",
+        "--as",
+        "alice",
+        "--symbol",
+        "KDS",
+        "--description",
+        "",
+        "--set",
+        "color=gold=ish",
+        "--unset",
+        "icon",
+    ])
+    .unwrap();
+    let Action::TokenEdit { token, actor, edit } = args.command
+    else
+    {
+        panic!("expected token-edit");
+    };
+    let edit = TokenEdit::from(edit);
+
+    assert_eq!(token, NameOrId::Name("kudos".to_string()));
+    assert_eq!(actor, NameOrId::Name("alice".to_string()));
+    assert_eq!(edit.symbol, Some(Some("KDS".to_string())));
+    assert_eq!(edit.description, Some(None));
+    assert_eq!(edit.decimals, None);
+    assert_eq!(
+        edit.metadata,
+        [("color".to_string(), Some("gold=ish".to_string())), ("icon".to_string(), None)]
+    );
+
+    assert!(Args::try_parse_from(["cli_console", "token-edit", "kudos", "--as", "alice", "--set", "color"]).is_err());
+}
+
+#[test]
+fn formatted_amounts_are_right_aligned()
+{
+    let mut listing = Listing::new(&["sender", "amount"]);
+    listing.push(vec!["alice".into(), "12.50 KDS".into()]);
+    listing.push(vec!["bob".into(), "-3.00 KDS".into()]);
+
+    assert_eq!(
+        render(&listing, OutputFormat::Table, true),
+        "sender     amount\nalice   12.50 KDS\nbob     -3.00 KDS\n"
+    );
+}
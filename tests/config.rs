@@ -0,0 +1,34 @@
+use points_exchange_rs::core::{CoreConfig, JournalMode, DATABASE_URL_ENV};
+use points_exchange_rs::error::Error;
+
+/// Single test, because the environment variable is shared by all threads of the test binary
+#[test]
+fn config_file_is_read_and_overridden_by_the_environment()
+{
+    std::env::remove_var(DATABASE_URL_ENV);
+    let path = std::env::temp_dir().join(format!("points_exchange_test_{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        "database_url = \"file:/tmp/ledger.jsonl\"\njournal_mode = \"delete\"\n\n[names]\ncase_insensitive = false\n",
+    )
+    .unwrap();
+
+    // Keys missing in the file keep their defaults
+    let config = CoreConfig::load(Some(&path)).unwrap();
+    assert_eq!(config.database_url, "file:/tmp/ledger.jsonl");
+    assert_eq!(config.journal_mode, JournalMode::Delete);
+    assert!(!config.names.case_insensitive);
+    assert_eq!(config.max_connections, CoreConfig::default().max_connections);
+    assert_eq!(CoreConfig::load(None).unwrap(), CoreConfig::default());
+
+    // The environment variable wins over the file and the defaults
+    std::env::set_var(DATABASE_URL_ENV, "sqlite://other.db");
+    assert_eq!(CoreConfig::load(Some(&path)).unwrap().database_url, "sqlite://other.db");
+    assert_eq!(CoreConfig::load(None).unwrap().database_url, "sqlite://other.db");
+    std::env::remove_var(DATABASE_URL_ENV);
+
+    std::fs::write(&path, "database_url = \"sqlite://var/data.db\"\nunknown_key = 1\n").unwrap();
+    assert!(matches!(CoreConfig::load(Some(&path)), Err(Error::Config(_))));
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(CoreConfig::load(Some(&path)), Err(Error::Config(_))));
+}
@@ -0,0 +1,1969 @@
+use points_exchange_rs::core::{persistance_layer::*, *};
+use points_exchange_rs::error::{Error, Identifier};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Base URL of a throw-away PostgreSQL server, e.g. "postgres://postgres@localhost:5432".
+/// The backend independent tests will run against PostgreSQL as well, if this variable is set.
+const TEST_POSTGRES_URL: &str = "TEST_POSTGRES_URL";
+
+static DATABASE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+async fn core() -> Core
+{
+    Core::new_in_memory().await.expect("in-memory database")
+}
+
+/// Path of a JSON-lines file, that doesn't exist yet
+fn fresh_file() -> std::path::PathBuf
+{
+    let path = std::env::temp_dir().join(format!(
+        "points_exchange_test_{}_{}.jsonl",
+        std::process::id(),
+        DATABASE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::remove_file(&path).ok();
+    path
+}
+
+async fn file_core(path: &std::path::Path) -> Result<Core, Error>
+{
+    let config = CoreConfig {
+        database_url: format!("file:{}", path.display()),
+        ..CoreConfig::default()
+    };
+    Core::new(&config).await
+}
+
+/// Configs of persistent databases for every available backend, so they can be opened several times
+fn persistent_configs() -> Vec<CoreConfig>
+{
+    let sqlite = fresh_file().with_extension("db");
+    let mut configs = vec![
+        CoreConfig {
+            database_url: format!("sqlite://{}", sqlite.display()),
+            ..CoreConfig::default()
+        },
+        CoreConfig {
+            database_url: format!("file:{}", fresh_file().display()),
+            ..CoreConfig::default()
+        },
+    ];
+
+    if let Ok(base_url) = std::env::var(TEST_POSTGRES_URL)
+    {
+        let database = format!(
+            "points_exchange_test_{}_{}",
+            std::process::id(),
+            DATABASE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        configs.push(CoreConfig {
+            database_url: format!("{}/{database}", base_url.trim_end_matches('/')),
+            ..CoreConfig::default()
+        });
+    }
+
+    configs
+}
+
+/// A fresh core for every available backend
+async fn cores() -> Vec<Core>
+{
+    let mut cores = vec![core().await, file_core(&fresh_file()).await.expect("JSON-lines file")];
+
+    if let Ok(base_url) = std::env::var(TEST_POSTGRES_URL)
+    {
+        let database = format!(
+            "points_exchange_test_{}_{}",
+            std::process::id(),
+            DATABASE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let config = CoreConfig {
+            database_url: format!("{}/{database}", base_url.trim_end_matches('/')),
+            ..CoreConfig::default()
+        };
+        cores.push(Core::new(&config).await.expect("PostgreSQL database"));
+    }
+
+    cores
+}
+
+async fn send(core: &Core, sender: &str, receiver: &str, token: &str, amount: TokenAmount) -> TokenAmount
+{
+    core.transaction(
+        UserQueryModeWithCreation::ByNameOrCreate(sender),
+        UserQueryModeWithCreation::ByNameOrCreate(receiver),
+        TokenQueryModeWithCreation::ByNameOrCreate(token),
+        amount,
+    )
+    .await
+    .expect("transaction")
+}
+
+#[tokio::test]
+async fn transaction_returns_running_total()
+{
+    for core in cores().await
+    {
+        // Without an owner, anybody may send points, that they don't have
+        core.create_token("kudos", None).await.unwrap();
+        assert_eq!(send(&core, "alice", "bob", "kudos", 3).await, 3);
+        assert_eq!(send(&core, "alice", "bob", "kudos", 4).await, 7);
+        assert_eq!(send(&core, "alice", "bob", "kudos", -2).await, 5);
+
+        // Totals are kept per (sender, receiver, token)
+        assert_eq!(send(&core, "carol", "bob", "kudos", 1).await, 1);
+        assert_eq!(send(&core, "alice", "bob", "coffee", 1).await, 1);
+
+        let total = core
+            .get_current_total(
+                UserQueryModeStrict::ByName("alice"),
+                UserQueryModeStrict::ByName("bob"),
+                TokenQueryModeStrict::ByName("kudos"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(total, Some(5));
+    }
+}
+
+#[tokio::test]
+async fn concurrent_transactions_see_distinct_totals()
+{
+    for core in cores().await
+    {
+        send(&core, "alice", "bob", "kudos", 0).await;
+
+        let (a, b, c, d) = tokio::join!(
+            send(&core, "alice", "bob", "kudos", 1),
+            send(&core, "alice", "bob", "kudos", 1),
+            send(&core, "alice", "bob", "kudos", 1),
+            send(&core, "alice", "bob", "kudos", 1),
+        );
+
+        let mut totals = [a, b, c, d];
+        totals.sort_unstable();
+        assert_eq!(totals, [1, 2, 3, 4]);
+    }
+}
+
+async fn try_send(core: &Core, sender: &str, receiver: &str, token: &str, amount: TokenAmount) -> Result<TokenAmount, Error>
+{
+    core.transaction(
+        UserQueryModeWithCreation::ByNameOrCreate(sender),
+        UserQueryModeWithCreation::ByNameOrCreate(receiver),
+        TokenQueryModeWithCreation::ByNameOrCreate(token),
+        amount,
+    )
+    .await
+}
+
+async fn balance(core: &Core, user: &str, token: &str) -> TokenAmount
+{
+    core.get_balance(UserQueryModeStrict::ByName(user), TokenQueryModeStrict::ByName(token))
+        .await
+        .expect("balance")
+}
+
+#[tokio::test]
+async fn overdraft_policy_limits_what_senders_can_give()
+{
+    for core in cores().await
+    {
+        let kudos = TokenQueryModeStrict::ByName;
+        send(&core, "alice", "bob", "kudos", 10).await;
+        assert_eq!(core.overdraft_policy(kudos("kudos")).await.unwrap(), OverdraftPolicy::Free);
+
+        core.set_overdraft_policy(UserQueryModeStrict::ByName("alice"), kudos("kudos"), OverdraftPolicy::BalanceBacked)
+            .await
+            .unwrap();
+        assert_eq!(core.overdraft_policy(kudos("kudos")).await.unwrap(), OverdraftPolicy::BalanceBacked);
+
+        assert_eq!(send(&core, "bob", "carol", "kudos", 4).await, 4);
+        let result = try_send(&core, "bob", "carol", "kudos", 7).await;
+        assert!(matches!(
+            result,
+            Err(Error::InsufficientBalance {
+                available: 6,
+                required:  7,
+            })
+        ));
+        assert!(matches!(
+            try_send(&core, "alice", "bob", "kudos", 1).await,
+            Err(Error::InsufficientBalance { .. })
+        ));
+        assert!(matches!(
+            try_send(&core, "dave", "bob", "kudos", 1).await,
+            Err(Error::InsufficientBalance { .. })
+        ));
+
+        // Taking points back debits the receiver
+        assert_eq!(send(&core, "bob", "carol", "kudos", -4).await, 0);
+        let result = try_send(&core, "alice", "bob", "kudos", -11).await;
+        assert!(matches!(
+            result,
+            Err(Error::InsufficientBalance {
+                available: 10,
+                required:  11,
+            })
+        ));
+
+        // Failed transactions leave no trace, not even the users they would have created
+        assert_eq!(balance(&core, "alice", "kudos").await, -10);
+        assert_eq!(balance(&core, "bob", "kudos").await, 10);
+        assert_eq!(balance(&core, "carol", "kudos").await, 0);
+        assert_eq!(core.query_all_users().await.unwrap().len(), 3);
+
+        core.set_overdraft_policy(
+            UserQueryModeStrict::ByName("alice"),
+            kudos("kudos"),
+            OverdraftPolicy::CreditLimit(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(send(&core, "carol", "bob", "kudos", 5).await, 5);
+        let result = try_send(&core, "carol", "bob", "kudos", 1).await;
+        assert!(matches!(
+            result,
+            Err(Error::InsufficientBalance {
+                available: 0,
+                required:  1,
+            })
+        ));
+
+        // Other tokens are unaffected
+        assert_eq!(send(&core, "carol", "bob", "coffee", 1).await, 1);
+    }
+}
+
+#[tokio::test]
+async fn only_the_owner_and_minters_create_points_of_an_owned_token()
+{
+    for core in cores().await
+    {
+        let (user, kudos) = (UserQueryModeStrict::ByName, TokenQueryModeStrict::ByName("kudos"));
+        send(&core, "alice", "bob", "kudos", 10).await;
+
+        let result = core
+            .set_overdraft_policy(user("bob"), kudos, OverdraftPolicy::CreditLimit(1000))
+            .await;
+        assert!(matches!(result, Err(Error::NotAuthorized { .. })));
+        assert_eq!(core.overdraft_policy(kudos).await.unwrap(), OverdraftPolicy::Free);
+
+        // The free policy only lets the owner overdraw, everybody else needs the points
+        let result = try_send(&core, "bob", "carol", "kudos", 11).await;
+        assert!(matches!(
+            result,
+            Err(Error::InsufficientBalance {
+                available: 10,
+                required:  11,
+            })
+        ));
+        assert!(matches!(
+            try_send(&core, "carol", "bob", "kudos", 1).await,
+            Err(Error::InsufficientBalance { .. })
+        ));
+        assert!(matches!(
+            try_send(&core, "alice", "bob", "kudos", -11).await,
+            Err(Error::InsufficientBalance { .. })
+        ));
+        assert_eq!(send(&core, "bob", "carol", "kudos", 10).await, 10);
+        assert_eq!(send(&core, "alice", "carol", "kudos", 5).await, 5);
+        assert_eq!(balance(&core, "alice", "kudos").await, -15);
+
+        core.grant_minter(user("alice"), kudos, user("carol")).await.unwrap();
+        assert_eq!(send(&core, "carol", "dave", "kudos", 20).await, 20);
+        assert_eq!(balance(&core, "carol", "kudos").await, -5);
+
+        // Tokens without an owner stay free for everybody
+        core.create_token("tea", None).await.unwrap();
+        assert_eq!(send(&core, "bob", "carol", "tea", 3).await, 3);
+    }
+}
+
+#[tokio::test]
+async fn concurrent_transactions_cannot_overdraw_balance()
+{
+    for core in cores().await
+    {
+        send(&core, "alice", "bob", "kudos", 3).await;
+        core.set_overdraft_policy(
+            UserQueryModeStrict::ByName("alice"),
+            TokenQueryModeStrict::ByName("kudos"),
+            OverdraftPolicy::BalanceBacked,
+        )
+        .await
+        .unwrap();
+
+        let results = tokio::join!(
+            try_send(&core, "bob", "carol", "kudos", 1),
+            try_send(&core, "bob", "carol", "kudos", 1),
+            try_send(&core, "bob", "carol", "kudos", 1),
+            try_send(&core, "bob", "carol", "kudos", 1),
+        );
+
+        let results = [results.0, results.1, results.2, results.3];
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 3);
+        assert_eq!(balance(&core, "bob", "kudos").await, 0);
+    }
+}
+
+#[tokio::test]
+async fn concurrent_transactions_in_opposite_directions_both_succeed()
+{
+    for core in cores().await
+    {
+        send(&core, "alice", "bob", "kudos", 0).await;
+        core.set_overdraft_policy(
+            UserQueryModeStrict::ByName("alice"),
+            TokenQueryModeStrict::ByName("kudos"),
+            OverdraftPolicy::CreditLimit(100),
+        )
+        .await
+        .unwrap();
+
+        for _ in 0..10
+        {
+            let (forth, back) = tokio::join!(
+                try_send(&core, "alice", "bob", "kudos", 1),
+                try_send(&core, "bob", "alice", "kudos", 1)
+            );
+            forth.unwrap();
+            back.unwrap();
+        }
+        assert_eq!(balance(&core, "alice", "kudos").await, 0);
+    }
+}
+
+#[tokio::test]
+async fn overdraft_policy_is_persisted()
+{
+    for config in persistent_configs()
+    {
+        {
+            let core = Core::new(&config).await.unwrap();
+            send(&core, "alice", "bob", "kudos", 1).await;
+            core.set_overdraft_policy(
+                UserQueryModeStrict::ByName("alice"),
+                TokenQueryModeStrict::ByName("kudos"),
+                OverdraftPolicy::CreditLimit(2),
+            )
+            .await
+            .unwrap();
+        }
+
+        let core = Core::new(&config).await.unwrap();
+        core.dedupe(false).await.unwrap();
+        let policy = core.overdraft_policy(TokenQueryModeStrict::ByName("kudos")).await.unwrap();
+        assert_eq!(policy, OverdraftPolicy::CreditLimit(2));
+        assert!(try_send(&core, "alice", "bob", "kudos", 2).await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn amounts_are_64_bit_and_never_overflow()
+{
+    for core in cores().await
+    {
+        core.create_token("kudos", None).await.unwrap();
+        assert_eq!(send(&core, "alice", "bob", "kudos", 3_000_000_000).await, 3_000_000_000);
+        assert_eq!(send(&core, "alice", "bob", "kudos", 3_000_000_000).await, 6_000_000_000);
+        assert_eq!(balance(&core, "alice", "kudos").await, -6_000_000_000);
+
+        // The running total of a relation
+        assert!(matches!(
+            try_send(&core, "alice", "bob", "kudos", TokenAmount::MAX).await,
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(
+            try_send(&core, "alice", "bob", "kudos", TokenAmount::MIN).await,
+            Err(Error::Overflow)
+        ));
+        assert_eq!(balance(&core, "bob", "kudos").await, 6_000_000_000);
+
+        // The sum of several relations
+        assert_eq!(send(&core, "carol", "bob", "kudos", TokenAmount::MAX).await, TokenAmount::MAX);
+        let result = core
+            .get_balance(UserQueryModeStrict::ByName("bob"), TokenQueryModeStrict::ByName("kudos"))
+            .await;
+        assert!(matches!(result, Err(Error::Overflow)));
+        core.set_overdraft_policy(
+            UserQueryModeStrict::ByName("alice"),
+            TokenQueryModeStrict::ByName("kudos"),
+            OverdraftPolicy::BalanceBacked,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(try_send(&core, "bob", "alice", "kudos", 1).await, Err(Error::Overflow)));
+
+        // Sending back brings the balance into range again
+        core.set_overdraft_policy(
+            UserQueryModeStrict::ByName("alice"),
+            TokenQueryModeStrict::ByName("kudos"),
+            OverdraftPolicy::Free,
+        )
+        .await
+        .unwrap();
+        assert_eq!(send(&core, "bob", "carol", "kudos", 6_000_000_000).await, 6_000_000_000);
+        assert_eq!(balance(&core, "bob", "kudos").await, TokenAmount::MAX);
+    }
+}
+
+#[tokio::test]
+async fn tokens_are_owned_by_their_issuer()
+{
+    for core in cores().await
+    {
+        let user = UserQueryModeStrict::ByName;
+        let token = TokenQueryModeStrict::ByName;
+
+        core.create_user("alice").await.unwrap();
+        let coffee = core.create_token("coffee", Some(user("alice"))).await.unwrap();
+        assert_eq!(coffee.owner_name.as_deref(), Some("alice"));
+        let tea = core.create_token("tea", None).await.unwrap();
+        assert_eq!(tea.owner_id, None);
+
+        // Implicitly created tokens belong to the first sender
+        send(&core, "bob", "carol", "kudos", 1).await;
+        let kudos = core.query_token("kudos", NameMatch::Exact).await.unwrap();
+        assert_eq!(kudos[0].owner_name.as_deref(), Some("bob"));
+
+        // Unowned tokens can be claimed by anybody, owned ones only handed over by their owner
+        core.set_token_owner(user("carol"), token("tea"), user("carol")).await.unwrap();
+        let result = core.set_token_owner(user("alice"), token("tea"), user("alice")).await;
+        assert!(matches!(result, Err(Error::NotAuthorized { .. })));
+        core.set_token_owner(user("carol"), token("tea"), user("alice")).await.unwrap();
+
+        let owners: Vec<(String, Option<String>)> = core
+            .query_all_tokens()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|token| (token.name, token.owner_name))
+            .collect();
+        assert_eq!(
+            owners,
+            [
+                ("coffee".to_string(), Some("alice".to_string())),
+                ("tea".to_string(), Some("alice".to_string())),
+                ("kudos".to_string(), Some("bob".to_string())),
+            ]
+        );
+    }
+}
+
+#[tokio::test]
+async fn only_owner_and_minters_can_mint_and_burn()
+{
+    for core in cores().await
+    {
+        let user = UserQueryModeStrict::ByName;
+        let token = TokenQueryModeStrict::ByName;
+
+        for name in ["alice", "bob", "carol"]
+        {
+            core.create_user(name).await.unwrap();
+        }
+        core.create_token("kudos", Some(user("alice"))).await.unwrap();
+        core.set_overdraft_policy(user("alice"), token("kudos"), OverdraftPolicy::BalanceBacked)
+            .await
+            .unwrap();
+
+        // Minting isn't limited by the owner's balance
+        assert_eq!(core.mint(user("alice"), user("carol"), token("kudos"), 10).await.unwrap(), 10);
+        let result = core.mint(user("bob"), user("carol"), token("kudos"), 10).await;
+        assert!(matches!(result, Err(Error::NotAuthorized { .. })));
+
+        let result = core.grant_minter(user("bob"), token("kudos"), user("bob")).await;
+        assert!(matches!(result, Err(Error::NotAuthorized { .. })));
+        core.grant_minter(user("alice"), token("kudos"), user("bob")).await.unwrap();
+        let minters: Vec<String> = core
+            .query_minters(token("kudos"))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.name)
+            .collect();
+        assert_eq!(minters, ["bob"]);
+
+        // Minters may issue, but not delegate
+        assert_eq!(core.mint(user("bob"), user("carol"), token("kudos"), 5).await.unwrap(), 15);
+        let result = core.grant_minter(user("bob"), token("kudos"), user("carol")).await;
+        assert!(matches!(result, Err(Error::NotAuthorized { .. })));
+
+        // Burning can't take more than the holder has
+        assert_eq!(core.burn(user("bob"), user("carol"), token("kudos"), 6).await.unwrap(), 9);
+        let result = core.burn(user("bob"), user("carol"), token("kudos"), 10).await;
+        assert!(matches!(
+            result,
+            Err(Error::InsufficientBalance {
+                available: 9,
+                required:  10,
+            })
+        ));
+
+        core.revoke_minter(user("alice"), token("kudos"), user("bob")).await.unwrap();
+        assert!(core.query_minters(token("kudos")).await.unwrap().is_empty());
+        let result = core.burn(user("bob"), user("carol"), token("kudos"), 1).await;
+        assert!(matches!(result, Err(Error::NotAuthorized { .. })));
+        assert_eq!(balance(&core, "carol", "kudos").await, 9);
+    }
+}
+
+#[tokio::test]
+async fn issuers_cannot_mint_or_burn_their_own_points()
+{
+    for core in cores().await
+    {
+        let (user, kudos) = (UserQueryModeStrict::ByName, TokenQueryModeStrict::ByName("kudos"));
+        let alice = core.create_user("alice").await.unwrap();
+        core.create_token("kudos", Some(user("alice"))).await.unwrap();
+
+        let result = core.mint(user("alice"), UserQueryModeStrict::ById(alice.id), kudos, 5).await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        let result = core.burn(user("alice"), user("alice"), kudos, 5).await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        assert!(history(&core, &HistoryFilter::default()).await.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn token_rights_are_persisted()
+{
+    for config in persistent_configs()
+    {
+        let user = UserQueryModeStrict::ByName;
+        let token = TokenQueryModeStrict::ByName;
+        {
+            let core = Core::new(&config).await.unwrap();
+            send(&core, "alice", "bob", "kudos", 1).await;
+            core.create_user("carol").await.unwrap();
+            core.grant_minter(user("alice"), token("kudos"), user("bob")).await.unwrap();
+        }
+
+        let core = Core::new(&config).await.unwrap();
+        core.dedupe(false).await.unwrap();
+        let kudos = core.query_token("kudos", NameMatch::Exact).await.unwrap();
+        assert_eq!(kudos[0].owner_name.as_deref(), Some("alice"));
+        assert_eq!(core.mint(user("bob"), user("carol"), token("kudos"), 2).await.unwrap(), 2);
+    }
+}
+
+#[tokio::test]
+async fn token_details_can_be_edited_by_the_owner()
+{
+    for mut core in cores().await
+    {
+        let user = UserQueryModeStrict::ByName;
+        let token = TokenQueryModeStrict::ByName;
+
+        core.create_user("alice").await.unwrap();
+        core.create_token("kudos", Some(user("alice"))).await.unwrap();
+        let edit = TokenEdit {
+            description: Some(Some("Thanks for the help".into())),
+            symbol:      Some(Some("KDS".into())),
+            decimals:    Some(2),
+            metadata:    vec![("color".into(), Some("gold".into())), ("icon".into(), Some("star.png".into()))],
+        };
+        let kudos = core.edit_token(user("alice"), token("kudos"), &edit).await.unwrap();
+        assert_eq!(kudos.symbol.as_deref(), Some("KDS"));
+        assert_eq!(kudos.format_amount(1250), "12.50 KDS");
+        send(&core, "alice", "bob", "kudos", 1250).await;
+
+        // Recorded amounts would change their meaning with the decimals
+        let rescale = TokenEdit {
+            decimals: Some(3),
+            ..TokenEdit::default()
+        };
+        let result = core.edit_token(user("alice"), token("kudos"), &rescale).await;
+        assert!(matches!(result, Err(Error::DecimalsInUse(Identifier::Id(_)))));
+        let same = TokenEdit {
+            decimals: Some(2),
+            ..TokenEdit::default()
+        };
+        core.edit_token(user("alice"), token("kudos"), &same).await.unwrap();
+
+        let result = core.edit_token(user("bob"), token("kudos"), &TokenEdit::default()).await;
+        assert!(matches!(result, Err(Error::NotAuthorized { .. })));
+        let invalid = TokenEdit {
+            decimals: Some(19),
+            ..TokenEdit::default()
+        };
+        let result = core.edit_token(user("alice"), token("kudos"), &invalid).await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+
+        // Unchanged fields stay as they are
+        let edit = TokenEdit {
+            description: Some(None),
+            metadata: vec![("icon".into(), None), ("color".into(), Some("red".into()))],
+            ..TokenEdit::default()
+        };
+        core.edit_token(user("alice"), token("kudos"), &edit).await.unwrap();
+
+        let kudos = core.query_token("kudos", NameMatch::Exact).await.unwrap().remove(0);
+        assert_eq!(kudos.description, None);
+        assert_eq!((kudos.symbol.as_deref(), kudos.decimals), (Some("KDS"), 2));
+        assert_eq!(
+            kudos.metadata.into_iter().collect::<Vec<_>>(),
+            [("color".to_string(), "red".to_string())]
+        );
+        assert_eq!(core.query_all_tokens().await.unwrap()[0].metadata.len(), 1);
+        assert_eq!(core.get_token(token("kudos")).await.unwrap().metadata["color"], "red");
+
+        let by_user = core.list_tokens_by_user(user("bob"), Order::Desc, None).await.unwrap();
+        assert_eq!(by_user[0].token.symbol.as_deref(), Some("KDS"));
+    }
+}
+
+#[tokio::test]
+async fn token_details_are_persisted_and_merged()
+{
+    for config in persistent_configs()
+    {
+        let case_sensitive = CoreConfig {
+            names: NameNormalization {
+                case_insensitive: false,
+                ..NameNormalization::default()
+            },
+            ..config.clone()
+        };
+        {
+            let core = Core::new(&case_sensitive).await.unwrap();
+            let user = UserQueryModeStrict::ByName;
+            send(&core, "alice", "bob", "kudos", 1).await;
+            send(&core, "alice", "bob", "Kudos", 1).await;
+            let edit = |symbol: &str, key: &str| TokenEdit {
+                symbol: Some(Some(symbol.into())),
+                metadata: vec![(key.into(), Some(symbol.into()))],
+                ..TokenEdit::default()
+            };
+            core.edit_token(user("alice"), TokenQueryModeStrict::ByName("kudos"), &edit("KDS", "a"))
+                .await
+                .unwrap();
+            core.edit_token(user("alice"), TokenQueryModeStrict::ByName("Kudos"), &edit("K", "b"))
+                .await
+                .unwrap();
+        }
+
+        let core = Core::new(&config).await.unwrap();
+        core.dedupe(false).await.unwrap();
+        let tokens = core.query_all_tokens().await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].symbol.as_deref(), Some("KDS"));
+        let metadata: Vec<(&str, &str)> = tokens[0]
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(metadata, [("a", "KDS"), ("b", "K")]);
+    }
+}
+
+#[test]
+fn amounts_are_formatted_with_decimals_and_symbol()
+{
+    let mut token = Token {
+        id:          1,
+        name:        "kudos".into(),
+        owner_id:    None,
+        owner_name:  None,
+        description: None,
+        symbol:      None,
+        decimals:    0,
+        metadata:    Default::default(),
+    };
+    assert_eq!(token.format_amount(-1250), "-1250");
+
+    token.decimals = 2;
+    assert_eq!(token.format_amount(1250), "12.50");
+    assert_eq!(token.format_amount(-5), "-0.05");
+
+    token.symbol = Some("⭐".into());
+    assert_eq!(token.format_amount(i64::MIN), "-92233720368547758.08 ⭐");
+}
+
+#[tokio::test]
+async fn transactions_keep_timestamp_memo_reference_and_actor()
+{
+    for config in persistent_configs()
+    {
+        let before = chrono::Utc::now();
+        {
+            let core = Core::new(&config).await.unwrap();
+            send(&core, "alice", "bob", "kudos", 3).await;
+            core.create_user("bot").await.unwrap();
+            let details = TransactionDetails {
+                memo: Some("thanks for the code review".to_string()),
+                reference: Some("PR-42".to_string()),
+                actor: Some(UserQueryModeStrict::ByName("bot")),
+                ..TransactionDetails::default()
+            };
+            let total = core
+                .transaction_with_details(
+                    UserQueryModeWithCreation::ByName("alice"),
+                    UserQueryModeWithCreation::ByNameOrCreate("carol"),
+                    TokenQueryModeWithCreation::ByName("kudos"),
+                    2,
+                    &details,
+                )
+                .await
+                .unwrap();
+            assert_eq!(total, 2);
+
+            // Senders acting on their own aren't recorded as actors
+            let details = TransactionDetails {
+                actor: Some(UserQueryModeStrict::ByName("alice")),
+                ..TransactionDetails::default()
+            };
+            core.transaction_with_details(
+                UserQueryModeWithCreation::ByName("alice"),
+                UserQueryModeWithCreation::ByName("bob"),
+                TokenQueryModeWithCreation::ByName("kudos"),
+                1,
+                &details,
+            )
+            .await
+            .unwrap();
+
+            // Unknown actors fail the whole transaction
+            let details = TransactionDetails {
+                actor: Some(UserQueryModeStrict::ByName("nobody")),
+                ..TransactionDetails::default()
+            };
+            let result = core
+                .transaction_with_details(
+                    UserQueryModeWithCreation::ByName("alice"),
+                    UserQueryModeWithCreation::ByNameOrCreate("dave"),
+                    TokenQueryModeWithCreation::ByName("kudos"),
+                    1,
+                    &details,
+                )
+                .await;
+            assert!(matches!(result, Err(Error::UserNotFound(_))));
+        }
+
+        let core = Core::new(&config).await.unwrap();
+        let entries = history(&core, &HistoryFilter::default()).await;
+        assert_eq!(entries.len(), 3);
+        assert!(entries.windows(2).all(|pair| pair[0].id < pair[1].id));
+        for entry in &entries
+        {
+            let created_at = entry.created_at.expect("timestamp");
+            assert!(before - chrono::Duration::seconds(1) <= created_at && created_at <= chrono::Utc::now());
+        }
+
+        let entry = &entries[1];
+        assert_eq!((entry.sender_name.as_str(), entry.receiver_name.as_str()), ("alice", "carol"));
+        assert_eq!((entry.token_name.as_str(), entry.amount), ("kudos", 2));
+        assert_eq!(entry.memo.as_deref(), Some("thanks for the code review"));
+        assert_eq!(entry.reference.as_deref(), Some("PR-42"));
+        assert_eq!(entry.actor_name.as_deref(), Some("bot"));
+        assert_eq!((entries[0].memo.as_deref(), entries[0].actor_id), (None, None));
+        assert_eq!(entries[2].actor_id, None);
+
+        let filter = HistoryFilter {
+            sender: Some(UserQueryModeStrict::ByName("alice")),
+            receiver: Some(UserQueryModeStrict::ByName("bob")),
+            ..HistoryFilter::default()
+        };
+        let amounts: Vec<TokenAmount> = history(&core, &filter).await.iter().map(|entry| entry.amount).collect();
+        assert_eq!(amounts, [3, 1]);
+        let filter = HistoryFilter {
+            token: Some(TokenQueryModeStrict::ByName("coffee")),
+            ..HistoryFilter::default()
+        };
+        let result = core.query_history(&filter, Order::Asc, None, 10).await;
+        assert!(matches!(result, Err(Error::TokenNotFound(_))));
+    }
+}
+
+/// All matching transactions, oldest first
+async fn history(core: &Core, filter: &HistoryFilter<'_>) -> Vec<HistoryEntry>
+{
+    let page = core.query_history(filter, Order::Asc, None, 1000).await.expect("history");
+    assert!(page.next.is_none());
+    page.entries
+}
+
+#[tokio::test]
+async fn history_is_filtered_ordered_and_paged()
+{
+    for core in cores().await
+    {
+        let before = chrono::Utc::now();
+        for (receiver, amount, memo) in [
+            ("bob", 50, Some("Code review")),
+            ("carol", 5, None),
+            ("bob", -10, Some("typo in the REVIEW")),
+            ("bob", 20, Some("coffee")),
+            ("carol", 50, Some("50% off")),
+        ]
+        {
+            let details = TransactionDetails {
+                memo: memo.map(str::to_string),
+                ..TransactionDetails::default()
+            };
+            core.transaction_with_details(
+                UserQueryModeWithCreation::ByNameOrCreate("alice"),
+                UserQueryModeWithCreation::ByNameOrCreate(receiver),
+                TokenQueryModeWithCreation::ByNameOrCreate("kudos"),
+                amount,
+                &details,
+            )
+            .await
+            .unwrap();
+        }
+        send(&core, "bob", "alice", "coffee", 1).await;
+
+        let amounts = |entries: &[HistoryEntry]| entries.iter().map(|entry| entry.amount).collect::<Vec<_>>();
+        let all = history(&core, &HistoryFilter::default()).await;
+        assert_eq!(amounts(&all), [50, 5, -10, 20, 50, 1]);
+
+        let filter = HistoryFilter {
+            receiver: Some(UserQueryModeStrict::ByName("bob")),
+            token: Some(TokenQueryModeStrict::ByName("kudos")),
+            min_amount: Some(0),
+            max_amount: Some(50),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(amounts(&history(&core, &filter).await), [50, 20]);
+
+        // Memos are searched ignoring case, and LIKE wildcards are matched literally
+        let memo = |memo: &str| HistoryFilter {
+            memo: Some(memo.to_string()),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(amounts(&history(&core, &memo("review")).await), [50, -10]);
+        assert_eq!(amounts(&history(&core, &memo("0%")).await), [50]);
+        assert!(history(&core, &memo("_")).await.is_empty());
+
+        let window = |since, until| HistoryFilter {
+            since,
+            until,
+            ..HistoryFilter::default()
+        };
+        let later = chrono::Utc::now() + chrono::Duration::hours(1);
+        let earlier = before - chrono::Duration::hours(1);
+        assert_eq!(history(&core, &window(Some(earlier), Some(later))).await.len(), 6);
+        assert!(history(&core, &window(Some(later), None)).await.is_empty());
+        assert!(history(&core, &window(None, Some(earlier))).await.is_empty());
+
+        // Pages continue where the previous one ended, in both orders
+        for order in [Order::Asc, Order::Desc]
+        {
+            let mut paged = Vec::new();
+            let mut after = None;
+            loop
+            {
+                let page = core.query_history(&HistoryFilter::default(), order, after, 4).await.unwrap();
+                assert!(page.entries.len() <= 4);
+                paged.extend(page.entries);
+                match page.next
+                {
+                    Some(next) => after = Some(next.to_string().parse().unwrap()),
+                    None => break,
+                }
+            }
+            let mut expected: Vec<DbPk> = all.iter().map(|entry| entry.id).collect();
+            if order == Order::Desc
+            {
+                expected.reverse();
+            }
+            assert_eq!(paged.iter().map(|entry| entry.id).collect::<Vec<_>>(), expected);
+        }
+
+        let page = core.query_history(&HistoryFilter::default(), Order::Desc, None, 6).await.unwrap();
+        assert!(page.next.is_none());
+        let page = core
+            .query_history(&HistoryFilter::default(), Order::Desc, None, usize::MAX)
+            .await
+            .unwrap();
+        assert!(page.entries.len() == 6 && page.next.is_none());
+        assert!(matches!(
+            core.query_history(&HistoryFilter::default(), Order::Desc, None, 0).await,
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
+
+#[tokio::test]
+async fn reversals_compensate_a_transaction_once()
+{
+    for mut core in cores().await
+    {
+        send(&core, "alice", "bob", "kudos", 100).await;
+        send(&core, "alice", "bob", "kudos", 5).await;
+        let original = history(&core, &HistoryFilter::default()).await[0].id;
+
+        let reversal = core.reverse_transaction(original, "extra zero").await.unwrap();
+        assert_eq!((reversal.sender_name.as_str(), reversal.receiver_name.as_str()), ("alice", "bob"));
+        assert_eq!((reversal.amount, reversal.memo.as_deref()), (-100, Some("extra zero")));
+        assert_eq!((reversal.reverses_id, reversal.reversed_by_id), (Some(original), None));
+        assert_eq!(balance(&core, "bob", "kudos").await, 5);
+
+        // The original stays in the history and links to its reversal
+        let entries = history(&core, &HistoryFilter::default()).await;
+        assert_eq!(entries.len(), 3);
+        assert_eq!((entries[0].amount, entries[0].reversed_by_id), (100, Some(reversal.id)));
+        assert_eq!((entries[1].reverses_id, entries[1].reversed_by_id), (None, None));
+
+        let entries = core
+            .list_user_token(
+                UserQueryModeStrict::ByName("bob"),
+                TokenQueryModeStrict::ByName("kudos"),
+                Order::Asc,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!((entries[0].amount, entries[0].reversed), (5, 100));
+
+        let result = core.reverse_transaction(original, "again").await;
+        assert!(matches!(result, Err(Error::AlreadyReversed { id, reversal_id }) if id == original && reversal_id == reversal.id));
+        let result = core.reverse_transaction(reversal.id, "undo").await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        let result = core.reverse_transaction(reversal.id + 1, "unknown").await;
+        assert!(matches!(result, Err(Error::TransactionNotFound(id)) if id == reversal.id + 1));
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 3);
+    }
+}
+
+#[tokio::test]
+async fn reversals_follow_the_overdraft_policy()
+{
+    for core in cores().await
+    {
+        send(&core, "alice", "bob", "kudos", 10).await;
+        send(&core, "bob", "carol", "kudos", 8).await;
+        let original = history(&core, &HistoryFilter::default()).await[0].id;
+        core.set_overdraft_policy(
+            UserQueryModeStrict::ByName("alice"),
+            TokenQueryModeStrict::ByName("kudos"),
+            OverdraftPolicy::BalanceBacked,
+        )
+        .await
+        .unwrap();
+
+        // Bob has already passed on most of the points, so they can't be taken back
+        let result = core.reverse_transaction(original, "wrong receiver").await;
+        assert!(matches!(
+            result,
+            Err(Error::InsufficientBalance {
+                available: 2,
+                required:  10,
+            })
+        ));
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 2);
+
+        send(&core, "carol", "bob", "kudos", 8).await;
+        core.reverse_transaction(original, "wrong receiver").await.unwrap();
+        assert_eq!(balance(&core, "bob", "kudos").await, 0);
+    }
+}
+
+#[tokio::test]
+async fn reversals_are_persisted()
+{
+    for config in persistent_configs()
+    {
+        let (original, reversal) = {
+            let core = Core::new(&config).await.unwrap();
+            send(&core, "alice", "bob", "kudos", 7).await;
+            send(&core, "alice", "carol", "kudos", 3).await;
+            let original = history(&core, &HistoryFilter::default()).await[1].id;
+            (original, core.reverse_transaction(original, "wrong receiver").await.unwrap().id)
+        };
+
+        let core = Core::new(&config).await.unwrap();
+        let entries = history(&core, &HistoryFilter::default()).await;
+        assert_eq!((entries[1].id, entries[1].reversed_by_id), (original, Some(reversal)));
+        assert_eq!((entries[2].id, entries[2].reverses_id), (reversal, Some(original)));
+        assert_eq!(entries[2].memo.as_deref(), Some("wrong receiver"));
+        assert_eq!(balance(&core, "carol", "kudos").await, 0);
+        assert!(matches!(
+            core.reverse_transaction(original, "again").await,
+            Err(Error::AlreadyReversed { .. })
+        ));
+    }
+}
+
+async fn send_once(core: &Core, sender: &str, receiver: &str, amount: TokenAmount, key: &str) -> Result<TokenAmount, Error>
+{
+    let details = TransactionDetails {
+        idempotency_key: Some(key.to_string()),
+        ..TransactionDetails::default()
+    };
+    core.transaction_with_details(
+        UserQueryModeWithCreation::ByNameOrCreate(sender),
+        UserQueryModeWithCreation::ByNameOrCreate(receiver),
+        TokenQueryModeWithCreation::ByNameOrCreate("kudos"),
+        amount,
+        &details,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn idempotency_keys_record_a_transaction_once()
+{
+    for core in cores().await
+    {
+        assert_eq!(send_once(&core, "alice", "bob", 5, "chat-1").await.unwrap(), 5);
+        assert_eq!(send(&core, "alice", "bob", "kudos", 2).await, 7);
+
+        // A retry returns the original result
+        assert_eq!(send_once(&core, "alice", "bob", 5, "chat-1").await.unwrap(), 5);
+        assert_eq!(send_once(&core, "alice", "bob", 5, "chat-2").await.unwrap(), 12);
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 3);
+
+        // Keys of other transactions are rejected, without creating anybody
+        let result = send_once(&core, "alice", "bob", 6, "chat-1").await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        let result = send_once(&core, "alice", "zoe", 5, "chat-1").await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        assert!(core.query_user("zoe", NameMatch::Exact).await.unwrap().is_empty());
+        assert_eq!(balance(&core, "bob", "kudos").await, 12);
+    }
+}
+
+#[tokio::test]
+async fn concurrent_retries_create_names_and_record_the_transaction_once()
+{
+    for core in cores().await
+    {
+        let results = tokio::join!(
+            send_once(&core, "alice", "bob", 5, "chat-1"),
+            send_once(&core, "alice", "bob", 5, "chat-1"),
+            send_once(&core, "alice", "bob", 5, "chat-1"),
+            send_once(&core, "alice", "bob", 5, "chat-1"),
+        );
+
+        for result in [results.0, results.1, results.2, results.3]
+        {
+            assert_eq!(result.unwrap(), 5);
+        }
+        assert_eq!(core.query_all_users().await.unwrap().len(), 2);
+        assert_eq!(core.query_all_tokens().await.unwrap().len(), 1);
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_persisted_until_they_expire()
+{
+    for config in persistent_configs()
+    {
+        {
+            let core = Core::new(&config).await.unwrap();
+            assert_eq!(send_once(&core, "alice", "bob", 5, "chat-1").await.unwrap(), 5);
+        }
+
+        let core = Core::new(&config).await.unwrap();
+        assert_eq!(send_once(&core, "alice", "bob", 5, "chat-1").await.unwrap(), 5);
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 1);
+        drop(core);
+
+        // Without a retention window, every key has expired right away and may be used again
+        let config = CoreConfig {
+            idempotency_hours: 0,
+            ..config
+        };
+        let core = Core::new(&config).await.unwrap();
+        assert_eq!(send_once(&core, "alice", "bob", 5, "chat-1").await.unwrap(), 10);
+        assert_eq!(send_once(&core, "alice", "carol", 1, "chat-1").await.unwrap(), 1);
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 3);
+    }
+}
+
+fn leg<'a>(sender: &'a str, receiver: &'a str, token: &'a str, amount: TokenAmount) -> TransferLeg<'a>
+{
+    TransferLeg {
+        sender: UserQueryModeWithCreation::ByNameOrCreate(sender),
+        receiver: UserQueryModeWithCreation::ByNameOrCreate(receiver),
+        token: TokenQueryModeWithCreation::ByNameOrCreate(token),
+        amount,
+        details: TransactionDetails::default(),
+    }
+}
+
+#[tokio::test]
+async fn batch_transaction_records_every_leg()
+{
+    for core in cores().await
+    {
+        let legs = vec![
+            leg("alice", "bob", "kudos", 3),
+            leg("alice", "carol", "kudos", 3),
+            leg("alice", "bob", "coffee", 1),
+        ];
+        assert_eq!(core.batch_transaction(legs).await.unwrap(), vec![3, 3, 1]);
+        assert_eq!(core.query_all_users().await.unwrap().len(), 3);
+
+        // Legs between the same users see the totals of the previous ones
+        let legs = vec![leg("alice", "bob", "kudos", 2), leg("alice", "bob", "kudos", 1)];
+        assert_eq!(core.batch_transaction(legs).await.unwrap(), vec![5, 6]);
+        assert_eq!(balance(&core, "alice", "kudos").await, -9);
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 5);
+
+        assert!(core.batch_transaction(vec![]).await.unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn batch_transaction_records_all_legs_or_none()
+{
+    for core in cores().await
+    {
+        send(&core, "alice", "bob", "kudos", 10).await;
+        core.set_overdraft_policy(
+            UserQueryModeStrict::ByName("alice"),
+            TokenQueryModeStrict::ByName("kudos"),
+            OverdraftPolicy::BalanceBacked,
+        )
+        .await
+        .unwrap();
+
+        let legs = vec![leg("bob", "carol", "kudos", 6), leg("bob", "dave", "kudos", 5)];
+        let result = core.batch_transaction(legs).await;
+        assert!(matches!(
+            result,
+            Err(Error::InsufficientBalance {
+                available: 4,
+                required:  5,
+            })
+        ));
+
+        let mut legs = vec![leg("bob", "carol", "tea", 1), leg("bob", "carol", "kudos", 1)];
+        legs[1].receiver = UserQueryModeWithCreation::ByName("zoe");
+        let result = core.batch_transaction(legs).await;
+        assert!(matches!(result, Err(Error::UserNotFound(Identifier::Name(name))) if name == "zoe"));
+
+        // Neither the earlier legs nor the users and tokens they created are left behind
+        assert_eq!(balance(&core, "bob", "kudos").await, 10);
+        assert_eq!(core.query_all_users().await.unwrap().len(), 2);
+        assert_eq!(core.query_all_tokens().await.unwrap().len(), 1);
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 1);
+
+        // Balances include the earlier legs of the same batch
+        let legs = vec![leg("bob", "carol", "kudos", 6), leg("carol", "dave", "kudos", 6)];
+        assert_eq!(core.batch_transaction(legs).await.unwrap(), vec![6, 6]);
+        assert_eq!(balance(&core, "carol", "kudos").await, 0);
+        assert_eq!(balance(&core, "dave", "kudos").await, 6);
+    }
+}
+
+#[tokio::test]
+async fn batch_transaction_is_persisted()
+{
+    for config in persistent_configs()
+    {
+        {
+            let core = Core::new(&config).await.unwrap();
+            let legs = vec![leg("alice", "bob", "kudos", 2), leg("alice", "carol", "kudos", 2)];
+            core.batch_transaction(legs).await.unwrap();
+
+            let mut legs = vec![leg("alice", "dave", "kudos", 2), leg("alice", "bob", "kudos", 2)];
+            legs[1].token = TokenQueryModeWithCreation::ById(4711);
+            assert!(core.batch_transaction(legs).await.is_err());
+        }
+
+        let core = Core::new(&config).await.unwrap();
+        assert_eq!(balance(&core, "alice", "kudos").await, -4);
+        assert_eq!(balance(&core, "carol", "kudos").await, 2);
+        assert!(core.query_user("dave", NameMatch::Exact).await.unwrap().is_empty());
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 2);
+    }
+}
+
+fn imported_records<'a>(created_at: chrono::DateTime<chrono::Utc>) -> Vec<ImportRecord<'a>>
+{
+    vec![
+        ImportRecord::User { name: "alice" },
+        ImportRecord::Token {
+            name:  "kudos",
+            owner: Some("carol"),
+        },
+        ImportRecord::Transaction {
+            leg:        leg("carol", "bob", "kudos", 5),
+            created_at: Some(created_at),
+        },
+        ImportRecord::Transaction {
+            leg:        leg("bob", "alice", "coffee", 2),
+            created_at: None,
+        },
+    ]
+}
+
+#[tokio::test]
+async fn import_creates_users_tokens_and_historical_transactions()
+{
+    let created_at = "2024-03-01T09:30:00Z".parse().unwrap();
+    for core in cores().await
+    {
+        let report = core.import(&imported_records(created_at), true).await.unwrap();
+        assert_eq!((report.users, report.tokens, report.transactions), (3, 2, 2));
+        assert!(report.failures.is_empty() && !report.committed);
+        assert!(core.query_all_users().await.unwrap().is_empty());
+
+        let report = core.import(&imported_records(created_at), false).await.unwrap();
+        assert_eq!((report.users, report.tokens, report.transactions), (3, 2, 2));
+        assert!(report.committed);
+
+        let kudos = core.get_token(TokenQueryModeStrict::ByName("kudos")).await.unwrap();
+        assert_eq!(kudos.owner_name.as_deref(), Some("carol"));
+        let entries = history(&core, &HistoryFilter::default()).await;
+        assert_eq!(entries[0].created_at, Some(created_at));
+        assert!(entries[1].created_at.unwrap() > created_at);
+        assert_eq!(balance(&core, "bob", "kudos").await, 5);
+
+        // Existing users and tokens are reused
+        let records = [
+            ImportRecord::User { name: "alice" },
+            ImportRecord::Token {
+                name:  "kudos",
+                owner: Some("alice"),
+            },
+        ];
+        let report = core.import(&records, false).await.unwrap();
+        assert_eq!((report.users, report.tokens, report.transactions), (0, 0, 0));
+        let kudos = core.get_token(TokenQueryModeStrict::ByName("kudos")).await.unwrap();
+        assert_eq!(kudos.owner_name.as_deref(), Some("carol"));
+    }
+}
+
+#[tokio::test]
+async fn import_reports_every_failure_and_writes_nothing()
+{
+    for core in cores().await
+    {
+        send(&core, "alice", "bob", "kudos", 10).await;
+        core.set_overdraft_policy(
+            UserQueryModeStrict::ByName("alice"),
+            TokenQueryModeStrict::ByName("kudos"),
+            OverdraftPolicy::BalanceBacked,
+        )
+        .await
+        .unwrap();
+
+        let mut unknown = leg("bob", "carol", "kudos", 1);
+        unknown.receiver = UserQueryModeWithCreation::ByName("zoe");
+        let records = [
+            ImportRecord::Transaction {
+                leg:        leg("dave", "erin", "kudos", 5),
+                created_at: None,
+            },
+            ImportRecord::User { name: "frank" },
+            ImportRecord::Transaction {
+                leg:        unknown,
+                created_at: None,
+            },
+            ImportRecord::Transaction {
+                leg:        leg("bob", "carol", "kudos", 10),
+                created_at: None,
+            },
+        ];
+        let report = core.import(&records, false).await.unwrap();
+        assert!(!report.committed);
+        assert!(matches!(
+            report.failures.as_slice(),
+            [(0, Error::InsufficientBalance { .. }), (2, Error::UserNotFound(_))]
+        ));
+
+        // Failed records don't count, and the others aren't written either
+        assert_eq!((report.users, report.tokens, report.transactions), (2, 0, 1));
+        assert_eq!(core.query_all_users().await.unwrap().len(), 2);
+        assert_eq!(balance(&core, "bob", "kudos").await, 10);
+    }
+}
+
+#[tokio::test]
+async fn import_is_persisted()
+{
+    let created_at = "2024-03-01T09:30:00Z".parse().unwrap();
+    for config in persistent_configs()
+    {
+        {
+            let core = Core::new(&config).await.unwrap();
+            assert!(core.import(&imported_records(created_at), false).await.unwrap().committed);
+            assert!(!core.import(&imported_records(created_at), true).await.unwrap().committed);
+        }
+
+        let core = Core::new(&config).await.unwrap();
+        assert_eq!(core.query_all_users().await.unwrap().len(), 3);
+        let entries = history(&core, &HistoryFilter::default()).await;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].created_at, Some(created_at));
+    }
+}
+
+#[tokio::test]
+async fn idempotency_keys_of_imported_transactions_are_kept_from_the_import_on()
+{
+    let created_at = "2024-03-01T09:30:00Z".parse().unwrap();
+    for config in persistent_configs()
+    {
+        {
+            let core = Core::new(&config).await.unwrap();
+            let mut imported = leg("alice", "bob", "kudos", 5);
+            imported.details.idempotency_key = Some("chat-1".to_string());
+            let records = [ImportRecord::Transaction {
+                leg:        imported,
+                created_at: Some(created_at),
+            }];
+            assert!(core.import(&records, false).await.unwrap().committed);
+        }
+
+        // The transaction is older than the retention window, but its key isn't
+        let core = Core::new(&config).await.unwrap();
+        assert_eq!(send_once(&core, "alice", "bob", 5, "chat-1").await.unwrap(), 5);
+        assert_eq!(history(&core, &HistoryFilter::default()).await.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn transaction_without_creation_fails_for_unknown_names()
+{
+    for core in cores().await
+    {
+        send(&core, "alice", "bob", "kudos", 1).await;
+
+        let result = core
+            .transaction(
+                UserQueryModeWithCreation::ByName("alice"),
+                UserQueryModeWithCreation::ByName("dave"),
+                TokenQueryModeWithCreation::ByName("kudos"),
+                1,
+            )
+            .await;
+        assert!(matches!(result, Err(Error::UserNotFound(Identifier::Name(name))) if name == "dave"));
+
+        let result = core
+            .transaction(
+                UserQueryModeWithCreation::ByName("alice"),
+                UserQueryModeWithCreation::ByName("bob"),
+                TokenQueryModeWithCreation::ById(4711),
+                1,
+            )
+            .await;
+        assert!(matches!(result, Err(Error::TokenNotFound(Identifier::Id(4711)))));
+    }
+}
+
+#[tokio::test]
+async fn query_user_matches_names_by_mode()
+{
+    for core in cores().await
+    {
+        for name in ["Ann", "Anna", "ann_b", "annxb", "50% Ann"]
+        {
+            core.create_user(name).await.unwrap();
+        }
+
+        let names = |users: Vec<User>| users.into_iter().map(|user| user.name).collect::<Vec<_>>();
+        let query = |name: &'static str, matching| core.query_user(name, matching);
+
+        assert_eq!(names(query("Ann", NameMatch::Exact).await.unwrap()), ["Ann"]);
+        // Exact matches compare the normalized names, the same way "Ann" is resolved elsewhere
+        assert_eq!(names(query(" ann ", NameMatch::Exact).await.unwrap()), ["Ann"]);
+        assert_eq!(names(query("An", NameMatch::Exact).await.unwrap()), Vec::<String>::new());
+        assert_eq!(names(query("ANN", NameMatch::IgnoreCase).await.unwrap()), ["Ann"]);
+        assert_eq!(
+            names(query("ann", NameMatch::Prefix).await.unwrap()),
+            ["Ann", "Anna", "ann_b", "annxb"]
+        );
+        assert_eq!(names(query("ann_", NameMatch::Prefix).await.unwrap()), ["ann_b"]);
+        assert_eq!(names(query("0% a", NameMatch::Substring).await.unwrap()), ["50% Ann"]);
+        assert_eq!(names(query("%", NameMatch::Substring).await.unwrap()), ["50% Ann"]);
+    }
+}
+
+#[tokio::test]
+async fn query_token_matches_names_by_mode()
+{
+    for core in cores().await
+    {
+        for name in ["kudos", "Kudos_2", "coffee"]
+        {
+            core.create_token(name, None).await.unwrap();
+        }
+
+        let tokens = core.query_token("KUDOS", NameMatch::IgnoreCase).await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].name, "kudos");
+
+        let tokens = core.query_token("s_", NameMatch::Substring).await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].name, "Kudos_2");
+
+        let tokens = core.query_token(" KUDOS", NameMatch::Exact).await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].name, "kudos");
+    }
+}
+
+#[tokio::test]
+async fn find_picks_the_only_match_or_fails_as_ambiguous()
+{
+    for core in cores().await
+    {
+        for name in ["Ann", "Anna", "Bob"]
+        {
+            core.create_user(name).await.unwrap();
+        }
+        core.create_token("kudos", None).await.unwrap();
+        core.create_token("kudos-2", None).await.unwrap();
+
+        assert_eq!(core.find_user("bo", NameMatch::Prefix).await.unwrap().name, "Bob");
+        assert_eq!(core.find_user("nna", NameMatch::Substring).await.unwrap().name, "Anna");
+        let result = core.find_user("ann", NameMatch::Prefix).await;
+        assert!(matches!(result, Err(Error::AmbiguousName { ref name, matches: 2 }) if name == "ann"));
+        let result = core.find_user("zoe", NameMatch::Substring).await;
+        assert!(matches!(result, Err(Error::UserNotFound(Identifier::Name(ref name))) if name == "zoe"));
+
+        assert_eq!(core.find_token("-2", NameMatch::Substring).await.unwrap().name, "kudos-2");
+        let result = core.find_token("kud", NameMatch::Prefix).await;
+        assert!(matches!(result, Err(Error::AmbiguousName { matches: 2, .. })));
+        let result = core.find_token("coffee", NameMatch::Prefix).await;
+        assert!(matches!(result, Err(Error::TokenNotFound(_))));
+    }
+}
+
+#[tokio::test]
+async fn transaction_resolves_names_exactly()
+{
+    for core in cores().await
+    {
+        let anna = core.create_user("Anna").await.unwrap();
+        core.create_token("kudos", None).await.unwrap();
+        send(&core, "Anna", "bob", "kudos", 1).await;
+
+        // "Ann" must not resolve to "Anna"
+        let result = core
+            .transaction(
+                UserQueryModeWithCreation::ByName("Ann"),
+                UserQueryModeWithCreation::ByName("bob"),
+                TokenQueryModeWithCreation::ByName("kudos"),
+                1,
+            )
+            .await;
+        assert!(matches!(result, Err(Error::UserNotFound(Identifier::Name(name))) if name == "Ann"));
+
+        assert_eq!(send(&core, "Ann", "bob", "kudos", 2).await, 2);
+        let ann = core.query_user("Ann", NameMatch::Exact).await.unwrap();
+        assert_eq!(ann.len(), 1);
+        assert_ne!(ann[0].id, anna.id);
+    }
+}
+
+#[tokio::test]
+async fn names_are_unique_after_normalization()
+{
+    for core in cores().await
+    {
+        let alice = core.create_user("Alice").await.unwrap();
+        for name in ["Alice", "alice", " ALICE\t", "Ａｌｉｃｅ"]
+        {
+            let result = core.create_user(name).await;
+            assert!(matches!(result, Err(Error::DuplicateName(duplicate)) if duplicate == name.trim()));
+        }
+
+        core.create_token("kudos", None).await.unwrap();
+        assert!(matches!(core.create_token("Kudos", None).await, Err(Error::DuplicateName(_))));
+
+        // Lookups by name use the same normalization
+        assert_eq!(send(&core, "alice ", "bob", "KUDOS", 1).await, 1);
+        let users = core.query_all_users().await.unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].id, alice.id);
+
+        // Names are stored without the whitespace ignored by the normalization
+        assert_eq!(core.create_user(" carol\t").await.unwrap().name, "carol");
+        assert_eq!(core.create_token(" coffee ", None).await.unwrap().name, "coffee");
+        send(&core, " dave ", "bob", " tea\n", 1).await;
+        let names: Vec<String> = core.query_all_users().await.unwrap().into_iter().map(|user| user.name).collect();
+        assert_eq!(names, ["Alice", "bob", "carol", "dave"]);
+        let names: Vec<String> = core.query_all_tokens().await.unwrap().into_iter().map(|token| token.name).collect();
+        assert_eq!(names, ["kudos", "coffee", "tea"]);
+    }
+}
+
+#[tokio::test]
+async fn dedupe_merges_names_after_normalization_change()
+{
+    for config in persistent_configs()
+    {
+        let case_sensitive = CoreConfig {
+            names: NameNormalization {
+                case_insensitive: false,
+                ..NameNormalization::default()
+            },
+            ..config.clone()
+        };
+        {
+            let core = Core::new(&case_sensitive).await.unwrap();
+            core.create_token("kudos", None).await.unwrap();
+            send(&core, "alice", "bob", "kudos", 1).await;
+            send(&core, "Alice", "bob", "kudos", 2).await;
+            send(&core, "carol", "BOB", "Kudos", 4).await;
+        }
+
+        let core = Core::new(&config).await.unwrap();
+
+        let report = core.dedupe(true).await.unwrap();
+        assert_eq!(report.users.len(), 2);
+        assert_eq!(report.tokens.len(), 1);
+        assert_eq!(core.query_all_users().await.unwrap().len(), 5);
+
+        let report = core.dedupe(false).await.unwrap();
+        let merged: Vec<(&str, Vec<&str>)> = report
+            .users
+            .iter()
+            .map(|group| {
+                (
+                    group.kept.name.as_str(),
+                    group.merged.iter().map(|user| user.name.as_str()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(merged, [("alice", vec!["Alice"]), ("bob", vec!["BOB"])]);
+
+        let users: Vec<String> = core.query_all_users().await.unwrap().into_iter().map(|user| user.name).collect();
+        assert_eq!(users, ["alice", "bob", "carol"]);
+        assert_eq!(core.query_all_tokens().await.unwrap().len(), 1);
+
+        let total = core
+            .get_current_total(
+                UserQueryModeStrict::ByName("ALICE"),
+                UserQueryModeStrict::ByName("bob"),
+                TokenQueryModeStrict::ByName("kudos"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(total, Some(3));
+        assert_eq!(send(&core, "carol", "bob", "kudos", 1).await, 5);
+
+        assert!(core.dedupe(false).await.unwrap().users.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn migrations_can_be_listed_reverted_and_reapplied()
+{
+    for config in persistent_configs()
+    {
+        let core = Core::new(&CoreConfig {
+            run_migrations: false,
+            ..config.clone()
+        })
+        .await
+        .unwrap();
+
+        let status = core.migration_status().await.unwrap();
+        if config.database_url.starts_with("file:")
+        {
+            // The file format has no migrations
+            assert!(status.is_empty());
+            core.migrate_up().await.unwrap();
+            assert!(core.migrate_down().await.unwrap().is_none());
+            continue;
+        }
+        assert!(status.len() > 1 && status.iter().all(|migration| !migration.applied));
+        assert!(core.migrate_down().await.unwrap().is_none());
+
+        core.migrate_up().await.unwrap();
+        assert!(core.migration_status().await.unwrap().iter().all(|migration| migration.applied));
+
+        let latest = status.iter().map(|migration| migration.version).max().unwrap();
+        let reverted = core.migrate_down().await.unwrap().unwrap();
+        assert_eq!(reverted.version, latest);
+        let applied: Vec<bool> = core
+            .migration_status()
+            .await
+            .unwrap()
+            .iter()
+            .map(|migration| migration.applied)
+            .collect();
+        assert_eq!(applied.iter().filter(|&&applied| !applied).count(), 1);
+        assert!(!applied.last().unwrap());
+
+        core.migrate_up().await.unwrap();
+        assert_eq!(send(&core, "alice", "bob", "kudos", 3).await, 3);
+    }
+}
+
+#[tokio::test]
+async fn databases_of_newer_schema_versions_are_refused()
+{
+    let path = fresh_file().with_extension("db");
+    let config = CoreConfig {
+        database_url: format!("sqlite://{}", path.display()),
+        ..CoreConfig::default()
+    };
+    let supported = {
+        let core = Core::new(&config).await.unwrap();
+        core.migration_status()
+            .await
+            .unwrap()
+            .iter()
+            .map(|migration| migration.version)
+            .max()
+            .unwrap()
+    };
+
+    let pool = sqlx::SqlitePool::connect(&config.database_url).await.unwrap();
+    sqlx::query(
+        "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+         VALUES (?, 'from the future', TRUE, x'00', 0)",
+    )
+    .bind(supported + 1)
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool.close().await;
+
+    let err = Core::new(&config).await.err().expect("newer schema");
+    assert!(matches!(err, Error::SchemaTooNew { database, supported: known } if database == supported + 1 && known == supported));
+
+    let file = fresh_file();
+    std::fs::write(&file, "{\"type\":\"format\",\"version\":999}\n").unwrap();
+    assert!(matches!(
+        file_core(&file).await,
+        Err(Error::SchemaTooNew {
+            database:  999,
+            supported: 1,
+        })
+    ));
+}
+
+#[tokio::test]
+async fn list_user_token_orders_senders()
+{
+    for mut core in cores().await
+    {
+        core.create_token("kudos", None).await.unwrap();
+        send(&core, "alice", "bob", "kudos", 5).await;
+        send(&core, "carol", "bob", "kudos", 9).await;
+        send(&core, "dave", "bob", "kudos", 1).await;
+        send(&core, "dave", "erin", "kudos", 100).await;
+
+        let entries = core
+            .list_user_token(
+                UserQueryModeStrict::ByName("bob"),
+                TokenQueryModeStrict::ByName("kudos"),
+                Order::Desc,
+                None,
+            )
+            .await
+            .unwrap();
+        let senders: Vec<(&str, TokenAmount)> = entries.iter().map(|entry| (entry.sender.name.as_str(), entry.amount)).collect();
+        assert_eq!(senders, [("carol", 9), ("alice", 5), ("dave", 1)]);
+
+        let entries = core
+            .list_user_token(
+                UserQueryModeStrict::ByName("bob"),
+                TokenQueryModeStrict::ByName("kudos"),
+                Order::Asc,
+                Some(OrderBySenderOrAmount::Sender),
+            )
+            .await
+            .unwrap();
+        let senders: Vec<&str> = entries.iter().map(|entry| entry.sender.name.as_str()).collect();
+        assert_eq!(senders, ["alice", "carol", "dave"]);
+    }
+}
+
+#[tokio::test]
+async fn list_tokens_by_user_groups_by_token()
+{
+    for mut core in cores().await
+    {
+        core.create_token("coffee", None).await.unwrap();
+        send(&core, "alice", "bob", "kudos", 5).await;
+        send(&core, "carol", "bob", "coffee", 2).await;
+        send(&core, "alice", "bob", "coffee", 1).await;
+
+        let entries = core
+            .list_tokens_by_user(
+                UserQueryModeStrict::ByName("bob"),
+                Order::Asc,
+                Some(OrderByTokenOrSenderOrAmount::Token),
+            )
+            .await
+            .unwrap();
+
+        let tokens: Vec<&str> = entries.iter().map(|entry| entry.token.name.as_str()).collect();
+        assert_eq!(tokens, ["coffee", "kudos"]);
+        let coffee: Vec<(&str, TokenAmount)> = entries[0]
+            .amount_by_sender
+            .iter()
+            .map(|entry| (entry.sender.name.as_str(), entry.amount))
+            .collect();
+        assert_eq!(coffee, [("carol", 2), ("alice", 1)]);
+    }
+}
+
+#[tokio::test]
+async fn list_users_by_token_groups_by_receiver()
+{
+    for mut core in cores().await
+    {
+        core.create_token("kudos", None).await.unwrap();
+        send(&core, "alice", "bob", "kudos", 5).await;
+        send(&core, "alice", "carol", "kudos", 7).await;
+        send(&core, "dave", "bob", "kudos", 1).await;
+        send(&core, "dave", "bob", "coffee", 50).await;
+
+        let entries = core
+            .list_users_by_token(TokenQueryModeStrict::ByName("kudos"), Order::Desc, None)
+            .await
+            .unwrap();
+
+        let receivers: Vec<(&str, usize)> = entries
+            .iter()
+            .map(|entry| (entry.receiver.name.as_str(), entry.amount_by_sender.len()))
+            .collect();
+        assert_eq!(receivers, [("carol", 1), ("bob", 2)]);
+    }
+}
+
+#[tokio::test]
+async fn in_memory_databases_are_isolated()
+{
+    let first = core().await;
+    let second = core().await;
+    send(&first, "alice", "bob", "kudos", 5).await;
+
+    let result = second
+        .get_current_total(
+            UserQueryModeStrict::ByName("alice"),
+            UserQueryModeStrict::ByName("bob"),
+            TokenQueryModeStrict::ByName("kudos"),
+        )
+        .await;
+    assert!(matches!(result, Err(Error::UserNotFound(_))));
+}
+
+#[tokio::test]
+async fn file_backend_replays_log_and_drops_torn_line()
+{
+    let path = fresh_file();
+    {
+        let core = file_core(&path).await.unwrap();
+        send(&core, "alice", "bob", "kudos", 3).await;
+        send(&core, "alice", "bob", "kudos", 4).await;
+
+        // The file is locked for as long as the core exists
+        assert!(matches!(file_core(&path).await, Err(Error::BackendUnavailable(_))));
+    }
+
+    // Simulate a crash in the middle of writing a record
+    let mut content = std::fs::read_to_string(&path).unwrap();
+    content.push_str(r#"{"type":"transaction","sender_id":1,"#);
+    std::fs::write(&path, content).unwrap();
+
+    let core = file_core(&path).await.unwrap();
+    assert_eq!(send(&core, "alice", "bob", "kudos", 1).await, 8);
+    drop(core);
+
+    let core = file_core(&path).await.unwrap();
+    let total = core
+        .get_current_total(
+            UserQueryModeStrict::ByName("alice"),
+            UserQueryModeStrict::ByName("bob"),
+            TokenQueryModeStrict::ByName("kudos"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(total, Some(8));
+    assert_eq!(core.query_all_users().await.unwrap().len(), 2);
+}
+
+/// A persistance layer defined outside of the crate, that always fails
+struct UnavailableBackend;
+
+fn unavailable<T>() -> Result<T, Error>
+{
+    Err(Error::BackendUnavailable("offline".into()))
+}
+
+#[async_trait::async_trait]
+impl PersistanceLayer for UnavailableBackend
+{
+    async fn migration_status(&self) -> Result<Vec<MigrationInfo>, Error>
+    {
+        unavailable()
+    }
+    async fn migrate_up(&self) -> Result<(), Error>
+    {
+        unavailable()
+    }
+    async fn migrate_down(&self) -> Result<Option<MigrationInfo>, Error>
+    {
+        unavailable()
+    }
+    async fn dedupe(&self, _dry_run: bool) -> Result<DedupeReport, Error>
+    {
+        unavailable()
+    }
+    async fn create_user(&self, _name: &str) -> Result<User, Error>
+    {
+        unavailable()
+    }
+    async fn get_all_users(&self) -> Result<Vec<User>, Error>
+    {
+        unavailable()
+    }
+    async fn query_user(&self, _name: &str, _matching: NameMatch) -> Result<Vec<User>, Error>
+    {
+        unavailable()
+    }
+    async fn create_token(&self, _name: &str, _owner: Option<UserQueryModeStrict<'_>>) -> Result<Token, Error>
+    {
+        unavailable()
+    }
+    async fn get_all_tokens(&self) -> Result<Vec<Token>, Error>
+    {
+        unavailable()
+    }
+    async fn query_token(&self, _name: &str, _matching: NameMatch) -> Result<Vec<Token>, Error>
+    {
+        unavailable()
+    }
+    async fn get_token(&self, _token: TokenQueryModeStrict<'_>) -> Result<Token, Error>
+    {
+        unavailable()
+    }
+    async fn edit_token(&self, _actor: UserQueryModeStrict<'_>, _token: TokenQueryModeStrict<'_>, _edit: &TokenEdit)
+        -> Result<Token, Error>
+    {
+        unavailable()
+    }
+    async fn set_token_owner(
+        &self,
+        _actor: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _owner: UserQueryModeStrict<'_>,
+    ) -> Result<(), Error>
+    {
+        unavailable()
+    }
+    async fn set_minter(
+        &self,
+        _actor: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _user: UserQueryModeStrict<'_>,
+        _allowed: bool,
+    ) -> Result<(), Error>
+    {
+        unavailable()
+    }
+    async fn get_minters(&self, _token: TokenQueryModeStrict<'_>) -> Result<Vec<User>, Error>
+    {
+        unavailable()
+    }
+    async fn issue(
+        &self,
+        _issuer: UserQueryModeStrict<'_>,
+        _holder: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _amount: TokenAmount,
+    ) -> Result<TokenAmount, Error>
+    {
+        unavailable()
+    }
+    async fn get_overdraft_policy(&self, _token: TokenQueryModeStrict<'_>) -> Result<OverdraftPolicy, Error>
+    {
+        unavailable()
+    }
+    async fn set_overdraft_policy(
+        &self,
+        _actor: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _policy: OverdraftPolicy,
+    ) -> Result<(), Error>
+    {
+        unavailable()
+    }
+    async fn get_balance(&self, _user: UserQueryModeStrict<'_>, _token: TokenQueryModeStrict<'_>) -> Result<TokenAmount, Error>
+    {
+        unavailable()
+    }
+    async fn get_current_total(
+        &self,
+        _sender: UserQueryModeStrict<'_>,
+        _receiver: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+    ) -> Result<Option<TokenAmount>, Error>
+    {
+        unavailable()
+    }
+    async fn transaction(
+        &self,
+        _sender: UserQueryModeWithCreation<'_>,
+        _receiver: UserQueryModeWithCreation<'_>,
+        _token: TokenQueryModeWithCreation<'_>,
+        _amount: TokenAmount,
+        _details: &TransactionDetails<'_>,
+    ) -> Result<TokenAmount, Error>
+    {
+        unavailable()
+    }
+    async fn batch_transaction(&self, _legs: &[TransferLeg<'_>]) -> Result<Vec<TokenAmount>, Error>
+    {
+        unavailable()
+    }
+    async fn import(&self, _records: &[ImportRecord<'_>], _dry_run: bool) -> Result<ImportReport, Error>
+    {
+        unavailable()
+    }
+    async fn reverse_transaction(&self, _id: TransactionID, _reason: &str) -> Result<HistoryEntry, Error>
+    {
+        unavailable()
+    }
+    async fn query_history(
+        &self,
+        _filter: &HistoryFilter<'_>,
+        _order: Order,
+        _after: Option<HistoryCursor>,
+        _limit: usize,
+    ) -> Result<Vec<HistoryEntry>, Error>
+    {
+        unavailable()
+    }
+    async fn list_user_token(
+        &self,
+        _receiver: UserQueryModeStrict<'_>,
+        _token: TokenQueryModeStrict<'_>,
+        _order: Order,
+        _order_by: Option<OrderBySenderOrAmount>,
+    ) -> Result<Vec<RelativeUserTokenAmountEntry>, Error>
+    {
+        unavailable()
+    }
+    async fn list_tokens_by_user(
+        &self,
+        _receiver: UserQueryModeStrict<'_>,
+        _order: Order,
+        _order_by: Option<OrderByTokenOrSenderOrAmount>,
+    ) -> Result<Vec<RelativeTokenAmountEntry>, Error>
+    {
+        unavailable()
+    }
+    async fn list_users_by_token(
+        &self,
+        _token: TokenQueryModeStrict<'_>,
+        _order: Order,
+        _order_by: Option<OrderByReceiverOrSenderOrAmount>,
+    ) -> Result<Vec<RelativeUserAmountEntry>, Error>
+    {
+        unavailable()
+    }
+}
+
+#[tokio::test]
+async fn core_accepts_external_persistance_layer()
+{
+    let core = Core::with_persistance_layer(Box::new(UnavailableBackend));
+
+    let result = core.create_user("alice").await;
+    assert!(matches!(result, Err(Error::BackendUnavailable(_))));
+}